@@ -9,10 +9,10 @@ use rust_image_decoder::{
 fn main() {
     let buffer = {
         let filename = "image-decoder-app/resources/test2.jpg";
-        let mut f = File::open(&filename).expect("no file found");
-        let metadata = std::fs::metadata(&filename).expect("unable to read metadata");
+        let mut f = File::open(filename).expect("no file found");
+        let metadata = std::fs::metadata(filename).expect("unable to read metadata");
         let mut buffer = vec![0; metadata.len() as usize];
-        f.read(&mut buffer).expect("buffer overflow");
+        f.read_exact(&mut buffer).expect("buffer overflow");
         buffer
     };
 
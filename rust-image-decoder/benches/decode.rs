@@ -0,0 +1,71 @@
+//! Decode benchmarks, run with `cargo bench`. They decode test2.jpg, a 474x315 4:2:0 photo, which
+//! is small enough to keep a run short but big enough that per-image overhead doesn't dominate.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+use rust_image_decoder::{
+    image::ImageDecoder,
+    jpeg::{dct, DecodeOptions, IdctMethod, JPEGDecoder},
+};
+
+const IMAGE: &[u8] = include_bytes!("../../image-decoder-app/resources/test2.jpg");
+
+/// Full decodes, from the compressed data to RGB pixels
+fn decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Bytes(IMAGE.len() as u64));
+    for (name, idct) in [
+        ("float", IdctMethod::Float),
+        ("integer", IdctMethod::Integer),
+        ("fast", IdctMethod::Fast),
+    ] {
+        let options = DecodeOptions {
+            idct,
+            ..Default::default()
+        };
+        group.bench_function(name, |b| {
+            b.iter(|| {
+                JPEGDecoder::new(black_box(IMAGE))
+                    .with_options(options.clone())
+                    .decode()
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+/// Huffman decoding every MCU, without the IDCT or color conversion
+fn entropy_decode(c: &mut Criterion) {
+    let mut group = c.benchmark_group("entropy_decode");
+    group.throughput(Throughput::Bytes(IMAGE.len() as u64));
+    group.bench_function("verify", |b| {
+        b.iter(|| JPEGDecoder::new(black_box(IMAGE)).verify().unwrap())
+    });
+    group.finish();
+}
+
+/// The IDCT of a single block
+fn idct(c: &mut Criterion) {
+    // Coefficients like a textured block of a photo, with energy falling off at high frequencies
+    let mut coefficients = [[0i16; 8]; 8];
+    for (v, row) in coefficients.iter_mut().enumerate() {
+        for (u, coefficient) in row.iter_mut().enumerate() {
+            let magnitude = 400 / (1 + u as i16 + v as i16).pow(2);
+            *coefficient = if (u + v) % 2 == 0 {
+                magnitude
+            } else {
+                -magnitude
+            };
+        }
+    }
+
+    let mut group = c.benchmark_group("idct");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("block", |b| {
+        b.iter(|| dct::inverse_dct(black_box(&coefficients)))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, decode, entropy_decode, idct);
+criterion_main!(benches);
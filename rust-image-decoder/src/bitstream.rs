@@ -6,6 +6,8 @@ pub struct Bitstream<'data> {
     data: &'data [u8],
     byte_cursor: usize,
     bit_cursor: u8,
+    fill_past_end: bool,
+    byte_stuffing: bool,
 }
 
 impl<'data> Bitstream<'data> {
@@ -15,16 +17,30 @@ impl<'data> Bitstream<'data> {
             data,
             byte_cursor: 0,
             bit_cursor: 0,
+            fill_past_end: false,
+            byte_stuffing: false,
         }
     }
 
-    // TODO: Figure out if this is actually needed
-    /* Currently unused
+    /// Sets whether reads past the end of the data return 1 bits instead of failing, like the fill
+    /// bits an encoder is meant to pad the last byte with. Off by default.
+    pub fn with_fill_past_end(mut self, fill_past_end: bool) -> Self {
+        self.fill_past_end = fill_past_end;
+        self
+    }
+
+    /// Sets whether the data is JPEG entropy coded data, where a 0x00 is stuffed after every 0xFF
+    /// data byte, and any other byte after an 0xFF makes a marker that ends the data. Off by
+    /// default. B.1.1.5 Page 32
+    pub fn with_byte_stuffing(mut self, byte_stuffing: bool) -> Self {
+        self.byte_stuffing = byte_stuffing;
+        self
+    }
+
     /// Returns the current cursor position in the bitstream in terms of its "bit index"
     pub fn get_cursor_position(&self) -> usize {
         self.byte_cursor * 8 + (self.bit_cursor as usize)
     }
-    */
 
     // TODO: Figure out if this is actually needed
     /* Currently unused
@@ -36,7 +52,7 @@ impl<'data> Bitstream<'data> {
         if self.byte_cursor > self.data.len()
             || (self.byte_cursor == self.data.len() && self.bit_cursor > 0)
         {
-            return Err(Error::Malformed("Bit cursor advanced past end of data"));
+            return Err(Error::Malformed("Bit cursor advanced past end of data".into()));
         }
         Ok(())
     }
@@ -50,35 +66,180 @@ impl<'data> Bitstream<'data> {
     }
     */
 
-    /// Reads up to 64 bits out of the bitstream and returns them in a u64.
+    /// Skips the rest of the current byte so the next read starts on a byte boundary. Does nothing
+    /// if the cursor is already aligned.
+    pub fn align_to_byte(&mut self) {
+        if self.bit_cursor != 0 {
+            self.advance_byte();
+        }
+    }
+
+    /// With byte stuffing, returns the identifier of the marker that ends the data at the next
+    /// byte boundary, if there is one, without moving the cursor.
+    pub fn next_marker(&self) -> Option<u8> {
+        let position = match (self.bit_cursor, self.current_byte()) {
+            (0, _) => self.byte_cursor,
+            (_, Some(0xFF)) => self.byte_cursor + 2,
+            (_, _) => self.byte_cursor + 1,
+        };
+        self.marker_at(position).map(|(identifier, _)| identifier)
+    }
+
+    /// Skips to the next byte boundary and, with byte stuffing, reads the marker that ends the
+    /// data there, returning its identifier. Returns `None`, leaving the cursor aligned, if the
+    /// data doesn't end in a marker there.
+    pub fn read_marker(&mut self) -> Option<u8> {
+        self.align_to_byte();
+        let (identifier, length) = self.marker_at(self.byte_cursor)?;
+        self.byte_cursor += length;
+        Some(identifier)
+    }
+
+    /// Returns the identifier and length of the marker at `position`, including any 0xFF fill
+    /// bytes before it.
+    fn marker_at(&self, position: usize) -> Option<(u8, usize)> {
+        if !self.byte_stuffing {
+            return None;
+        }
+        let fill = self
+            .data
+            .get(position..)?
+            .iter()
+            .take_while(|&&byte| byte == 0xFF)
+            .count();
+        match self.data.get(position + fill) {
+            Some(&identifier) if fill > 0 && identifier != 0x00 => Some((identifier, fill + 1)),
+            _ => None,
+        }
+    }
+
+    /// Returns the byte under the cursor, or `None` past the end of the data or at a marker.
+    fn current_byte(&self) -> Option<u8> {
+        let byte = *self.data.get(self.byte_cursor)?;
+        if self.byte_stuffing && byte == 0xFF && self.data.get(self.byte_cursor + 1) != Some(&0x00)
+        {
+            return None;
+        }
+        Some(byte)
+    }
+
+    /// Moves the cursor to the start of the next byte, skipping the 0x00 stuffed after an 0xFF.
+    fn advance_byte(&mut self) {
+        self.bit_cursor = 0;
+        match self.current_byte() {
+            Some(0xFF) if self.byte_stuffing => self.byte_cursor += 2,
+            // Reads past a marker are filled without moving on to the marker itself
+            None if self.byte_cursor < self.data.len() => {}
+            _ => self.byte_cursor += 1,
+        }
+    }
+
+    /// Reads up to 64 bits out of the bitstream and returns them in a u64. The read fails with
+    /// `Error::UnexpectedEof`, leaving the cursor where it was, only if it needs a bit past the end
+    /// of the data. Reading the last bit, or zero bits at the end, succeeds.
     pub fn read_bits(&mut self, bits: usize) -> Result<u64, Error> {
         if bits > 64 {
             return Err(Error::InternalError(
-                "Can't read more than 64 bits at a time",
+                "Can't read more than 64 bits at a time".into(),
             ));
         }
 
-        if self.byte_cursor >= self.data.len() {
-            return Err(Error::InternalError("Read past end of bit buffer"));
-        }
-
+        let start = (self.byte_cursor, self.bit_cursor);
         let mut value: u64 = 0;
         for _ in 0..bits {
-            let current_byte = self.data[self.byte_cursor];
+            let current_byte = match self.current_byte() {
+                Some(byte) => byte,
+                None if self.fill_past_end => 0xFF,
+                None => {
+                    (self.byte_cursor, self.bit_cursor) = start;
+                    return Err(Error::UnexpectedEof);
+                }
+            };
             let current_bit = 1u8 & (current_byte >> (7 - self.bit_cursor));
 
             value = (value << 1) | current_bit as u64;
 
             self.bit_cursor += 1;
             if self.bit_cursor == 8 {
-                self.byte_cursor += 1;
-                self.bit_cursor = 0;
-
-                if self.byte_cursor > self.data.len() {
-                    return Err(Error::InternalError("Read past end of bit buffer"));
-                }
+                self.advance_byte();
             }
         }
         Ok(value)
     }
 }
+
+#[test]
+fn align_to_byte() {
+    let mut bitstream = Bitstream::new(&[0b1010_0000, 0b1100_0011, 0xFF]);
+    assert_eq!(bitstream.read_bits(3).unwrap(), 0b101);
+
+    bitstream.align_to_byte();
+    assert_eq!(bitstream.get_cursor_position(), 8);
+    assert_eq!(bitstream.read_bits(4).unwrap(), 0b1100);
+
+    bitstream.align_to_byte();
+    bitstream.align_to_byte();
+    assert_eq!(bitstream.read_bits(8).unwrap(), 0xFF);
+    assert_eq!(bitstream.get_cursor_position(), 24);
+}
+
+#[test]
+fn read_to_end() {
+    let data = [0b1011_0010, 0b0110_1001];
+
+    let mut bitstream = Bitstream::new(&data);
+    assert_eq!(bitstream.read_bits(8).unwrap(), 0b1011_0010);
+    assert_eq!(bitstream.read_bits(7).unwrap(), 0b011_0100);
+    assert_eq!(bitstream.read_bits(1).unwrap(), 1);
+    assert_eq!(bitstream.get_cursor_position(), 16);
+    assert_eq!(bitstream.read_bits(0).unwrap(), 0);
+    assert!(matches!(bitstream.read_bits(1), Err(Error::UnexpectedEof)));
+
+    // A read that runs off the end fails as a whole, without moving the cursor
+    let mut bitstream = Bitstream::new(&data);
+    assert_eq!(bitstream.read_bits(4).unwrap(), 0b1011);
+    assert!(bitstream.read_bits(13).is_err());
+    assert_eq!(bitstream.get_cursor_position(), 4);
+    assert_eq!(bitstream.read_bits(12).unwrap(), 0b0010_0110_1001);
+
+    let mut empty = Bitstream::new(&[]);
+    assert_eq!(empty.read_bits(0).unwrap(), 0);
+    assert!(empty.read_bits(1).is_err());
+}
+
+#[test]
+fn byte_stuffing() {
+    let data = [0xFF, 0x00, 0x80, 0xFF, 0xFF, 0xD1, 0x40];
+    let mut bitstream = Bitstream::new(&data).with_byte_stuffing(true);
+    assert_eq!(bitstream.read_bits(12).unwrap(), 0xFF8);
+    assert_eq!(bitstream.next_marker(), Some(0xD1));
+    assert!(bitstream.read_bits(8).is_err());
+
+    // The marker and its fill byte are skipped as a whole
+    assert_eq!(bitstream.read_marker(), Some(0xD1));
+    assert_eq!(bitstream.get_cursor_position(), 48);
+    assert_eq!(bitstream.read_bits(2).unwrap(), 0b01);
+    assert_eq!(bitstream.read_marker(), None);
+
+    // Reads past a marker are filled, leaving the marker to be read
+    let mut bitstream = Bitstream::new(&data[3..])
+        .with_byte_stuffing(true)
+        .with_fill_past_end(true);
+    assert_eq!(bitstream.read_bits(12).unwrap(), 0xFFF);
+    assert_eq!(bitstream.read_marker(), Some(0xD1));
+}
+
+#[test]
+fn fill_past_end() {
+    let data = [0b0101_0000];
+
+    let mut bitstream = Bitstream::new(&data);
+    assert_eq!(bitstream.read_bits(4).unwrap(), 0b0101);
+    assert!(bitstream.read_bits(8).is_err());
+
+    let mut bitstream = Bitstream::new(&data).with_fill_past_end(true);
+    assert_eq!(bitstream.read_bits(4).unwrap(), 0b0101);
+    assert_eq!(bitstream.read_bits(8).unwrap(), 0b0000_1111);
+    assert_eq!(bitstream.read_bits(12).unwrap(), 0xFFF);
+    assert_eq!(bitstream.get_cursor_position(), 24);
+}
@@ -1,14 +1,33 @@
+use alloc::string::String;
+
+/// The result of reading or writing an image
 pub type Result<T> = core::result::Result<T, Error>;
 
 /// Describes an error encountered while reading an image.
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug)]
 pub enum Error {
     /// The image is malformed in some way. The string describes how.
-    Malformed(&'static str),
+    Malformed(String),
     /// A feature is not supported by the decoder
-    UnsupportedFeature(&'static str),
+    UnsupportedFeature(String),
     /// The decoder had a problem
-    InternalError(&'static str),
+    InternalError(String),
+    /// The image data ended before the decoder was done reading it
+    UnexpectedEof,
+    /// Decoding was stopped by the caller before it finished
+    Cancelled,
     /// There was an error reading the image
+    #[cfg(feature = "std")]
     Io(std::io::Error),
 }
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        match error.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+            _ => Error::Io(error),
+        }
+    }
+}
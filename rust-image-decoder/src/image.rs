@@ -1,10 +1,35 @@
-use crate::error::Result;
+use alloc::{format, string::String, vec, vec::Vec};
+
+use crate::error::{Error, Result};
+
+/// Describes how the channels of each pixel in a bitmap are interpreted
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    /// A single luminance channel
+    Gray,
+    /// Red, green, blue
+    #[default]
+    Rgb,
+    /// Blue, green, red
+    Bgr,
+    /// Red, green, blue, alpha
+    Rgba,
+    /// Cyan, magenta, yellow, black
+    Cmyk,
+    /// YCbCr with an additional black channel
+    Ycck,
+}
 
 /// Stores a single frame of image data in a simple bitmap form
-#[derive(Debug, Default)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Bitmap {
     /// The number of color channels in the image. Ex. RGBA = 4
     pub channels: u8,
+    /// How the channels of each pixel are interpreted
+    pub color_space: ColorSpace,
+    /// The number of bits in each sample, either 8 or 16. 16 bit samples are stored as two big
+    /// endian bytes.
+    pub bit_depth: u8,
 
     /// The size of the image
     pub size: (u16, u16),
@@ -12,6 +37,754 @@ pub struct Bitmap {
     pub data: Vec<u8>,
 }
 
+/// Stores a single frame of image data with a float for every sample, for processing that
+/// shouldn't be limited to 8 bits, like tone mapping
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BitmapF32 {
+    /// The number of color channels in the image
+    pub channels: u8,
+    /// How the channels of each pixel are interpreted
+    pub color_space: ColorSpace,
+    /// The size of the image
+    pub size: (u16, u16),
+    /// The samples, on the same 0 to 255 scale as an 8 bit bitmap. They're neither rounded nor
+    /// clamped, so they can fall outside that range.
+    pub data: Vec<f32>,
+}
+
+impl BitmapF32 {
+    /// Converts to an 8 bit bitmap, clamping each sample to 0 to 255 and dropping its fraction
+    /// the same way decoding straight to 8 bits does.
+    pub fn to_bitmap(&self) -> Bitmap {
+        Bitmap {
+            channels: self.channels,
+            color_space: self.color_space,
+            bit_depth: 8,
+            size: self.size,
+            data: self.data.iter().map(|&sample| sample as u8).collect(),
+        }
+    }
+}
+
+impl Default for Bitmap {
+    fn default() -> Self {
+        Self {
+            channels: 0,
+            color_space: ColorSpace::default(),
+            bit_depth: 8,
+            size: (0, 0),
+            data: Vec::new(),
+        }
+    }
+}
+
+impl Bitmap {
+    /// Creates an 8 bit bitmap from raw pixel data, for example to pass to an encoder. The color
+    /// space is grayscale, RGB, or RGBA for 1, 3, or 4 channels. Fails if `data` doesn't hold
+    /// exactly `width * height` pixels.
+    pub fn new(width: u16, height: u16, channels: u8, data: Vec<u8>) -> Result<Self> {
+        let color_space = match channels {
+            1 => ColorSpace::Gray,
+            3 => ColorSpace::Rgb,
+            4 => ColorSpace::Rgba,
+            _ => {
+                return Err(Error::UnsupportedFeature(
+                    "Bitmaps need 1, 3, or 4 channels".into(),
+                ))
+            }
+        };
+        if data.len() != width as usize * height as usize * channels as usize {
+            return Err(Error::Malformed(
+                "Bitmap data doesn't match its size".into(),
+            ));
+        }
+
+        Ok(Self {
+            channels,
+            color_space,
+            bit_depth: 8,
+            size: (width, height),
+            data,
+        })
+    }
+
+    /// The width of the image in pixels
+    pub fn width(&self) -> u32 {
+        self.size.0 as u32
+    }
+
+    /// The height of the image in pixels
+    pub fn height(&self) -> u32 {
+        self.size.1 as u32
+    }
+
+    /// The number of color channels in the image
+    pub fn channels(&self) -> u8 {
+        self.channels
+    }
+
+    /// Iterates over the pixels in raster order, yielding the x and y coordinates and the bytes of
+    /// each pixel.
+    pub fn pixels(&self) -> Pixels<'_> {
+        Pixels {
+            chunks: self.data.chunks_exact(self.bytes_per_pixel().max(1)),
+            width: self.size.0 as u32,
+            index: 0,
+        }
+    }
+
+    /// The number of bytes each pixel takes up in `data`
+    pub fn bytes_per_pixel(&self) -> usize {
+        self.channels as usize * self.bit_depth.div_ceil(8) as usize
+    }
+
+    /// Returns the samples of a 16 bit bitmap as words, or `None` if the bitmap has 8 bit
+    /// samples.
+    pub fn to_u16_samples(&self) -> Option<Vec<u16>> {
+        if self.bit_depth != 16 {
+            return None;
+        }
+        Some(
+            self.data
+                .chunks_exact(2)
+                .map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+                .collect(),
+        )
+    }
+
+    /// Copies the pixels into a new buffer with every row padded with zeros to a multiple of
+    /// `row_align` bytes, as expected by many video and GPU texture APIs. A `row_align` of 0 or 1
+    /// leaves the rows tightly packed.
+    pub fn to_buffer_with_stride(&self, row_align: usize) -> Vec<u8> {
+        let row_length = self.size.0 as usize * self.bytes_per_pixel();
+        let stride = row_length.next_multiple_of(row_align.max(1));
+
+        let mut buffer = vec![0u8; stride * self.size.1 as usize];
+        if row_length == 0 {
+            return buffer;
+        }
+        for (row, padded_row) in self
+            .data
+            .chunks_exact(row_length)
+            .zip(buffer.chunks_exact_mut(stride))
+        {
+            padded_row[..row_length].copy_from_slice(row);
+        }
+        buffer
+    }
+
+    /// Packs each pixel into a `u32` as 0xAARRGGBB, the layout software framebuffers and most
+    /// windowing crates expect. Bitmaps without an alpha channel get `alpha`, gray bitmaps are
+    /// expanded to RGB, and 16 bit samples keep their high byte.
+    ///
+    /// Fails for CMYK and YCCK bitmaps, which have to be converted to RGB first.
+    pub fn to_argb_u32(&self, alpha: u8) -> Result<Vec<u32>> {
+        Ok(self
+            .rgba_pixels(alpha)?
+            .map(|[r, g, b, a]| u32::from_be_bytes([a, r, g, b]))
+            .collect())
+    }
+
+    /// Packs each pixel into a `u32` as 0xRRGGBBAA, otherwise like `to_argb_u32`.
+    pub fn to_rgba_u32(&self, alpha: u8) -> Result<Vec<u32>> {
+        Ok(self.rgba_pixels(alpha)?.map(u32::from_be_bytes).collect())
+    }
+
+    /// Converts to an 8 bit RGBA bitmap, with the same conversions as `to_argb_u32`.
+    ///
+    /// Fails for CMYK and YCCK bitmaps, which have to be converted to RGB first.
+    pub fn to_rgba(&self, alpha: u8) -> Result<Bitmap> {
+        Ok(Bitmap {
+            channels: 4,
+            color_space: ColorSpace::Rgba,
+            bit_depth: 8,
+            size: self.size,
+            data: self.rgba_pixels(alpha)?.flatten().collect(),
+        })
+    }
+
+    /// Iterates over the pixels as 8 bit RGBA, filling in `alpha` where there's no alpha channel
+    fn rgba_pixels(&self, alpha: u8) -> Result<impl Iterator<Item = [u8; 4]> + '_> {
+        let color_space = self.color_space;
+        if matches!(color_space, ColorSpace::Cmyk | ColorSpace::Ycck) {
+            return Err(Error::UnsupportedFeature(format!(
+                "{:?} bitmaps have to be converted to RGB before packing",
+                color_space
+            )));
+        }
+
+        // The high byte of a 16 bit sample comes first
+        let sample_bytes = self.bit_depth.div_ceil(8) as usize;
+        Ok(self
+            .data
+            .chunks_exact(self.bytes_per_pixel().max(1))
+            .map(move |pixel| {
+                let sample = |channel: usize| pixel[channel * sample_bytes];
+                match color_space {
+                    ColorSpace::Gray => [sample(0), sample(0), sample(0), alpha],
+                    ColorSpace::Bgr => [sample(2), sample(1), sample(0), alpha],
+                    ColorSpace::Rgba => [sample(0), sample(1), sample(2), sample(3)],
+                    _ => [sample(0), sample(1), sample(2), alpha],
+                }
+            }))
+    }
+
+    /// Counts how many times each value appears in each channel, returning one 256 bin histogram
+    /// per channel. 16 bit samples are binned by their high byte.
+    pub fn histogram(&self) -> Vec<[u32; 256]> {
+        let channels = self.channels as usize;
+        let mut histograms = vec![[0u32; 256]; channels];
+        if channels == 0 {
+            return histograms;
+        }
+
+        let shift = self.bit_depth.saturating_sub(8);
+        for pixel in self.samples().chunks_exact(channels) {
+            for (histogram, value) in histograms.iter_mut().zip(pixel) {
+                histogram[(*value >> shift) as usize] += 1;
+            }
+        }
+        histograms
+    }
+
+    /// Flattens an RGBA bitmap onto a solid background color, returning an RGB bitmap of the
+    /// same bit depth. The background is scaled up to 16 bits for 16 bit bitmaps. Bitmaps without
+    /// 4 channels have no alpha to blend, so they're returned as a copy.
+    pub fn composite_over(&self, bg: (u8, u8, u8)) -> Bitmap {
+        if self.channels != 4 {
+            return self.clone();
+        }
+
+        let max = self.max_sample() as u32;
+        // 255 * 257 is 65535, so this maps the background onto the full 16 bit range
+        let scale = if self.bit_depth == 16 { 257 } else { 1 };
+        let background = [bg.0 as u32, bg.1 as u32, bg.2 as u32].map(|color| color * scale);
+        let samples = self.samples();
+        let mut blended_samples = Vec::with_capacity(samples.len() / 4 * 3);
+        for pixel in samples.chunks_exact(4) {
+            let alpha = pixel[3] as u32;
+            for (color, background) in pixel[..3].iter().zip(background) {
+                let blended = *color as u32 * alpha + background * (max - alpha);
+                blended_samples.push(((blended + max / 2) / max) as u16);
+            }
+        }
+
+        Bitmap {
+            channels: 3,
+            color_space: ColorSpace::Rgb,
+            bit_depth: self.bit_depth,
+            size: self.size,
+            data: self.pack_samples(blended_samples),
+        }
+    }
+
+    /// Converts a CMYK bitmap to RGB with `R = (255 - C) * (255 - K) / 255`, and likewise for
+    /// green and blue. Set `inverted` for Adobe CMYK JPEGs, which store every channel as
+    /// `255 - value`.
+    pub fn cmyk_to_rgb(&self, inverted: bool) -> Result<Bitmap> {
+        if self.color_space != ColorSpace::Cmyk || self.channels != 4 || self.bit_depth != 8 {
+            return Err(Error::UnsupportedFeature(
+                "Only 8 bit CMYK bitmaps can be converted from CMYK".into(),
+            ));
+        }
+
+        let mut data = Vec::with_capacity(self.data.len() / 4 * 3);
+        for pixel in self.data.chunks_exact(4) {
+            // Work with the amount of light let through, the inverse of the ink coverage
+            let [c, m, y, k] = if inverted {
+                [pixel[0], pixel[1], pixel[2], pixel[3]]
+            } else {
+                [
+                    255 - pixel[0],
+                    255 - pixel[1],
+                    255 - pixel[2],
+                    255 - pixel[3],
+                ]
+            };
+            for light in [c, m, y] {
+                data.push(((light as u32 * k as u32 + 127) / 255) as u8);
+            }
+        }
+
+        Ok(Bitmap {
+            channels: 3,
+            color_space: ColorSpace::Rgb,
+            bit_depth: 8,
+            size: self.size,
+            data,
+        })
+    }
+
+    /// Copies channel `index` out as a gray bitmap of the same size and bit depth, such as the K
+    /// plate of a CMYK bitmap or the alpha of an RGBA one.
+    pub fn extract_channel(&self, index: u8) -> Result<Bitmap> {
+        if index >= self.channels {
+            return Err(Error::Malformed(format!(
+                "can't extract channel {} of a bitmap with {} channels",
+                index, self.channels
+            )));
+        }
+
+        let sample_length = self.bit_depth.div_ceil(8) as usize;
+        let start = index as usize * sample_length;
+        let data = self
+            .data
+            .chunks_exact(self.bytes_per_pixel())
+            .flat_map(|pixel| &pixel[start..start + sample_length])
+            .copied()
+            .collect();
+        Ok(Bitmap {
+            channels: 1,
+            color_space: ColorSpace::Gray,
+            bit_depth: self.bit_depth,
+            size: self.size,
+            data,
+        })
+    }
+
+    /// Overwrites channel `index` with the samples of `plane`, a single channel bitmap of the
+    /// same size and bit depth, such as one returned by `extract_channel` and then edited.
+    pub fn replace_channel(&mut self, index: u8, plane: &Bitmap) -> Result<()> {
+        if index >= self.channels {
+            return Err(Error::Malformed(format!(
+                "can't replace channel {} of a bitmap with {} channels",
+                index, self.channels
+            )));
+        }
+        if plane.channels != 1
+            || plane.size != self.size
+            || plane.bit_depth != self.bit_depth
+            || plane.data.len() != self.data.len() / self.channels as usize
+        {
+            return Err(Error::Malformed(format!(
+                "can't replace a channel of a {}x{} bitmap of {} bits with a {}x{} one with {} \
+                 channels of {} bits",
+                self.size.0,
+                self.size.1,
+                self.bit_depth,
+                plane.size.0,
+                plane.size.1,
+                plane.channels,
+                plane.bit_depth
+            )));
+        }
+
+        let sample_length = self.bit_depth.div_ceil(8) as usize;
+        let start = index as usize * sample_length;
+        let pixel_length = self.bytes_per_pixel();
+        for (pixel, sample) in self
+            .data
+            .chunks_exact_mut(pixel_length)
+            .zip(plane.data.chunks_exact(sample_length))
+        {
+            pixel[start..start + sample_length].copy_from_slice(sample);
+        }
+        Ok(())
+    }
+
+    /// Checks that `other` has the same size and channels and that no byte differs from this
+    /// bitmap's by more than `max_diff`. Useful in tests, where the float IDCT can round slightly
+    /// differently between platforms. The error describes the first mismatch.
+    pub fn assert_close(&self, other: &Bitmap, max_diff: u8) -> core::result::Result<(), String> {
+        if self.size != other.size || self.channels != other.channels {
+            return Err(format!(
+                "{}x{} with {} channels doesn't match {}x{} with {} channels",
+                self.size.0, self.size.1, self.channels, other.size.0, other.size.1, other.channels
+            ));
+        }
+
+        let channels = self.channels.max(1) as usize;
+        for (index, (&a, &b)) in self.data.iter().zip(&other.data).enumerate() {
+            if a.abs_diff(b) > max_diff {
+                let pixel = index / channels;
+                return Err(format!(
+                    "Pixel ({}, {}) channel {} differs by {} ({} vs {}), more than {}",
+                    pixel % self.size.0 as usize,
+                    pixel / self.size.0 as usize,
+                    index % channels,
+                    a.abs_diff(b),
+                    a,
+                    b,
+                    max_diff
+                ));
+            }
+        }
+        if self.data.len() != other.data.len() {
+            return Err(format!(
+                "{} bytes of data doesn't match {}",
+                self.data.len(),
+                other.data.len()
+            ));
+        }
+        Ok(())
+    }
+
+    /// The peak signal to noise ratio between this bitmap and `other` in decibels, over every
+    /// sample. Higher is closer, and identical bitmaps give infinity. Fails if the bitmaps differ
+    /// in size, channels, or bit depth.
+    #[cfg(feature = "std")]
+    pub fn psnr(&self, other: &Bitmap) -> Result<f64> {
+        let (samples, other_samples) = self.comparable_samples(other)?;
+        if samples.is_empty() {
+            return Ok(f64::INFINITY);
+        }
+
+        let squared_error: f64 = samples
+            .iter()
+            .zip(&other_samples)
+            .map(|(&a, &b)| (a as f64 - b as f64) * (a as f64 - b as f64))
+            .sum();
+        let mean_squared_error = squared_error / samples.len() as f64;
+        if mean_squared_error == 0.0 {
+            return Ok(f64::INFINITY);
+        }
+
+        let peak = ((1u32 << self.bit_depth) - 1) as f64;
+        Ok(10.0 * (peak * peak / mean_squared_error).log10())
+    }
+
+    /// The structural similarity between this bitmap and `other`, from -1 to 1 where 1 means
+    /// identical. Each channel is compared in 8x8 windows, smaller at the right and bottom edges,
+    /// and the scores of every window are averaged. Fails if the bitmaps differ in size,
+    /// channels, or bit depth.
+    pub fn ssim(&self, other: &Bitmap) -> Result<f64> {
+        let (samples, other_samples) = self.comparable_samples(other)?;
+        let (width, height) = (self.size.0 as usize, self.size.1 as usize);
+        let channels = self.channels as usize;
+        if samples.is_empty() {
+            return Ok(1.0);
+        }
+
+        // The stabilizing constants from the original paper, (0.01 L)^2 and (0.03 L)^2
+        let peak = ((1u32 << self.bit_depth) - 1) as f64;
+        let (c1, c2) = ((0.01 * peak) * (0.01 * peak), (0.03 * peak) * (0.03 * peak));
+
+        let mut total = 0.0;
+        let mut windows = 0;
+        for channel in 0..channels {
+            for window_y in (0..height).step_by(8) {
+                for window_x in (0..width).step_by(8) {
+                    let indices = (window_y..(window_y + 8).min(height)).flat_map(|y| {
+                        (window_x..(window_x + 8).min(width))
+                            .map(move |x| (y * width + x) * channels + channel)
+                    });
+
+                    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                        (0.0, 0.0, 0.0, 0.0, 0.0);
+                    let mut count = 0.0;
+                    for index in indices {
+                        let (a, b) = (samples[index] as f64, other_samples[index] as f64);
+                        sum_a += a;
+                        sum_b += b;
+                        sum_aa += a * a;
+                        sum_bb += b * b;
+                        sum_ab += a * b;
+                        count += 1.0;
+                    }
+
+                    let (mean_a, mean_b) = (sum_a / count, sum_b / count);
+                    let variance_a = sum_aa / count - mean_a * mean_a;
+                    let variance_b = sum_bb / count - mean_b * mean_b;
+                    let covariance = sum_ab / count - mean_a * mean_b;
+                    total += (2.0 * mean_a * mean_b + c1) * (2.0 * covariance + c2)
+                        / ((mean_a * mean_a + mean_b * mean_b + c1)
+                            * (variance_a + variance_b + c2));
+                    windows += 1;
+                }
+            }
+        }
+        Ok(total / windows as f64)
+    }
+
+    /// Builds an image of the absolute difference between each sample of this bitmap and
+    /// `other`, so changes from a decoder tweak can be seen rather than counted. Identical
+    /// bitmaps give an all zero image. Fails if the bitmaps differ in size, channels, or bit
+    /// depth.
+    pub fn diff(&self, other: &Bitmap) -> Result<Bitmap> {
+        self.diff_amplified(other, 1)
+    }
+
+    /// Builds a difference image like `diff`, but multiplies every difference by `gain`,
+    /// saturating at the maximum sample value, so small differences stand out.
+    pub fn diff_amplified(&self, other: &Bitmap, gain: u16) -> Result<Bitmap> {
+        let (samples, other_samples) = self.comparable_samples(other)?;
+        let differences = samples
+            .iter()
+            .zip(&other_samples)
+            .map(|(a, b)| a.abs_diff(*b).saturating_mul(gain));
+        let data = if self.bit_depth == 16 {
+            differences.flat_map(u16::to_be_bytes).collect()
+        } else {
+            differences
+                .map(|difference| difference.min(255) as u8)
+                .collect()
+        };
+
+        Ok(Bitmap {
+            data,
+            ..self.clone()
+        })
+    }
+
+    /// Returns the samples of both bitmaps as words, after checking they can be compared
+    fn comparable_samples(&self, other: &Bitmap) -> Result<(Vec<u16>, Vec<u16>)> {
+        if self.size != other.size
+            || self.channels != other.channels
+            || self.bit_depth != other.bit_depth
+            || self.data.len() != other.data.len()
+        {
+            return Err(Error::Malformed(format!(
+                "can't compare a {}x{} bitmap with {} channels of {} bits to a {}x{} one with {} \
+                 channels of {} bits",
+                self.size.0,
+                self.size.1,
+                self.channels,
+                self.bit_depth,
+                other.size.0,
+                other.size.1,
+                other.channels,
+                other.bit_depth
+            )));
+        }
+        Ok((self.samples(), other.samples()))
+    }
+
+    /// Returns the samples as words, whatever the bit depth
+    fn samples(&self) -> Vec<u16> {
+        self.to_u16_samples()
+            .unwrap_or_else(|| self.data.iter().map(|&sample| sample as u16).collect())
+    }
+
+    /// Packs samples into bytes at this bitmap's bit depth. 8 bit samples must already fit in a
+    /// byte.
+    fn pack_samples(&self, samples: Vec<u16>) -> Vec<u8> {
+        if self.bit_depth == 16 {
+            samples.into_iter().flat_map(u16::to_be_bytes).collect()
+        } else {
+            samples.into_iter().map(|sample| sample as u8).collect()
+        }
+    }
+
+    /// The largest value a sample can hold at this bitmap's bit depth
+    fn max_sample(&self) -> u16 {
+        if self.bit_depth == 16 {
+            u16::MAX
+        } else {
+            u8::MAX as u16
+        }
+    }
+
+    /// Encodes the bitmap to a file, picking the format from the extension of `path`. Supports
+    /// `.ppm`, `.png`, and `.jpg` or `.jpeg` at the JPEG encoder's default quality.
+    #[cfg(feature = "std")]
+    pub fn save(&self, path: &str) -> Result<()> {
+        use crate::{jpeg::JPEGEncoder, png::PNGEncoder, ppm::PPMEncoder};
+
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("ppm") => PPMEncoder::new(self).encode_to_file(path)?,
+            Some("png") => PNGEncoder::new(self).encode_to_file(path)?,
+            Some("jpg" | "jpeg") => JPEGEncoder::new(self).encode_to_file(path)?,
+            _ => {
+                return Err(Error::UnsupportedFeature(
+                    "No encoder for the file extension".into(),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Mirrors the image left to right.
+    pub fn flip_horizontal(&mut self) {
+        let row_length = self.size.0 as usize * self.bytes_per_pixel();
+        if row_length == 0 {
+            return;
+        }
+
+        let pixel_size = self.bytes_per_pixel();
+        for row in self.data.chunks_exact_mut(row_length) {
+            let width = row.len() / pixel_size;
+            for x in 0..width / 2 {
+                let (left, right) = row.split_at_mut((width - 1 - x) * pixel_size);
+                left[x * pixel_size..(x + 1) * pixel_size]
+                    .swap_with_slice(&mut right[..pixel_size]);
+            }
+        }
+    }
+
+    /// Mirrors the image top to bottom.
+    pub fn flip_vertical(&mut self) {
+        let row_length = self.size.0 as usize * self.bytes_per_pixel();
+        let height = self.size.1 as usize;
+        for y in 0..height / 2 {
+            let (top, bottom) = self.data.split_at_mut((height - 1 - y) * row_length);
+            top[y * row_length..(y + 1) * row_length].swap_with_slice(&mut bottom[..row_length]);
+        }
+    }
+
+    /// Rotates the image 90 degrees clockwise.
+    pub fn rotate_90(&mut self) {
+        let height = self.size.1 as usize;
+        self.rotate_quarter(|x, y| (height - 1 - y, x));
+    }
+
+    /// Rotates the image 180 degrees.
+    pub fn rotate_180(&mut self) {
+        self.flip_horizontal();
+        self.flip_vertical();
+    }
+
+    /// Rotates the image 270 degrees clockwise.
+    pub fn rotate_270(&mut self) {
+        let width = self.size.0 as usize;
+        self.rotate_quarter(|x, y| (y, width - 1 - x));
+    }
+
+    /// Paints the outline of a rectangle, `thickness` pixels wide and drawn inside its bounds.
+    /// `x` and `y` give the top left corner, which can be off the image; anything outside the
+    /// image is clipped. `color` holds the bytes of one pixel, so it must be `bytes_per_pixel`
+    /// long or an error is returned.
+    pub fn draw_rect(
+        &mut self,
+        x: i32,
+        y: i32,
+        width: u32,
+        height: u32,
+        color: &[u8],
+        thickness: u8,
+    ) -> Result<()> {
+        let pixel_size = self.bytes_per_pixel();
+        if color.len() != pixel_size {
+            return Err(Error::Malformed(format!(
+                "color is {} bytes long, but a pixel is {}",
+                color.len(),
+                pixel_size
+            )));
+        }
+
+        // Work in 64 bits so corners far off the image can't overflow
+        let (left, top) = (x as i64, y as i64);
+        let (right, bottom) = (left + width as i64, top + height as i64);
+        let thickness = thickness as i64;
+        for pixel_y in top.max(0)..bottom.min(self.size.1 as i64) {
+            for pixel_x in left.max(0)..right.min(self.size.0 as i64) {
+                let on_outline = pixel_x < left + thickness
+                    || pixel_x >= right - thickness
+                    || pixel_y < top + thickness
+                    || pixel_y >= bottom - thickness;
+                if on_outline {
+                    let index =
+                        (pixel_y as usize * self.size.0 as usize + pixel_x as usize) * pixel_size;
+                    self.data[index..index + pixel_size].copy_from_slice(color);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns a copy of the image with a box blur of the given radius applied to each channel.
+    /// Pixels past the edges are treated as copies of the nearest edge pixel.
+    pub fn blur(&self, radius: u8) -> Bitmap {
+        let (width, height) = (self.size.0 as usize, self.size.1 as usize);
+        let channels = self.channels as usize;
+
+        // The blur is separable, so run it across the rows and then down the columns
+        let horizontal = Self::box_blur_pass(&self.samples(), width, height, channels, radius, 1);
+        let samples = Self::box_blur_pass(&horizontal, height, width, channels, radius, width);
+
+        Bitmap {
+            channels: self.channels,
+            color_space: self.color_space,
+            bit_depth: self.bit_depth,
+            size: self.size,
+            data: self.pack_samples(samples),
+        }
+    }
+
+    /// Averages each sample with the `radius` samples either side of it along one axis. `length`
+    /// is the number of pixels along that axis, `lines` the number of lines to blur, and `step`
+    /// the distance in pixels between neighbors on the axis.
+    fn box_blur_pass(
+        data: &[u16],
+        length: usize,
+        lines: usize,
+        channels: usize,
+        radius: u8,
+        step: usize,
+    ) -> Vec<u16> {
+        let radius = radius as isize;
+        let window = (2 * radius + 1) as u32;
+        let line_step = if step == 1 { length } else { 1 };
+
+        let mut result = vec![0u16; data.len()];
+        for line in 0..lines {
+            for position in 0..length {
+                for channel in 0..channels {
+                    let sum: u32 = (-radius..=radius)
+                        .map(|offset| {
+                            let neighbor =
+                                (position as isize + offset).clamp(0, length as isize - 1);
+                            let pixel = line * line_step + neighbor as usize * step;
+                            data[pixel * channels + channel] as u32
+                        })
+                        .sum();
+
+                    let pixel = line * line_step + position * step;
+                    result[pixel * channels + channel] = ((sum + window / 2) / window) as u16;
+                }
+            }
+        }
+        result
+    }
+
+    /// Moves every pixel to the position returned by `destination` and swaps the dimensions.
+    fn rotate_quarter(&mut self, destination: impl Fn(usize, usize) -> (usize, usize)) {
+        let (width, height) = (self.size.0 as usize, self.size.1 as usize);
+        let pixel_size = self.bytes_per_pixel();
+
+        let mut data = vec![0u8; self.data.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let (dest_x, dest_y) = destination(x, y);
+                let source_index = (y * width + x) * pixel_size;
+                let dest_index = (dest_y * height + dest_x) * pixel_size;
+                data[dest_index..dest_index + pixel_size]
+                    .copy_from_slice(&self.data[source_index..source_index + pixel_size]);
+            }
+        }
+
+        self.data = data;
+        self.size = (self.size.1, self.size.0);
+    }
+}
+
+/// Iterator over the pixels of a bitmap, created by `Bitmap::pixels`
+pub struct Pixels<'bitmap> {
+    chunks: core::slice::ChunksExact<'bitmap, u8>,
+    width: u32,
+    index: u32,
+}
+
+impl<'bitmap> Iterator for Pixels<'bitmap> {
+    type Item = (u32, u32, &'bitmap [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let pixel = self.chunks.next()?;
+        let (x, y) = (self.index % self.width, self.index / self.width);
+        self.index += 1;
+        Some((x, y, pixel))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+impl ExactSizeIterator for Pixels<'_> {}
+
 /// Used to decode an image. This trait can be implemented for any image format I want to decode.
 pub trait ImageDecoder<'data> {
     /// Supplies the decode with the image data
@@ -21,9 +794,584 @@ pub trait ImageDecoder<'data> {
 }
 
 /// Used to encode an image. This trait can be implemented for any image format I want to encode.
+#[cfg(feature = "std")]
 pub trait ImageEncoder<'bitmap> {
     /// Supplies the encoder with a raw bitmap to encode.
     fn new(bitmap: &'bitmap Bitmap) -> Self;
     /// Encodes the bitmap and saves the result to a file at the given path.
     fn encode_to_file(&self, path: &str) -> std::io::Result<()>;
 }
+
+#[cfg(test)]
+fn test_bitmap() -> Bitmap {
+    // 3x2 RGB image where every channel value is unique
+    Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 8,
+        size: (3, 2),
+        data: (0..18).collect(),
+    }
+}
+
+#[test]
+fn new() {
+    let bitmap = Bitmap::new(3, 2, 3, (0..18).collect()).unwrap();
+    assert_eq!(bitmap, test_bitmap());
+
+    let gray = Bitmap::new(2, 2, 1, vec![0; 4]).unwrap();
+    assert_eq!(gray.color_space, ColorSpace::Gray);
+
+    assert!(matches!(
+        Bitmap::new(3, 2, 3, vec![0; 17]),
+        Err(Error::Malformed(_))
+    ));
+    assert!(matches!(
+        Bitmap::new(3, 2, 2, vec![0; 12]),
+        Err(Error::UnsupportedFeature(_))
+    ));
+}
+
+#[test]
+fn pixels() {
+    let bitmap = Bitmap::new(2, 2, 3, (0..12).collect()).unwrap();
+    let pixels: Vec<(u32, u32, &[u8])> = bitmap.pixels().collect();
+    assert_eq!(
+        pixels,
+        [
+            (0, 0, &[0, 1, 2][..]),
+            (1, 0, &[3, 4, 5][..]),
+            (0, 1, &[6, 7, 8][..]),
+            (1, 1, &[9, 10, 11][..]),
+        ]
+    );
+    assert_eq!(bitmap.pixels().len(), 4);
+    assert_eq!(Bitmap::default().pixels().count(), 0);
+}
+
+#[test]
+fn flip_horizontal() {
+    let mut bitmap = test_bitmap();
+    bitmap.flip_horizontal();
+    assert_eq!(&bitmap.data[..9], &[6, 7, 8, 3, 4, 5, 0, 1, 2]);
+
+    bitmap.flip_horizontal();
+    assert_eq!(bitmap.data, test_bitmap().data);
+}
+
+#[test]
+fn flip_vertical() {
+    let mut bitmap = test_bitmap();
+    bitmap.flip_vertical();
+    assert_eq!(&bitmap.data[..9], &[9, 10, 11, 12, 13, 14, 15, 16, 17]);
+
+    bitmap.flip_vertical();
+    assert_eq!(bitmap.data, test_bitmap().data);
+}
+
+#[test]
+fn rotate() {
+    let mut bitmap = test_bitmap();
+    bitmap.rotate_90();
+    assert_eq!(bitmap.size, (2, 3));
+    // The bottom left pixel moves to the top left
+    assert_eq!(&bitmap.data[..6], &[9, 10, 11, 0, 1, 2]);
+
+    for _ in 0..3 {
+        bitmap.rotate_90();
+    }
+    assert_eq!(bitmap.size, (3, 2));
+    assert_eq!(bitmap.data, test_bitmap().data);
+
+    let mut rotated = test_bitmap();
+    rotated.rotate_270();
+    bitmap.rotate_90();
+    bitmap.rotate_180();
+    assert_eq!(rotated.size, bitmap.size);
+    assert_eq!(rotated.data, bitmap.data);
+}
+
+#[test]
+fn blur() {
+    let solid = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 8,
+        size: (4, 3),
+        data: [10, 200, 30].repeat(12),
+    };
+    assert_eq!(solid.blur(2).data, solid.data);
+
+    let mut data = vec![0u8; 25];
+    data[12] = 90;
+    let point = Bitmap {
+        channels: 1,
+        color_space: ColorSpace::Gray,
+        bit_depth: 8,
+        size: (5, 5),
+        data,
+    };
+    let blurred = point.blur(1);
+    // The pixel is spread evenly over its 3x3 neighborhood
+    assert_eq!(blurred.data[12], 10);
+    assert_eq!(blurred.data[6], 10);
+    assert_eq!(blurred.data[18], 10);
+    assert_eq!(blurred.data[0], 0);
+    assert_eq!(blurred.data.iter().map(|v| *v as u32).sum::<u32>(), 90);
+
+    // 16 bit samples are blurred as words, not as pairs of bytes
+    let deep = Bitmap {
+        channels: 1,
+        color_space: ColorSpace::Gray,
+        bit_depth: 16,
+        size: (3, 1),
+        data: [256u16, 256, 4000]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect(),
+    };
+    let blurred = deep.blur(1);
+    assert_eq!(blurred.bit_depth, 16);
+    assert_eq!(blurred.to_u16_samples().unwrap(), [256, 1504, 2752]);
+}
+
+#[test]
+fn clone() {
+    let bitmap = test_bitmap();
+    let mut copy = bitmap.clone();
+    assert_eq!(copy, bitmap);
+    assert_eq!((copy.width(), copy.height(), copy.channels()), (3, 2, 3));
+
+    copy.flip_vertical();
+    assert_ne!(copy, bitmap);
+}
+
+#[test]
+fn cmyk_to_rgb() {
+    let cmyk = Bitmap {
+        channels: 4,
+        color_space: ColorSpace::Cmyk,
+        bit_depth: 8,
+        size: (3, 1),
+        // Pure cyan, half black, and no ink at all
+        data: vec![255, 0, 0, 0, 0, 0, 0, 128, 0, 0, 0, 0],
+    };
+    let rgb = cmyk.cmyk_to_rgb(false).unwrap();
+    assert_eq!(rgb.color_space, ColorSpace::Rgb);
+    assert_eq!(rgb.data, [0, 255, 255, 127, 127, 127, 255, 255, 255]);
+
+    // Adobe stores the same pixels inverted
+    let adobe = Bitmap {
+        data: cmyk.data.iter().map(|value| 255 - value).collect(),
+        ..cmyk.clone()
+    };
+    assert_eq!(adobe.cmyk_to_rgb(true).unwrap(), rgb);
+
+    assert!(test_bitmap().cmyk_to_rgb(false).is_err());
+}
+
+#[test]
+fn extract_channel() {
+    let mut cmyk = Bitmap {
+        color_space: ColorSpace::Cmyk,
+        ..Bitmap::new(2, 2, 4, (0..16).collect()).unwrap()
+    };
+    let original = cmyk.clone();
+
+    let mut key = cmyk.extract_channel(3).unwrap();
+    assert_eq!(key.channels, 1);
+    assert_eq!(key.color_space, ColorSpace::Gray);
+    assert_eq!(key.size, (2, 2));
+    assert_eq!(key.data, [3, 7, 11, 15]);
+
+    // Putting the channel back unchanged is a no-op, and edits land in the right place
+    cmyk.replace_channel(3, &key).unwrap();
+    assert_eq!(cmyk, original);
+    key.data
+        .iter_mut()
+        .for_each(|sample| *sample = 255 - *sample);
+    cmyk.replace_channel(3, &key).unwrap();
+    assert_eq!(cmyk.data[..8], [0, 1, 2, 252, 4, 5, 6, 248]);
+    assert_eq!(cmyk.extract_channel(3).unwrap(), key);
+
+    // 16 bit samples stay whole
+    let deep = Bitmap {
+        bit_depth: 16,
+        size: (1, 1),
+        data: vec![1, 2, 3, 4, 5, 6],
+        ..test_bitmap()
+    };
+    assert_eq!(deep.extract_channel(1).unwrap().data, [3, 4]);
+
+    assert!(matches!(cmyk.extract_channel(4), Err(Error::Malformed(_))));
+    let small = Bitmap::new(1, 2, 1, vec![0; 2]).unwrap();
+    assert!(matches!(
+        cmyk.replace_channel(0, &small),
+        Err(Error::Malformed(_))
+    ));
+    assert!(matches!(
+        cmyk.replace_channel(0, &original),
+        Err(Error::Malformed(_))
+    ));
+    assert_eq!(cmyk.extract_channel(3).unwrap(), key);
+}
+
+#[test]
+fn assert_close() {
+    let bitmap = test_bitmap();
+    let mut other = test_bitmap();
+    other.data[10] += 1;
+
+    assert!(bitmap.assert_close(&bitmap, 0).is_ok());
+    assert!(bitmap.assert_close(&other, 1).is_ok());
+    assert_eq!(
+        bitmap.assert_close(&other, 0).unwrap_err(),
+        "Pixel (0, 1) channel 1 differs by 1 (10 vs 11), more than 0"
+    );
+
+    other.size = (2, 3);
+    assert!(bitmap.assert_close(&other, 255).is_err());
+}
+
+#[test]
+fn draw_rect() {
+    let black = Bitmap::new(8, 8, 3, vec![0; 8 * 8 * 3]).unwrap();
+    let red = [255, 0, 0];
+    let pixel = |bitmap: &Bitmap, x: usize, y: usize| {
+        let index = (y * 8 + x) * 3;
+        [
+            bitmap.data[index],
+            bitmap.data[index + 1],
+            bitmap.data[index + 2],
+        ]
+    };
+
+    let mut bitmap = black.clone();
+    bitmap.draw_rect(1, 2, 5, 4, &red, 1).unwrap();
+    for (x, y) in [
+        (1, 2),
+        (5, 2),
+        (1, 5),
+        (5, 5),
+        (3, 2),
+        (1, 4),
+        (5, 3),
+        (4, 5),
+    ] {
+        assert_eq!(pixel(&bitmap, x, y), red, "({}, {})", x, y);
+    }
+    for (x, y) in [(2, 3), (4, 4), (0, 2), (6, 2), (1, 1), (1, 6)] {
+        assert_eq!(pixel(&bitmap, x, y), [0, 0, 0], "({}, {})", x, y);
+    }
+
+    // Only the inner two pixels of the thick outline land on the image
+    let mut bitmap = black.clone();
+    bitmap.draw_rect(-2, -2, 12, 12, &red, 4).unwrap();
+    assert_eq!(pixel(&bitmap, 0, 0), red);
+    assert_eq!(pixel(&bitmap, 1, 7), red);
+    assert_eq!(pixel(&bitmap, 2, 2), [0, 0, 0]);
+    assert_eq!(pixel(&bitmap, 5, 5), [0, 0, 0]);
+    assert_eq!(pixel(&bitmap, 6, 5), red);
+
+    let mut bitmap = black.clone();
+    bitmap.draw_rect(20, 20, 4, 4, &red, 1).unwrap();
+    assert_eq!(bitmap, black);
+
+    assert!(bitmap.draw_rect(0, 0, 4, 4, &[255, 0, 0, 255], 1).is_err());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn psnr() {
+    let bitmap = test_bitmap();
+    assert_eq!(bitmap.psnr(&bitmap).unwrap(), f64::INFINITY);
+
+    // Every sample one level off gives a mean squared error of 1
+    let mut brighter = bitmap.clone();
+    brighter.data.iter_mut().for_each(|sample| *sample += 1);
+    let expected = 20.0 * 255f64.log10();
+    assert!((bitmap.psnr(&brighter).unwrap() - expected).abs() < 1e-9);
+    assert!((expected - 48.13).abs() < 0.01);
+
+    // Fewer samples off, but by ten levels, is worse
+    let mut noisy = bitmap.clone();
+    noisy
+        .data
+        .iter_mut()
+        .step_by(4)
+        .for_each(|sample| *sample += 10);
+    assert!(bitmap.psnr(&noisy).unwrap() < bitmap.psnr(&brighter).unwrap());
+}
+
+#[test]
+fn ssim() {
+    let bitmap = Bitmap::new(16, 16, 1, (0..=255).collect()).unwrap();
+    assert_eq!(bitmap.ssim(&bitmap).unwrap(), 1.0);
+
+    let mut brighter = bitmap.clone();
+    brighter
+        .data
+        .iter_mut()
+        .for_each(|sample| *sample = sample.saturating_add(1));
+    let score = bitmap.ssim(&brighter).unwrap();
+    assert!(score > 0.99 && score < 1.0, "{}", score);
+
+    // Flattening the structure scores far lower than a small shift
+    let flat = Bitmap::new(16, 16, 1, vec![128; 256]).unwrap();
+    assert!(bitmap.ssim(&flat).unwrap() < 0.5);
+}
+
+#[test]
+fn compare_mismatched_bitmaps() {
+    let bitmap = test_bitmap();
+    let mut other = test_bitmap();
+    other.size = (2, 3);
+    assert!(matches!(bitmap.ssim(&other), Err(Error::Malformed(_))));
+    #[cfg(feature = "std")]
+    assert!(matches!(bitmap.psnr(&other), Err(Error::Malformed(_))));
+}
+
+#[test]
+fn diff() {
+    let bitmap = test_bitmap();
+    let difference = bitmap.diff(&bitmap).unwrap();
+    assert_eq!((difference.size, difference.channels), (bitmap.size, 3));
+    assert!(difference.data.iter().all(|&sample| sample == 0));
+
+    let mut other = test_bitmap();
+    other.data[4] = 1;
+    other.data[17] = 200;
+    let difference = bitmap.diff(&other).unwrap();
+    assert_eq!(difference.data[4], 3);
+    assert_eq!(difference.data[17], 183);
+    assert_eq!(
+        difference
+            .data
+            .iter()
+            .filter(|&&sample| sample != 0)
+            .count(),
+        2
+    );
+
+    let amplified = bitmap.diff_amplified(&other, 10).unwrap();
+    assert_eq!((amplified.data[4], amplified.data[17]), (30, 255));
+
+    let smaller = Bitmap::new(2, 3, 3, (0..18).collect()).unwrap();
+    assert!(matches!(bitmap.diff(&smaller), Err(Error::Malformed(_))));
+}
+
+#[test]
+fn sixteen_bit_samples() {
+    // 2x1 RGB image with 16 bit samples
+    let words: [u16; 6] = [0, 1, 256, 4095, 40000, 65535];
+    let mut bitmap = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 16,
+        size: (2, 1),
+        data: words.iter().flat_map(|word| word.to_be_bytes()).collect(),
+    };
+    assert_eq!(bitmap.bytes_per_pixel(), 6);
+    assert_eq!(bitmap.to_u16_samples().unwrap(), words);
+    assert_eq!(test_bitmap().to_u16_samples(), None);
+
+    // Whole pixels move, not single bytes
+    bitmap.flip_horizontal();
+    assert_eq!(
+        bitmap.to_u16_samples().unwrap(),
+        [4095, 40000, 65535, 0, 1, 256]
+    );
+    assert_eq!(bitmap.to_buffer_with_stride(8).len(), 16);
+}
+
+#[test]
+fn to_buffer_with_stride() {
+    let bitmap = test_bitmap();
+    assert_eq!(bitmap.to_buffer_with_stride(1), bitmap.data);
+    assert_eq!(bitmap.to_buffer_with_stride(0), bitmap.data);
+
+    // Rows of 9 bytes padded to 12
+    let buffer = bitmap.to_buffer_with_stride(4);
+    assert_eq!(buffer.len(), 24);
+    assert_eq!(&buffer[..9], &bitmap.data[..9]);
+    assert_eq!(&buffer[9..12], &[0, 0, 0]);
+    assert_eq!(&buffer[12..21], &bitmap.data[9..]);
+    assert_eq!(&buffer[21..], &[0, 0, 0]);
+
+    assert_eq!(bitmap.to_buffer_with_stride(256).len(), 512);
+}
+
+#[test]
+fn pack_u32() {
+    let bitmap = test_bitmap();
+    let argb = bitmap.to_argb_u32(0xFF).unwrap();
+    assert_eq!(argb.len(), 6);
+    assert_eq!(argb[1], 0xFF030405);
+    assert_eq!(bitmap.to_rgba_u32(0x80).unwrap()[1], 0x03040580);
+
+    let bgr = Bitmap {
+        color_space: ColorSpace::Bgr,
+        ..test_bitmap()
+    };
+    assert_eq!(bgr.to_argb_u32(0xFF).unwrap()[1], 0xFF050403);
+
+    let gray = Bitmap::new(1, 1, 1, vec![0x7F]).unwrap();
+    assert_eq!(gray.to_argb_u32(0).unwrap(), [0x007F7F7F]);
+
+    // Alpha comes from the bitmap when it has it, and 16 bit samples keep their high byte
+    let rgba = Bitmap::new(1, 1, 4, vec![1, 2, 3, 4]).unwrap();
+    assert_eq!(rgba.to_argb_u32(0xFF).unwrap(), [0x04010203]);
+    let wide = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 16,
+        size: (1, 1),
+        data: vec![0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC],
+    };
+    assert_eq!(wide.to_rgba_u32(0xFF).unwrap(), [0x12569AFF]);
+
+    let unpacked = wide.to_rgba(0x80).unwrap();
+    assert_eq!(
+        (unpacked.channels, unpacked.color_space),
+        (4, ColorSpace::Rgba)
+    );
+    assert_eq!((unpacked.bit_depth, unpacked.size), (8, (1, 1)));
+    assert_eq!(unpacked.data, [0x12, 0x56, 0x9A, 0x80]);
+
+    let cmyk = Bitmap {
+        color_space: ColorSpace::Cmyk,
+        ..rgba
+    };
+    assert!(matches!(
+        cmyk.to_argb_u32(0xFF),
+        Err(Error::UnsupportedFeature(_))
+    ));
+    assert!(cmyk.to_rgba(0xFF).is_err());
+}
+
+#[test]
+fn histogram() {
+    // 2x2 RGB image, red is always 255, green is 0 or 100 and blue counts up
+    let bitmap = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 8,
+        size: (2, 2),
+        data: vec![255, 0, 1, 255, 100, 2, 255, 0, 3, 255, 0, 3],
+    };
+    let histograms = bitmap.histogram();
+    assert_eq!(histograms.len(), 3);
+
+    assert_eq!(histograms[0][255], 4);
+    assert_eq!((histograms[1][0], histograms[1][100]), (3, 1));
+    assert_eq!(&histograms[2][..5], &[0, 1, 1, 2, 0]);
+    for histogram in &histograms {
+        assert_eq!(histogram.iter().sum::<u32>(), 4);
+    }
+
+    // 16 bit samples are binned by their high byte
+    let deep = Bitmap {
+        channels: 1,
+        color_space: ColorSpace::Gray,
+        bit_depth: 16,
+        size: (3, 1),
+        data: [256u16, 511, 65535]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect(),
+    };
+    let histograms = deep.histogram();
+    assert_eq!((histograms[0][0], histograms[0][1]), (0, 2));
+    assert_eq!(histograms[0][255], 1);
+}
+
+#[test]
+fn composite_over() {
+    let bitmap = Bitmap {
+        channels: 4,
+        color_space: ColorSpace::Rgba,
+        bit_depth: 8,
+        size: (2, 2),
+        data: vec![
+            200, 100, 50, 255, // Opaque
+            200, 100, 50, 0, // Transparent
+            200, 100, 50, 128, // Half
+            0, 255, 0, 64, // Quarter
+        ],
+    };
+    let flattened = bitmap.composite_over((0, 0, 255));
+    assert_eq!(flattened.channels, 3);
+    assert_eq!(flattened.color_space, ColorSpace::Rgb);
+    assert_eq!(flattened.size, (2, 2));
+    assert_eq!(
+        flattened.data,
+        [200, 100, 50, 0, 0, 255, 100, 50, 152, 0, 64, 191]
+    );
+
+    let rgb = test_bitmap();
+    assert_eq!(rgb.composite_over((255, 255, 255)), rgb);
+
+    let deep = Bitmap {
+        channels: 4,
+        color_space: ColorSpace::Rgba,
+        bit_depth: 16,
+        size: (2, 1),
+        data: [1000u16, 2000, 3000, 65535, 40000, 0, 65535, 0]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .collect(),
+    };
+    let flattened = deep.composite_over((0, 255, 0));
+    assert_eq!((flattened.channels, flattened.bit_depth), (3, 16));
+    assert_eq!(
+        flattened.to_u16_samples().unwrap(),
+        [1000, 2000, 3000, 0, 65535, 0]
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn save() {
+    let bitmap = test_bitmap();
+    let directory = std::env::temp_dir();
+    let path = |extension: &str| {
+        let name = format!("bitmap-save-{}.{}", std::process::id(), extension);
+        directory.join(name).to_str().unwrap().to_string()
+    };
+
+    let ppm = path("ppm");
+    bitmap.save(&ppm).unwrap();
+    let contents = std::fs::read(&ppm).unwrap();
+    assert!(contents.starts_with(b"P3\n3 2\n255\n"));
+
+    let deep = Bitmap {
+        bit_depth: 16,
+        size: (1, 1),
+        data: vec![1, 0, 0, 2, 255, 255],
+        ..bitmap.clone()
+    };
+    deep.save(&ppm).unwrap();
+    let contents = std::fs::read(&ppm).unwrap();
+    assert_eq!(contents, b"P3\n1 1\n65535\n256 2 65535\n");
+
+    let jpeg = path("JPG");
+    bitmap.save(&jpeg).unwrap();
+    let contents = std::fs::read(&jpeg).unwrap();
+    assert_eq!(contents[..2], [0xFF, 0xD8]);
+
+    let png = path("png");
+    bitmap.save(&png).unwrap();
+    let contents = std::fs::read(&png).unwrap();
+    assert!(contents.starts_with(b"\x89PNG"));
+
+    let unknown = path("tiff");
+    assert!(matches!(
+        bitmap.save(&unknown),
+        Err(Error::UnsupportedFeature(_))
+    ));
+    assert!(!std::path::Path::new(&unknown).exists());
+
+    std::fs::remove_file(ppm).unwrap();
+    std::fs::remove_file(jpeg).unwrap();
+    std::fs::remove_file(png).unwrap();
+}
@@ -0,0 +1,197 @@
+//! Conversions between `Bitmap` and the `image` crate's buffers. The conversions into the `image`
+//! crate are fallible since a `Bitmap`'s data isn't guaranteed to match its size, and CMYK/YCCK
+//! bitmaps have no equivalent there.
+
+use ::image::{DynamicImage, GrayImage, RgbImage, RgbaImage};
+
+use crate::{
+    error::Error,
+    image::{Bitmap, ColorSpace},
+};
+
+impl TryFrom<Bitmap> for DynamicImage {
+    type Error = Error;
+
+    fn try_from(bitmap: Bitmap) -> Result<Self, Self::Error> {
+        if bitmap.bit_depth != 8 {
+            return Err(Error::UnsupportedFeature(
+                "Only 8 bit bitmaps can be converted to the image crate".into(),
+            ));
+        }
+
+        let (width, height) = (bitmap.width(), bitmap.height());
+        let image = match bitmap.color_space {
+            ColorSpace::Gray => {
+                GrayImage::from_raw(width, height, bitmap.data).map(DynamicImage::ImageLuma8)
+            }
+            ColorSpace::Rgb => {
+                RgbImage::from_raw(width, height, bitmap.data).map(DynamicImage::ImageRgb8)
+            }
+            ColorSpace::Bgr => {
+                let mut data = bitmap.data;
+                data.chunks_exact_mut(3).for_each(|pixel| pixel.swap(0, 2));
+                RgbImage::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+            }
+            ColorSpace::Rgba => {
+                RgbaImage::from_raw(width, height, bitmap.data).map(DynamicImage::ImageRgba8)
+            }
+            ColorSpace::Cmyk | ColorSpace::Ycck => {
+                return Err(Error::UnsupportedFeature(
+                    "The image crate has no CMYK or YCCK representation".into(),
+                ))
+            }
+        };
+
+        image.ok_or_else(|| Error::Malformed("Bitmap data doesn't match its size".into()))
+    }
+}
+
+impl TryFrom<Bitmap> for RgbImage {
+    type Error = Error;
+
+    /// Converts the bitmap to RGB, expanding grayscale and dropping alpha if needed
+    fn try_from(bitmap: Bitmap) -> Result<Self, Self::Error> {
+        match DynamicImage::try_from(bitmap)? {
+            DynamicImage::ImageRgb8(image) => Ok(image),
+            image => Ok(image.to_rgb8()),
+        }
+    }
+}
+
+impl TryFrom<RgbImage> for Bitmap {
+    type Error = Error;
+
+    fn try_from(image: RgbImage) -> Result<Self, Self::Error> {
+        Ok(Bitmap {
+            channels: 3,
+            color_space: ColorSpace::Rgb,
+            bit_depth: 8,
+            size: bitmap_size(image.width(), image.height())?,
+            data: image.into_raw(),
+        })
+    }
+}
+
+impl TryFrom<DynamicImage> for Bitmap {
+    type Error = Error;
+
+    /// Converts the image to a grayscale, RGB, or RGBA bitmap, whichever is closest. Samples with
+    /// more than 8 bits are reduced to 8.
+    fn try_from(image: DynamicImage) -> Result<Self, Self::Error> {
+        let size = bitmap_size(image.width(), image.height())?;
+        match image {
+            DynamicImage::ImageLuma8(image) => Ok(Bitmap {
+                channels: 1,
+                color_space: ColorSpace::Gray,
+                bit_depth: 8,
+                size,
+                data: image.into_raw(),
+            }),
+            DynamicImage::ImageRgba8(image) => Ok(Bitmap {
+                channels: 4,
+                color_space: ColorSpace::Rgba,
+                bit_depth: 8,
+                size,
+                data: image.into_raw(),
+            }),
+            image if image.color().has_alpha() => Ok(Bitmap {
+                channels: 4,
+                color_space: ColorSpace::Rgba,
+                bit_depth: 8,
+                size,
+                data: image.to_rgba8().into_raw(),
+            }),
+            image => Bitmap::try_from(image.to_rgb8()),
+        }
+    }
+}
+
+/// Checks that an image from the `image` crate fits in a `Bitmap`, whose sides are at most 65535
+/// pixels
+fn bitmap_size(width: u32, height: u32) -> Result<(u16, u16), Error> {
+    match (u16::try_from(width), u16::try_from(height)) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(Error::UnsupportedFeature(
+            "Bitmaps can't be wider or taller than 65535 pixels".into(),
+        )),
+    }
+}
+
+#[test]
+fn rgb_round_trip() {
+    let bitmap = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 8,
+        size: (3, 2),
+        data: (0..18).collect(),
+    };
+
+    let image = RgbImage::try_from(bitmap.clone()).unwrap();
+    assert_eq!(image.dimensions(), (3, 2));
+    assert_eq!(image.get_pixel(1, 1).0, [12, 13, 14]);
+
+    assert_eq!(Bitmap::try_from(image).unwrap(), bitmap);
+}
+
+#[test]
+fn gray_to_rgb() {
+    let bitmap = Bitmap {
+        channels: 1,
+        color_space: ColorSpace::Gray,
+        bit_depth: 8,
+        size: (2, 1),
+        data: vec![7, 9],
+    };
+
+    let image = RgbImage::try_from(bitmap.clone()).unwrap();
+    assert_eq!(image.get_pixel(1, 0).0, [9, 9, 9]);
+
+    let dynamic = DynamicImage::try_from(bitmap.clone()).unwrap();
+    assert_eq!(Bitmap::try_from(dynamic).unwrap(), bitmap);
+}
+
+#[test]
+fn bgr_to_rgb() {
+    let bitmap = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Bgr,
+        bit_depth: 8,
+        size: (2, 1),
+        data: vec![1, 2, 3, 4, 5, 6],
+    };
+
+    let image = RgbImage::try_from(bitmap).unwrap();
+    assert_eq!(image.get_pixel(0, 0).0, [3, 2, 1]);
+    assert_eq!(image.get_pixel(1, 0).0, [6, 5, 4]);
+}
+
+#[test]
+fn mismatched_size() {
+    let bitmap = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 8,
+        size: (3, 2),
+        data: vec![0; 6],
+    };
+    assert!(RgbImage::try_from(bitmap).is_err());
+}
+
+#[test]
+fn too_large() {
+    let wide = RgbImage::new(u16::MAX as u32 + 1, 1);
+    assert!(matches!(
+        Bitmap::try_from(wide),
+        Err(Error::UnsupportedFeature(_))
+    ));
+
+    let tall = DynamicImage::new_luma8(1, u16::MAX as u32 + 1);
+    assert!(matches!(
+        Bitmap::try_from(tall),
+        Err(Error::UnsupportedFeature(_))
+    ));
+
+    let widest = RgbImage::new(u16::MAX as u32, 1);
+    assert_eq!(Bitmap::try_from(widest).unwrap().size, (u16::MAX, 1));
+}
@@ -0,0 +1,50 @@
+use alloc::{vec, vec::Vec};
+
+/// The quantized DCT coefficients of every block of one component, for the whole image. Each block
+/// is kept in zigzag order as it's decoded. Keeping the whole image, rather than one MCU at a time,
+/// lets later scans refine the coefficients decoded by earlier ones.
+#[derive(Debug, Clone)]
+pub struct CoefficientPlane {
+    /// The number of blocks horizontally and vertically, including MCU padding
+    pub block_dimensions: (usize, usize),
+    blocks: Vec<[i16; 64]>,
+}
+
+impl CoefficientPlane {
+    pub fn new(block_dimensions: (usize, usize)) -> Self {
+        Self {
+            block_dimensions,
+            blocks: vec![[0; 64]; block_dimensions.0 * block_dimensions.1],
+        }
+    }
+
+    pub fn block(&self, x: usize, y: usize) -> &[i16; 64] {
+        &self.blocks[y * self.block_dimensions.0 + x]
+    }
+
+    pub fn block_mut(&mut self, x: usize, y: usize) -> &mut [i16; 64] {
+        &mut self.blocks[y * self.block_dimensions.0 + x]
+    }
+
+    /// Sets every coefficient back to zero
+    pub fn clear(&mut self) {
+        self.blocks.fill([0; 64]);
+    }
+
+    /// All blocks in raster order
+    pub fn blocks(&self) -> &[[i16; 64]] {
+        &self.blocks
+    }
+}
+
+#[test]
+fn block_indexing() {
+    let mut plane = CoefficientPlane::new((3, 2));
+    plane.block_mut(2, 1)[0] = 7;
+    plane.block_mut(0, 1)[63] = -3;
+
+    assert_eq!(plane.blocks().len(), 6);
+    assert_eq!(plane.block(2, 1)[0], 7);
+    assert_eq!(plane.blocks()[5][0], 7);
+    assert_eq!(plane.blocks()[3][63], -3);
+}
@@ -0,0 +1,158 @@
+#[cfg(feature = "simd")]
+use wide::f32x8;
+
+/// Converts a single level shifted YCbCr sample to RGB.
+pub fn ycbcr_to_rgb(y_cb_cr: (i16, i16, i16)) -> (u8, u8, u8) {
+    let (red, green, blue) =
+        ycbcr_to_rgb_f32((y_cb_cr.0 as f32, y_cb_cr.1 as f32, y_cb_cr.2 as f32));
+    (red as u8, green as u8, blue as u8)
+}
+
+/// Converts a single level shifted YCbCr sample to RGB on the 0 to 255 scale, without rounding or
+/// clamping the result.
+pub fn ycbcr_to_rgb_f32(y_cb_cr: (f32, f32, f32)) -> (f32, f32, f32) {
+    let (lum, cb, cr) = y_cb_cr;
+
+    let red = (cr * (2f32 - 2f32 * 0.299)) + lum;
+    let blue = (cb * (2f32 - 2f32 * 0.114)) + lum;
+    let green = (lum - (0.114 * blue) - (0.299 * red)) / 0.587;
+
+    (red + 128f32, green + 128f32, blue + 128f32)
+}
+
+/// Converts an RGB pixel to level shifted YCbCr, the inverse of `ycbcr_to_rgb`.
+#[cfg(feature = "std")]
+pub fn rgb_to_ycbcr(rgb: (u8, u8, u8)) -> (f32, f32, f32) {
+    let red = rgb.0 as f32;
+    let green = rgb.1 as f32;
+    let blue = rgb.2 as f32;
+
+    let lum = 0.299 * red + 0.587 * green + 0.114 * blue;
+    let cb = (blue - lum) / (2f32 - 2f32 * 0.114);
+    let cr = (red - lum) / (2f32 - 2f32 * 0.299);
+
+    (lum - 128f32, cb, cr)
+}
+
+/// Converts a row of level shifted YCbCr samples into interleaved RGB. `rgb` must hold three
+/// bytes for every sample.
+pub fn ycbcr_row_to_rgb(lum: &[i16], cb: &[i16], cr: &[i16], rgb: &mut [u8]) {
+    ycbcr_row_to_pixels::<3>(lum, cb, cr, rgb);
+}
+
+/// Converts a row of level shifted YCbCr samples into interleaved RGBA with an opaque alpha.
+/// `rgba` must hold four bytes for every sample.
+pub fn ycbcr_row_to_rgba(lum: &[i16], cb: &[i16], cr: &[i16], rgba: &mut [u8]) {
+    ycbcr_row_to_pixels::<4>(lum, cb, cr, rgba);
+}
+
+/// Converts a row into pixels of `CHANNELS` bytes, RGB followed by 255 for any fourth byte
+fn ycbcr_row_to_pixels<const CHANNELS: usize>(
+    lum: &[i16],
+    cb: &[i16],
+    cr: &[i16],
+    pixels: &mut [u8],
+) {
+    #[cfg(feature = "simd")]
+    ycbcr_row_to_rgb_simd::<CHANNELS>(lum, cb, cr, pixels);
+    #[cfg(not(feature = "simd"))]
+    ycbcr_row_to_rgb_scalar::<CHANNELS>(lum, cb, cr, pixels);
+}
+
+fn ycbcr_row_to_rgb_scalar<const CHANNELS: usize>(
+    lum: &[i16],
+    cb: &[i16],
+    cr: &[i16],
+    pixels: &mut [u8],
+) {
+    for (i, pixel) in pixels.chunks_exact_mut(CHANNELS).enumerate() {
+        let (red, green, blue) = ycbcr_to_rgb((lum[i], cb[i], cr[i]));
+        pixel[..3].copy_from_slice(&[red, green, blue]);
+        if CHANNELS == 4 {
+            pixel[3] = 255;
+        }
+    }
+}
+
+/// Same as the scalar conversion, but handles 8 pixels at a time. Any pixels left over at the end
+/// of the row go through the scalar path.
+#[cfg(feature = "simd")]
+fn ycbcr_row_to_rgb_simd<const CHANNELS: usize>(
+    lum: &[i16],
+    cb: &[i16],
+    cr: &[i16],
+    pixels: &mut [u8],
+) {
+    let vector_length = lum.len() / 8 * 8;
+
+    let load = |samples: &[i16]| f32x8::from(core::array::from_fn(|i| samples[i] as f32));
+    // Saturates and truncates the same way as casting a float to u8
+    let to_bytes = |values: f32x8| {
+        (values + f32x8::splat(128.0))
+            .max(f32x8::ZERO)
+            .min(f32x8::splat(255.0))
+            .trunc_int()
+            .to_array()
+    };
+
+    for start in (0..vector_length).step_by(8) {
+        let end = start + 8;
+        let lum = load(&lum[start..end]);
+        let cb = load(&cb[start..end]);
+        let cr = load(&cr[start..end]);
+
+        let red = (cr * f32x8::splat(2f32 - 2f32 * 0.299)) + lum;
+        let blue = (cb * f32x8::splat(2f32 - 2f32 * 0.114)) + lum;
+        let green = (lum - (f32x8::splat(0.114) * blue) - (f32x8::splat(0.299) * red))
+            / f32x8::splat(0.587);
+
+        let (red, green, blue) = (to_bytes(red), to_bytes(green), to_bytes(blue));
+        let row = &mut pixels[start * CHANNELS..end * CHANNELS];
+        for (i, pixel) in row.chunks_exact_mut(CHANNELS).enumerate() {
+            pixel[..3].copy_from_slice(&[red[i] as u8, green[i] as u8, blue[i] as u8]);
+            if CHANNELS == 4 {
+                pixel[3] = 255;
+            }
+        }
+    }
+
+    ycbcr_row_to_rgb_scalar::<CHANNELS>(
+        &lum[vector_length..],
+        &cb[vector_length..],
+        &cr[vector_length..],
+        &mut pixels[vector_length * CHANNELS..],
+    );
+}
+
+#[cfg(feature = "simd")]
+#[test]
+fn simd_matches_scalar() {
+    // Simple LCG so the test doesn't need a random number crate
+    let mut state = 0x2545_F491u32;
+    let mut next_sample = || {
+        state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        ((state >> 16) % 256) as i16 - 128
+    };
+
+    // Not a multiple of 8, so the scalar tail is exercised too
+    let length = 1003;
+    let lum: alloc::vec::Vec<i16> = (0..length).map(|_| next_sample()).collect();
+    let cb: alloc::vec::Vec<i16> = (0..length).map(|_| next_sample()).collect();
+    let cr: alloc::vec::Vec<i16> = (0..length).map(|_| next_sample()).collect();
+
+    let mut scalar = alloc::vec![0u8; length * 3];
+    let mut simd = alloc::vec![0u8; length * 3];
+    ycbcr_row_to_rgb_scalar::<3>(&lum, &cb, &cr, &mut scalar);
+    ycbcr_row_to_rgb_simd::<3>(&lum, &cb, &cr, &mut simd);
+
+    for (scalar, simd) in scalar.iter().zip(&simd) {
+        assert!(scalar.abs_diff(*simd) <= 1, "{} vs {}", scalar, simd);
+    }
+
+    let mut rgba = alloc::vec![0u8; length * 4];
+    ycbcr_row_to_rgb_simd::<4>(&lum, &cb, &cr, &mut rgba);
+    for (rgb, rgba) in simd.chunks_exact(3).zip(rgba.chunks_exact(4)) {
+        assert_eq!(rgb, &rgba[..3]);
+        assert_eq!(rgba[3], 255);
+    }
+}
@@ -0,0 +1,306 @@
+//! The forward and inverse discrete cosine transforms used by JPEG, on 8x8 blocks. Samples are
+//! level shifted to range from -128 to 127, and coefficients are indexed `[v][u]`, with the
+//! vertical frequency first, in natural rather than zigzag order.
+
+use alloc::vec::Vec;
+use core::f32::consts::FRAC_1_SQRT_2;
+
+/// `IDCT_COSINES[x][u]` is cos((2x + 1)uπ / 16), precomputed since core has no cos.
+#[rustfmt::skip]
+#[allow(clippy::excessive_precision)]
+const IDCT_COSINES: [[f32; 8]; 8] = [
+    [1.000000000, 0.980785280, 0.923879533, 0.831469612, FRAC_1_SQRT_2, 0.555570233, 0.382683432, 0.195090322],
+    [1.000000000, 0.831469612, 0.382683432, -0.195090322, -FRAC_1_SQRT_2, -0.980785280, -0.923879533, -0.555570233],
+    [1.000000000, 0.555570233, -0.382683432, -0.980785280, -FRAC_1_SQRT_2, 0.195090322, 0.923879533, 0.831469612],
+    [1.000000000, 0.195090322, -0.923879533, -0.555570233, FRAC_1_SQRT_2, 0.831469612, -0.382683432, -0.980785280],
+    [1.000000000, -0.195090322, -0.923879533, 0.555570233, FRAC_1_SQRT_2, -0.831469612, -0.382683432, 0.980785280],
+    [1.000000000, -0.555570233, -0.382683432, 0.980785280, -FRAC_1_SQRT_2, -0.195090322, 0.923879533, -0.831469612],
+    [1.000000000, -0.831469612, 0.382683432, 0.195090322, -FRAC_1_SQRT_2, 0.980785280, -0.923879533, 0.555570233],
+    [1.000000000, -0.980785280, 0.923879533, -0.831469612, FRAC_1_SQRT_2, -0.555570233, 0.382683432, -0.195090322],
+];
+
+/// `IDCT_FIXED_POINT[x][u]` is C(u)cos((2x + 1)uπ / 16) scaled by 2^13, for the integer IDCT.
+#[rustfmt::skip]
+const IDCT_FIXED_POINT: [[i64; 8]; 8] = [
+    [5793, 8035, 7568, 6811, 5793, 4551, 3135, 1598],
+    [5793, 6811, 3135, -1598, -5793, -8035, -7568, -4551],
+    [5793, 4551, -3135, -8035, -5793, 1598, 7568, 6811],
+    [5793, 1598, -7568, -4551, 5793, 6811, -3135, -8035],
+    [5793, -1598, -7568, 4551, 5793, -6811, -3135, 8035],
+    [5793, -4551, -3135, 8035, -5793, -1598, 7568, -6811],
+    [5793, -6811, 3135, 1598, -5793, 8035, -7568, 4551],
+    [5793, -8035, 7568, -6811, 5793, -4551, 3135, -1598],
+];
+
+/// `IDCT_FAST[x][u]` is C(u)cos((2x + 1)uπ / 16) scaled by 2^8, for the fast IDCT.
+#[rustfmt::skip]
+const IDCT_FAST: [[i32; 8]; 8] = [
+    [181, 251, 237, 213, 181, 142, 98, 50],
+    [181, 213, 98, -50, -181, -251, -237, -142],
+    [181, 142, -98, -251, -181, 50, 237, 213],
+    [181, 50, -237, -142, 181, 213, -98, -251],
+    [181, -50, -237, 142, 181, -213, -98, 251],
+    [181, -142, -98, 251, -181, -50, 237, -213],
+    [181, -213, 98, 50, -181, 251, -237, 142],
+    [181, -251, 237, -213, 181, -142, 98, -50],
+];
+
+/// Transforms a block of level shifted samples into DCT coefficients, rounded to the nearest
+/// integer. This is the inverse of `inverse_dct`, up to rounding.
+pub fn forward_dct(block: &[[i16; 8]; 8]) -> [[i16; 8]; 8] {
+    let coefficients = forward_dct_float(&block.map(|row| row.map(|sample| sample as f32)));
+    coefficients.map(|row| row.map(round))
+}
+
+/// Transforms a block of DCT coefficients back into level shifted samples, the same way the
+/// decoder does with `IdctMethod::Float`. Samples are range limited to [-128, 127].
+pub fn inverse_dct(coefficients: &[[i16; 8]; 8]) -> [[i16; 8]; 8] {
+    let mut block: Vec<Vec<i16>> = coefficients.iter().map(|row| row.to_vec()).collect();
+    inverse_dct_in_place(&mut block, 0, 0);
+    core::array::from_fn(|y| core::array::from_fn(|x| block[y][x]))
+}
+
+/// The forward DCT of a block of level shifted samples, indexed `[v][u]` like the coefficients
+/// the IDCT takes.
+/// https://www.w3.org/Graphics/JPEG/itu-t81.pdf A.3.3 Page 27
+pub(crate) fn forward_dct_float(block: &[[f32; 8]; 8]) -> [[f32; 8]; 8] {
+    let scale = |u: usize| if u == 0 { FRAC_1_SQRT_2 } else { 1.0 };
+
+    // Transform the rows, then the columns
+    let rows: [[f32; 8]; 8] = core::array::from_fn(|y| {
+        core::array::from_fn(|u| (0..8).map(|x| block[y][x] * IDCT_COSINES[x][u]).sum())
+    });
+    core::array::from_fn(|v| {
+        core::array::from_fn(|u| {
+            let sum: f32 = (0..8).map(|y| rows[y][u] * IDCT_COSINES[y][v]).sum();
+            sum * scale(u) * scale(v) / 4.0
+        })
+    })
+}
+
+/// Rounds half away from zero, since core has no `f32::round`
+pub(crate) fn round(value: f32) -> i16 {
+    if value < 0.0 {
+        (value - 0.5) as i16
+    } else {
+        (value + 0.5) as i16
+    }
+}
+
+/// Same as `inverse_dct_in_place`, but in fixed point so the result doesn't depend on the platform's
+/// float behavior. The rows and columns are transformed separately and the result is rounded
+/// rather than truncated.
+pub(crate) fn inverse_dct_integer_in_place(
+    component_block: &mut [Vec<i16>],
+    base_y: usize,
+    base_x: usize,
+) {
+    // Transform the rows. Scaled by 2^13
+    let mut rows = [[0i64; 8]; 8];
+    for (v, row) in rows.iter_mut().enumerate() {
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = (0..8)
+                .map(|u| IDCT_FIXED_POINT[x][u] * component_block[base_y + v][base_x + u] as i64)
+                .sum();
+        }
+    }
+
+    // Then the columns. Scaled by 2^26, and the 1/4 from the formula adds two more bits
+    for y in 0..8 {
+        for x in 0..8 {
+            let value: i64 = (0..8).map(|v| IDCT_FIXED_POINT[y][v] * rows[v][x]).sum();
+            let value = (value + (1 << 27)) >> 28;
+
+            // Range limiting for 8 bit samples. F.2.1.5 Page 103
+            component_block[base_y + y][base_x + x] = value.clamp(-128, 127) as i16;
+        }
+    }
+}
+
+/// A faster but less precise version of `inverse_dct_in_place`. Blocks with only a DC coefficient,
+/// which are most blocks in a typical image, are filled without a transform, and the rest are
+/// transformed separately by rows and columns in 32 bit fixed point with 8 bit cosines.
+pub(crate) fn inverse_dct_fast_in_place(
+    component_block: &mut [Vec<i16>],
+    base_y: usize,
+    base_x: usize,
+) {
+    let block_rows = &mut component_block[base_y..base_y + 8];
+
+    let dc_only = block_rows.iter().enumerate().all(|(v, row)| {
+        row[base_x..base_x + 8]
+            .iter()
+            .enumerate()
+            .all(|(u, coefficient)| *coefficient == 0 || u + v == 0)
+    });
+    if dc_only {
+        // The DC coefficient is 8 times the value of every sample
+        let value = ((block_rows[0][base_x] as i32 + 4) >> 3).clamp(-128, 127) as i16;
+        for row in block_rows {
+            row[base_x..base_x + 8].fill(value);
+        }
+        return;
+    }
+
+    // Transform the rows, keeping 3 fractional bits so the columns fit in 32 bits
+    let mut rows = [[0i32; 8]; 8];
+    for (v, row) in rows.iter_mut().enumerate() {
+        for (x, value) in row.iter_mut().enumerate() {
+            let sum: i32 = (0..8)
+                .map(|u| IDCT_FAST[x][u] * block_rows[v][base_x + u] as i32)
+                .sum();
+            *value = (sum + (1 << 4)) >> 5;
+        }
+    }
+
+    // Then the columns. Scaled by 2^11, and the 1/4 from the formula adds two more bits
+    for (y, block_row) in block_rows.iter_mut().enumerate() {
+        for x in 0..8 {
+            let value: i32 = (0..8).map(|v| IDCT_FAST[y][v] * rows[v][x]).sum();
+            let value = (value + (1 << 12)) >> 13;
+
+            // Range limiting for 8 bit samples. F.2.1.5 Page 103
+            block_row[base_x + x] = value.clamp(-128, 127) as i16;
+        }
+    }
+}
+
+/// Performs the IDCT in place on the 8x8 block at (base_x, base_y), then range limits the
+/// reconstructed samples to [-128, 127] so they can't wrap once level shifted.
+pub(crate) fn inverse_dct_in_place(component_block: &mut [Vec<i16>], base_y: usize, base_x: usize) {
+    // https://www.w3.org/Graphics/JPEG/itu-t81.pdf
+    // A.3.3 Page 27
+    let mut idct_block = [[0i16; 8]; 8];
+    for (y, idct_row) in idct_block.iter_mut().enumerate() {
+        for (x, idct_sample) in idct_row.iter_mut().enumerate() {
+            let mut value = 0.0f32;
+            for u in 0..8 {
+                for v in 0..8 {
+                    let cu = if u == 0 { FRAC_1_SQRT_2 } else { 1.0f32 };
+                    let cv = if v == 0 { FRAC_1_SQRT_2 } else { 1f32 };
+                    let idct_val = cu * cv * IDCT_COSINES[x][u] * IDCT_COSINES[y][v];
+
+                    let coeff = component_block[base_y + v][base_x + u] as f32;
+                    value += idct_val * coeff;
+                }
+            }
+
+            value /= 4.0f32;
+
+            // Range limiting for 8 bit samples. F.2.1.5 Page 103
+            *idct_sample = value.clamp(-128.0f32, 127.0f32) as i16;
+        }
+    }
+
+    for (y, idct_row) in idct_block.iter().enumerate() {
+        component_block[base_y + y][base_x..base_x + 8].copy_from_slice(idct_row);
+    }
+}
+
+#[test]
+fn idct_clamps_overshoot() {
+    let mut block = alloc::vec![alloc::vec![0i16; 16]; 16];
+    block[8][8] = 2000; // DC only, reconstructs to a flat 250 before range limiting
+    block[0][0] = -2000;
+    inverse_dct_in_place(&mut block, 8, 8);
+    inverse_dct_in_place(&mut block, 0, 0);
+
+    assert_eq!(block[8][8], 127);
+    assert_eq!(block[15][15], 127);
+    assert_eq!(block[0][0], -128);
+    assert_eq!(block[7][7], -128);
+    assert_eq!(block[0][8], 0);
+}
+
+#[test]
+fn integer_idct_matches_float() {
+    // A mix of DC only, low frequency, and high frequency blocks, including ones that overshoot
+    let mut block = alloc::vec![alloc::vec![0i16; 16]; 16];
+    block[0][0] = 300;
+    block[0][1] = -75;
+    block[2][3] = 40;
+    block[8][8] = -900;
+    block[9][8] = 220;
+    block[15][15] = 60;
+    block[0][15] = 1200;
+    block[3][12] = -500;
+    block[12][2] = 33;
+    block[13][5] = -17;
+
+    let mut float = block.clone();
+    let mut integer = block.clone();
+    let mut integer_again = block;
+    for (base_y, base_x) in [(0, 0), (0, 8), (8, 0), (8, 8)] {
+        inverse_dct_in_place(&mut float, base_y, base_x);
+        inverse_dct_integer_in_place(&mut integer, base_y, base_x);
+        inverse_dct_integer_in_place(&mut integer_again, base_y, base_x);
+    }
+
+    assert_eq!(integer, integer_again);
+    for (float_row, integer_row) in float.iter().zip(&integer) {
+        for (float, integer) in float_row.iter().zip(integer_row) {
+            assert!(float.abs_diff(*integer) <= 1, "{} vs {}", float, integer);
+        }
+    }
+}
+
+#[test]
+fn fast_idct_close_to_float() {
+    let mut block = alloc::vec![alloc::vec![0i16; 16]; 16];
+    block[0][0] = 300;
+    block[0][1] = -75;
+    block[2][3] = 40;
+    block[0][8] = -900; // DC only
+    block[8][0] = 2000; // DC only and overshoots
+    block[8][8] = -900;
+    block[9][8] = 220;
+    block[15][15] = 60;
+    block[11][12] = -500;
+
+    let mut float = block.clone();
+    let mut fast = block;
+    for (base_y, base_x) in [(0, 0), (0, 8), (8, 0), (8, 8)] {
+        inverse_dct_in_place(&mut float, base_y, base_x);
+        inverse_dct_fast_in_place(&mut fast, base_y, base_x);
+    }
+
+    assert_eq!(fast[0][8], -112);
+    assert_eq!(fast[8][0], 127);
+    for (float_row, fast_row) in float.iter().zip(&fast) {
+        for (float, fast) in float_row.iter().zip(fast_row) {
+            assert!(float.abs_diff(*fast) <= 2, "{} vs {}", float, fast);
+        }
+    }
+}
+
+#[test]
+fn forward_then_inverse() {
+    // Pseudo random samples covering the whole range
+    let mut state = 12345u32;
+    for _ in 0..16 {
+        let block: [[i16; 8]; 8] = core::array::from_fn(|_| {
+            core::array::from_fn(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                ((state >> 16) % 256) as i16 - 128
+            })
+        });
+
+        let coefficients = forward_dct(&block);
+        let reconstructed = inverse_dct(&coefficients);
+        for (row, reconstructed_row) in block.iter().zip(&reconstructed) {
+            for (sample, reconstructed) in row.iter().zip(reconstructed_row) {
+                assert!(
+                    sample.abs_diff(*reconstructed) <= 1,
+                    "{} vs {}",
+                    sample,
+                    reconstructed
+                );
+            }
+        }
+    }
+
+    // A flat block only has a DC coefficient, of 8 times the sample value
+    let coefficients = forward_dct(&[[-100; 8]; 8]);
+    assert_eq!(coefficients[0][0], -800);
+    assert!(coefficients.iter().flatten().skip(1).all(|c| *c == 0));
+}
@@ -0,0 +1,455 @@
+use std::{fs::File, io, io::Write};
+
+use crate::{
+    error::{Error, Result},
+    image::{Bitmap, ColorSpace, ImageEncoder},
+};
+
+use super::{
+    color,
+    dct::{forward_dct_float, round},
+    header::{HuffmanTable, HuffmanTableType},
+    standard_tables::{CHROMA_QUANTIZATION, LUMA_QUANTIZATION},
+    zigzag::ZIGZAG_MAP,
+};
+
+/// How much the chroma components are subsampled when encoding
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChromaSubsampling {
+    /// 4:4:4, chroma is stored at full resolution
+    #[default]
+    Ratio444,
+    /// 4:2:0, chroma is stored at half resolution in both directions
+    Ratio420,
+}
+
+/// Baseline JPEG encoder. Uses the Annex K quantization tables scaled by the quality, and the Annex
+/// K Huffman tables.
+pub struct JPEGEncoder<'bitmap> {
+    bitmap: &'bitmap Bitmap,
+    quality: u8,
+    subsampling: ChromaSubsampling,
+}
+
+/// A Huffman code and its length in bits, indexed by symbol
+type CodeLookup = [(u16, u8); 256];
+
+/// The tables used to encode one component
+struct ComponentTables {
+    quantization: [[u16; 8]; 8],
+    dc_codes: CodeLookup,
+    ac_codes: CodeLookup,
+}
+
+impl<'bitmap> JPEGEncoder<'bitmap> {
+    /// Sets the quality from 1 to 100, where higher is better. Values outside that range are
+    /// clamped. Defaults to 90.
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Sets how the chroma components are subsampled. Defaults to 4:4:4.
+    pub fn with_subsampling(mut self, subsampling: ChromaSubsampling) -> Self {
+        self.subsampling = subsampling;
+        self
+    }
+
+    /// Encodes the bitmap and returns the JPEG file data. Only RGB bitmaps are supported.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let bitmap = self.bitmap;
+        if bitmap.color_space != ColorSpace::Rgb || bitmap.channels != 3 {
+            return Err(Error::UnsupportedFeature(
+                "JPEG encoder only supports RGB bitmaps".into(),
+            ));
+        }
+        if bitmap.bit_depth != 8 {
+            return Err(Error::UnsupportedFeature(
+                "JPEG encoder only supports 8 bit samples".into(),
+            ));
+        }
+        if bitmap.size.0 == 0 || bitmap.size.1 == 0 {
+            return Err(Error::Malformed("image has zero dimension".into()));
+        }
+        if bitmap.data.len() != bitmap.size.0 as usize * bitmap.size.1 as usize * 3 {
+            return Err(Error::Malformed(
+                "Bitmap data doesn't match its size".into(),
+            ));
+        }
+
+        let luma_factor: u8 = match self.subsampling {
+            ChromaSubsampling::Ratio444 => 1,
+            ChromaSubsampling::Ratio420 => 2,
+        };
+        let mcu_size = 8 * luma_factor as usize;
+        let mcus = (
+            (bitmap.size.0 as usize).div_ceil(mcu_size),
+            (bitmap.size.1 as usize).div_ceil(mcu_size),
+        );
+
+        let luma_tables = ComponentTables {
+            quantization: scale_quantization_table(&LUMA_QUANTIZATION, self.quality),
+            dc_codes: code_lookup(&HuffmanTable::standard_luma_dc()),
+            ac_codes: code_lookup(&HuffmanTable::standard_luma_ac()),
+        };
+        let chroma_tables = ComponentTables {
+            quantization: scale_quantization_table(&CHROMA_QUANTIZATION, self.quality),
+            dc_codes: code_lookup(&HuffmanTable::standard_chroma_dc()),
+            ac_codes: code_lookup(&HuffmanTable::standard_chroma_ac()),
+        };
+
+        let mut output = vec![0xFF, 0xD8];
+        Self::write_headers(
+            &mut output,
+            bitmap.size,
+            luma_factor,
+            [&luma_tables, &chroma_tables],
+        );
+
+        let [lum, cb, cr] = Self::color_planes(bitmap, (mcus.0 * mcu_size, mcus.1 * mcu_size));
+        let chroma_planes = [
+            downsample(&cb, mcus.0 * mcu_size, luma_factor as usize),
+            downsample(&cr, mcus.0 * mcu_size, luma_factor as usize),
+        ];
+        let chroma_width = mcus.0 * 8;
+
+        let mut writer = BitWriter::new(&mut output);
+        let mut dc_predictions = [0i16; 3];
+        for mcu_y in 0..mcus.1 {
+            for mcu_x in 0..mcus.0 {
+                for row in 0..luma_factor as usize {
+                    for col in 0..luma_factor as usize {
+                        let block = extract_block(
+                            &lum,
+                            mcus.0 * mcu_size,
+                            mcu_x * mcu_size + col * 8,
+                            mcu_y * mcu_size + row * 8,
+                        );
+                        encode_block(&mut writer, &block, &luma_tables, &mut dc_predictions[0]);
+                    }
+                }
+
+                for (plane, prediction) in chroma_planes.iter().zip(&mut dc_predictions[1..]) {
+                    let block = extract_block(plane, chroma_width, mcu_x * 8, mcu_y * 8);
+                    encode_block(&mut writer, &block, &chroma_tables, prediction);
+                }
+            }
+        }
+        writer.flush();
+
+        output.extend([0xFF, 0xD9]);
+        Ok(output)
+    }
+
+    /// Writes the JFIF, DQT, SOF0, DHT, and SOS segments
+    fn write_headers(
+        output: &mut Vec<u8>,
+        size: (u16, u16),
+        luma_factor: u8,
+        tables: [&ComponentTables; 2],
+    ) {
+        // JFIF 1.01, no density, no thumbnail
+        write_segment(
+            output,
+            0xE0,
+            &[b'J', b'F', b'I', b'F', 0, 1, 1, 0, 0, 1, 0, 1, 0, 0],
+        );
+
+        let mut quantization = vec![];
+        for (destination_id, table) in tables.iter().enumerate() {
+            quantization.push(destination_id as u8); // 8 bit precision
+            quantization.extend(
+                ZIGZAG_MAP
+                    .iter()
+                    .map(|(row, col)| table.quantization[*row as usize][*col as usize] as u8),
+            );
+        }
+        write_segment(output, 0xDB, &quantization);
+
+        let mut frame = vec![8];
+        frame.extend(size.1.to_be_bytes());
+        frame.extend(size.0.to_be_bytes());
+        frame.push(3);
+        frame.extend([1, luma_factor << 4 | luma_factor, 0]);
+        frame.extend([2, 0x11, 1]);
+        frame.extend([3, 0x11, 1]);
+        write_segment(output, 0xC0, &frame);
+
+        let mut huffman = vec![];
+        for table in [
+            HuffmanTable::standard_luma_dc(),
+            HuffmanTable::standard_luma_ac(),
+            HuffmanTable::standard_chroma_dc(),
+            HuffmanTable::standard_chroma_ac(),
+        ] {
+            let class = match table.table_type {
+                HuffmanTableType::Dc => 0,
+                HuffmanTableType::Ac => 1,
+            };
+            huffman.push(class << 4 | table.destination_id);
+            huffman.extend(table.bitcode_counts);
+            huffman.extend(&table.symbols);
+        }
+        write_segment(output, 0xC4, &huffman);
+
+        // Three components, with luma using tables 0 and chroma tables 1, covering the full
+        // spectrum with no successive approximation
+        write_segment(output, 0xDA, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 63, 0]);
+    }
+
+    /// Converts the bitmap to level shifted Y, Cb, and Cr planes of `padded_size`, repeating the
+    /// last row and column of the image into the padding.
+    fn color_planes(bitmap: &Bitmap, padded_size: (usize, usize)) -> [Vec<f32>; 3] {
+        let mut planes = [
+            Vec::with_capacity(padded_size.0 * padded_size.1),
+            Vec::with_capacity(padded_size.0 * padded_size.1),
+            Vec::with_capacity(padded_size.0 * padded_size.1),
+        ];
+        for y in 0..padded_size.1 {
+            let source_y = y.min(bitmap.size.1 as usize - 1);
+            for x in 0..padded_size.0 {
+                let source_x = x.min(bitmap.size.0 as usize - 1);
+                let index = (source_y * bitmap.size.0 as usize + source_x) * 3;
+                let (lum, cb, cr) = color::rgb_to_ycbcr((
+                    bitmap.data[index],
+                    bitmap.data[index + 1],
+                    bitmap.data[index + 2],
+                ));
+                planes[0].push(lum);
+                planes[1].push(cb);
+                planes[2].push(cr);
+            }
+        }
+        planes
+    }
+}
+
+impl<'bitmap> ImageEncoder<'bitmap> for JPEGEncoder<'bitmap> {
+    fn new(bitmap: &'bitmap Bitmap) -> Self {
+        Self {
+            bitmap,
+            quality: 90,
+            subsampling: Default::default(),
+        }
+    }
+
+    fn encode_to_file(&self, path: &str) -> io::Result<()> {
+        let data = self.encode().map_err(|error| match error {
+            Error::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", error)),
+        })?;
+        File::create(path)?.write_all(&data)
+    }
+}
+
+/// Writes bits most significant first, stuffing a zero byte after every 0xFF
+struct BitWriter<'output> {
+    output: &'output mut Vec<u8>,
+    buffer: u32,
+    bit_count: u8,
+}
+
+impl<'output> BitWriter<'output> {
+    fn new(output: &'output mut Vec<u8>) -> Self {
+        Self {
+            output,
+            buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Writes the low `length` bits of `bits`. `length` can be at most 16.
+    fn write_bits(&mut self, bits: u16, length: u8) {
+        self.buffer = self.buffer << length | (bits as u32 & ((1 << length) - 1));
+        self.bit_count += length;
+        while self.bit_count >= 8 {
+            self.bit_count -= 8;
+            let byte = (self.buffer >> self.bit_count) as u8;
+            self.output.push(byte);
+            if byte == 0xFF {
+                self.output.push(0x00);
+            }
+        }
+    }
+
+    /// Pads the last byte with one bits
+    fn flush(&mut self) {
+        if self.bit_count > 0 {
+            self.write_bits(0xFF, 8 - self.bit_count);
+        }
+    }
+}
+
+fn write_segment(output: &mut Vec<u8>, marker: u8, body: &[u8]) {
+    output.extend([0xFF, marker]);
+    output.extend((body.len() as u16 + 2).to_be_bytes());
+    output.extend(body);
+}
+
+/// Scales an Annex K table the same way as the IJG library, so quality 50 is the table as is.
+fn scale_quantization_table(table: &[[u16; 8]; 8], quality: u8) -> [[u16; 8]; 8] {
+    let scale = if quality < 50 {
+        5000 / quality as u32
+    } else {
+        200 - 2 * quality as u32
+    };
+    table.map(|row| row.map(|value| ((value as u32 * scale + 50) / 100).clamp(1, 255) as u16))
+}
+
+/// Maps each symbol of a Huffman table to its code and length
+fn code_lookup(table: &HuffmanTable) -> CodeLookup {
+    let mut lookup = [(0, 0); 256];
+    let lengths = table
+        .bitcode_counts
+        .iter()
+        .enumerate()
+        .flat_map(|(length, count)| core::iter::repeat_n(length as u8 + 1, *count as usize));
+    for ((symbol, code), length) in table.symbols.iter().zip(&table.codes).zip(lengths) {
+        lookup[*symbol as usize] = (*code, length);
+    }
+    lookup
+}
+
+/// Averages each `factor` by `factor` square of samples in a plane `width` samples wide
+fn downsample(plane: &[f32], width: usize, factor: usize) -> Vec<f32> {
+    if factor == 1 {
+        return plane.to_vec();
+    }
+
+    let height = plane.len() / width;
+    let mut downsampled = Vec::with_capacity(plane.len() / (factor * factor));
+    for y in (0..height).step_by(factor) {
+        for x in (0..width).step_by(factor) {
+            let sum: f32 = (0..factor)
+                .flat_map(|dy| (0..factor).map(move |dx| plane[(y + dy) * width + x + dx]))
+                .sum();
+            downsampled.push(sum / (factor * factor) as f32);
+        }
+    }
+    downsampled
+}
+
+fn extract_block(plane: &[f32], width: usize, x: usize, y: usize) -> [[f32; 8]; 8] {
+    core::array::from_fn(|row| core::array::from_fn(|col| plane[(y + row) * width + x + col]))
+}
+
+/// Returns the number of bits needed for the magnitude of `value`, which is its Huffman category
+fn category(value: i16) -> u8 {
+    16 - value.unsigned_abs().leading_zeros() as u8
+}
+
+/// Writes the low `category` bits of a value, with negative values one less, as in F.1.2.1
+fn write_value(writer: &mut BitWriter, value: i16, category: u8) {
+    let bits = if value < 0 { value - 1 } else { value };
+    writer.write_bits(bits as u16, category);
+}
+
+/// Quantizes and Huffman encodes one block of samples
+fn encode_block(
+    writer: &mut BitWriter,
+    block: &[[f32; 8]; 8],
+    tables: &ComponentTables,
+    dc_prediction: &mut i16,
+) {
+    let coefficients = forward_dct_float(block);
+    let quantized: [i16; 64] = core::array::from_fn(|i| {
+        let (row, col) = ZIGZAG_MAP[i];
+        let (row, col) = (row as usize, col as usize);
+        // The standard AC tables only have codes for values of up to 10 bits
+        round(coefficients[row][col] / tables.quantization[row][col] as f32).clamp(-1023, 1023)
+    });
+
+    // https://www.w3.org/Graphics/JPEG/itu-t81.pdf F.1.2.1 Page 88
+    let diff = quantized[0] - *dc_prediction;
+    *dc_prediction = quantized[0];
+    let diff_category = category(diff);
+    let (code, length) = tables.dc_codes[diff_category as usize];
+    writer.write_bits(code, length);
+    write_value(writer, diff, diff_category);
+
+    // F.1.2.2 Page 89
+    let mut run = 0;
+    for &value in &quantized[1..] {
+        if value == 0 {
+            run += 1;
+            continue;
+        }
+
+        while run > 15 {
+            let (code, length) = tables.ac_codes[0xF0];
+            writer.write_bits(code, length);
+            run -= 16;
+        }
+
+        let value_category = category(value);
+        let (code, length) = tables.ac_codes[(run << 4 | value_category) as usize];
+        writer.write_bits(code, length);
+        write_value(writer, value, value_category);
+        run = 0;
+    }
+
+    if run > 0 {
+        let (code, length) = tables.ac_codes[0x00];
+        writer.write_bits(code, length);
+    }
+}
+
+#[test]
+fn encode_round_trip() {
+    use super::JPEGDecoder;
+    use crate::image::ImageDecoder;
+
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let bitmap = JPEGDecoder::new(image_data).decode().unwrap();
+
+    for (subsampling, quality, min_psnr) in [
+        (ChromaSubsampling::Ratio444, 100, 45.0),
+        (ChromaSubsampling::Ratio444, 90, 34.0),
+        (ChromaSubsampling::Ratio420, 90, 33.0),
+        (ChromaSubsampling::Ratio444, 50, 25.0),
+    ] {
+        let encoded = JPEGEncoder::new(&bitmap)
+            .with_quality(quality)
+            .with_subsampling(subsampling)
+            .encode()
+            .unwrap();
+        let decoded = JPEGDecoder::new(&encoded).decode().unwrap();
+
+        assert_eq!(decoded.size, bitmap.size);
+        let psnr = bitmap.psnr(&decoded).unwrap();
+        assert!(
+            psnr > min_psnr,
+            "{:?} at quality {}: {} dB",
+            subsampling,
+            quality,
+            psnr
+        );
+    }
+}
+
+#[test]
+fn encode_rejects_unsupported_bitmaps() {
+    let bitmap = Bitmap {
+        channels: 1,
+        color_space: ColorSpace::Gray,
+        bit_depth: 8,
+        size: (1, 1),
+        data: vec![0],
+    };
+    assert!(matches!(
+        JPEGEncoder::new(&bitmap).encode(),
+        Err(Error::UnsupportedFeature(_))
+    ));
+
+    let bitmap = Bitmap {
+        channels: 3,
+        color_space: ColorSpace::Rgb,
+        bit_depth: 16,
+        size: (1, 1),
+        data: vec![0; 6],
+    };
+    assert!(matches!(
+        JPEGEncoder::new(&bitmap).encode(),
+        Err(Error::UnsupportedFeature(message)) if message == "JPEG encoder only supports 8 bit samples"
+    ));
+}
@@ -1,13 +1,14 @@
-use std::{cmp::max, collections::HashMap};
+use alloc::{format, vec, vec::Vec};
+use core::cmp::max;
 
 use crate::{
     error::{Error, Result},
     jpeg::jpeg_reader::*,
 };
 
-use super::jpeg_core::ZIGZAG_MAP;
+use super::{standard_tables::*, zigzag::ZIGZAG_MAP};
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub enum HuffmanTableType {
     #[default]
     Ac,
@@ -15,7 +16,7 @@ pub enum HuffmanTableType {
 }
 
 /// Defines a JPEG huffman table
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct HuffmanTable {
     pub table_type: HuffmanTableType,
     pub destination_id: u8,
@@ -25,43 +26,82 @@ pub struct HuffmanTable {
 }
 
 impl HuffmanTable {
-    fn generate_codes(&mut self) {
-        let mut code = 0;
-        for code_count in self.bitcode_counts {
-            for _ in 0..code_count {
-                self.codes.push(code);
+    fn standard(
+        table_type: HuffmanTableType,
+        destination_id: u8,
+        bitcode_counts: [u8; 16],
+        symbols: &[u8],
+    ) -> Self {
+        let mut table = Self {
+            table_type,
+            destination_id,
+            bitcode_counts,
+            symbols: symbols.to_vec(),
+            codes: vec![],
+        };
+        table
+            .generate_codes()
+            .expect("standard Huffman tables are valid");
+        table
+    }
+
+    /// The Annex K luma DC table, at destination 0
+    pub fn standard_luma_dc() -> Self {
+        Self::standard(HuffmanTableType::Dc, 0, LUMA_DC_COUNTS, &LUMA_DC_SYMBOLS)
+    }
+
+    /// The Annex K luma AC table, at destination 0
+    pub fn standard_luma_ac() -> Self {
+        Self::standard(HuffmanTableType::Ac, 0, LUMA_AC_COUNTS, &LUMA_AC_SYMBOLS)
+    }
+
+    /// The Annex K chroma DC table, at destination 1
+    pub fn standard_chroma_dc() -> Self {
+        Self::standard(
+            HuffmanTableType::Dc,
+            1,
+            CHROMA_DC_COUNTS,
+            &CHROMA_DC_SYMBOLS,
+        )
+    }
+
+    /// The Annex K chroma AC table, at destination 1
+    pub fn standard_chroma_ac() -> Self {
+        Self::standard(
+            HuffmanTableType::Ac,
+            1,
+            CHROMA_AC_COUNTS,
+            &CHROMA_AC_SYMBOLS,
+        )
+    }
+
+    /// Generates the canonical code for each symbol. Fails if the bitcode counts don't describe a
+    /// valid prefix code, i.e. a bit length is given more codes than it can hold.
+    fn generate_codes(&mut self) -> Result<()> {
+        let mut code: u32 = 0;
+        for (length, code_count) in self.bitcode_counts.iter().enumerate() {
+            for _ in 0..*code_count {
+                self.codes.push(code as u16);
                 code += 1;
             }
+
+            if code > 1 << (length + 1) {
+                return Err(Error::Malformed("invalid Huffman table".into()));
+            }
             code <<= 1;
         }
+        Ok(())
     }
 }
 
-#[derive(Debug)]
-pub enum QuantizationTableType {
-    Luma,
-    Chroma,
-}
-
-#[derive(Debug)]
+#[allow(dead_code)]
+#[derive(Debug, Default, Clone)]
 pub struct QuantizationTable {
-    pub table_type: QuantizationTableType,
     pub precision: u8,
     pub destination_id: u8,
     pub table: [[u16; 8]; 8],
 }
 
-impl Default for QuantizationTable {
-    fn default() -> Self {
-        Self {
-            table_type: QuantizationTableType::Luma,
-            precision: 0,
-            destination_id: 0,
-            table: [[0; 8]; 8],
-        }
-    }
-}
-
 #[derive(Debug, Default, Clone)]
 pub struct FrameComponent {
     pub identifier: u8,
@@ -82,6 +122,7 @@ pub struct Component {
     pub scan: ScanComponent,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct ScanInfo {
     pub components: Vec<ScanComponent>,
@@ -89,11 +130,12 @@ pub struct ScanInfo {
     pub successive_approximation: u8,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Default)]
 pub struct FrameInfo {
     pub precision: u8,
     pub image_size: (u16, u16),
-    pub padded_size: (u16, u16),
+    pub padded_size: (u32, u32),
     pub components: Vec<FrameComponent>,
 }
 
@@ -105,41 +147,125 @@ pub struct MCUInfo {
     pub mcu_padded_dimensions: (u16, u16),
 }
 
+/// Tables indexed by their destination id. JPEG only allows ids 0 to 3.
+pub type TableSlots<T> = [Option<T>; 4];
+
+/// The Huffman and quantization tables available to a frame. Abbreviated streams, like Motion JPEG
+/// frames, may leave some or all of them out and rely on tables the decoder was given beforehand.
+#[derive(Debug, Default, Clone)]
+pub struct Tables {
+    pub ac_huff_tables: TableSlots<HuffmanTable>,
+    pub dc_huff_tables: TableSlots<HuffmanTable>,
+    pub quant_tables: TableSlots<QuantizationTable>,
+}
+
+impl Tables {
+    /// The Annex K Huffman tables, luma in slot 0 and chroma in slot 1, with no quantization tables
+    pub fn standard_huffman() -> Self {
+        let mut tables = Self::default();
+        tables.store_huffman_tables(vec![
+            HuffmanTable::standard_luma_dc(),
+            HuffmanTable::standard_luma_ac(),
+            HuffmanTable::standard_chroma_dc(),
+            HuffmanTable::standard_chroma_ac(),
+        ]);
+        tables
+    }
+
+    /// Stores the tables from a DHT segment, replacing any with the same class and destination.
+    fn store_huffman_tables(&mut self, tables: Vec<HuffmanTable>) {
+        for table in tables {
+            let destination_id = table.destination_id as usize;
+            let slots = match table.table_type {
+                HuffmanTableType::Ac => &mut self.ac_huff_tables,
+                HuffmanTableType::Dc => &mut self.dc_huff_tables,
+            };
+            slots[destination_id] = Some(table);
+        }
+    }
+
+    /// Stores the tables from a DQT segment, replacing any with the same destination.
+    fn store_quantization_tables(&mut self, tables: Vec<QuantizationTable>) {
+        for table in tables {
+            let destination_id = table.destination_id as usize;
+            self.quant_tables[destination_id] = Some(table);
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct HeaderInfo {
     pub frame_info: FrameInfo,
     pub scan_info: ScanInfo,
+    /// The components in frame order
     pub components: Vec<Component>,
-    pub ac_huff_tables: HashMap<u8, HuffmanTable>,
-    pub dc_huff_tables: HashMap<u8, HuffmanTable>,
-    pub quant_tables: HashMap<u8, QuantizationTable>,
+    /// The indices into `components` in the order the scan codes them
+    pub scan_order: Vec<usize>,
+    pub tables: Tables,
     pub header_length: usize,
     pub mcu_info: MCUInfo,
+    /// The number of MCUs in each restart interval, or 0 if the image doesn't use them
+    pub restart_interval: u16,
+    /// The payloads of the COM segments before the scan
+    pub comments: Vec<Vec<u8>>,
 }
 
 impl HeaderInfo {
     fn read_start_of_frame(reader: &mut JPEGParser) -> Result<FrameInfo> {
-        let _struct_size = reader.read_next_word()? - 2;
+        let _struct_size = reader.read_segment_length()?;
 
         let precision = reader.read_next_byte()?;
+        // Baseline frames only have 8 bit samples. B.2.2 Page 36
+        if precision != 8 {
+            return Err(Error::UnsupportedFeature(format!(
+                "unsupported sample precision: {} bits",
+                precision
+            )));
+        }
 
         let height = reader.read_next_word()?;
         let width = reader.read_next_word()?;
 
+        if width == 0 {
+            return Err(Error::Malformed("image has zero dimension".into()));
+        }
+        if height == 0 {
+            // A zero height means it's given by a DNL marker after the first scan
+            return Err(Error::UnsupportedFeature(
+                "image height defined by DNL marker not supported".into(),
+            ));
+        }
+
         let component_count = reader.read_next_byte()?;
+        if component_count == 0 {
+            return Err(Error::Malformed("Frame has no components".into()));
+        }
 
         let mut components: Vec<FrameComponent> = Vec::with_capacity(component_count as usize);
 
         for _ in 0..component_count {
             let identifier = reader.read_next_byte()?;
             if components.iter().any(|c| c.identifier == identifier) {
-                return Err(Error::Malformed("Duplicate component identifier"));
+                return Err(Error::Malformed("Duplicate component identifier".into()));
             }
 
             let sample_factors = reader.read_next_byte()?;
             let xy_sampling_factor = (sample_factors >> 4, sample_factors & 0x0F);
+            if !(1..=4).contains(&xy_sampling_factor.0) || !(1..=4).contains(&xy_sampling_factor.1)
+            {
+                return Err(Error::Malformed(format!(
+                    "Sampling factors must be between 1 and 4, got {}x{}",
+                    xy_sampling_factor.0, xy_sampling_factor.1
+                )));
+            }
 
             let qtable_id = reader.read_next_byte()?;
+            if qtable_id > 3 {
+                return Err(Error::Malformed(format!(
+                    "Invalid quantization table destination {}",
+                    qtable_id
+                )));
+            }
 
             components.push(FrameComponent {
                 identifier,
@@ -156,30 +282,28 @@ impl HeaderInfo {
         })
     }
 
-    fn read_quantization_tables(reader: &mut JPEGParser) -> Result<HashMap<u8, QuantizationTable>> {
-        let struct_size = reader.read_next_word()? - 2;
+    fn read_quantization_tables(reader: &mut JPEGParser) -> Result<Vec<QuantizationTable>> {
+        let struct_size = reader.read_segment_length()?;
 
-        let mut quant_tables: HashMap<u8, QuantizationTable> = HashMap::new();
+        let mut quant_tables: Vec<QuantizationTable> = vec![];
 
-        let end_of_table = reader.position() + struct_size as u64;
-        while reader.position() != end_of_table {
+        let end_of_table = reader.position() + struct_size as usize;
+        while reader.position() < end_of_table {
             let table_info = reader.read_next_byte()?;
             let precision = table_info >> 4;
             let destination_id = table_info & 0x0F;
-            let table_type = match destination_id {
-                0 => Ok(QuantizationTableType::Luma),
-                1 => Ok(QuantizationTableType::Chroma),
-                _ => Err(Error::UnsupportedFeature(
-                    "Unsupported quantization table type",
-                )),
-            }?;
+            if destination_id > 3 {
+                return Err(Error::Malformed(
+                    "Invalid quantization table destination".into(),
+                ));
+            }
 
             let mut zagged_table = [0u16; 64];
             for value in zagged_table.iter_mut() {
                 *value = match precision {
                     0 => reader.read_next_byte()? as u16,
                     1 => reader.read_next_word()?,
-                    _ => return Err(Error::Malformed("Invalid precision value")),
+                    _ => return Err(Error::Malformed("Invalid precision value".into())),
                 }
             }
 
@@ -188,44 +312,44 @@ impl HeaderInfo {
                 let (row, col) = ZIGZAG_MAP[i];
                 unzagged_table[row as usize][col as usize] = zagged_table[i];
             }
-            quant_tables.insert(
+            quant_tables.push(QuantizationTable {
+                precision,
                 destination_id,
-                QuantizationTable {
-                    table_type,
-                    precision,
-                    destination_id,
-                    table: unzagged_table,
-                },
-            );
+                table: unzagged_table,
+            });
+        }
+        if reader.position() != end_of_table {
+            return Err(Error::Malformed(
+                "DQT tables overrun the segment length".into(),
+            ));
         }
 
         Ok(quant_tables)
     }
 
-    fn read_huffman_tables(
-        reader: &mut JPEGParser,
-    ) -> Result<(HashMap<u8, HuffmanTable>, HashMap<u8, HuffmanTable>)> {
-        let struct_size = reader.read_next_word()? - 2;
+    fn read_huffman_tables(reader: &mut JPEGParser) -> Result<Vec<HuffmanTable>> {
+        let struct_size = reader.read_segment_length()?;
 
-        let mut ac_tables: HashMap<u8, HuffmanTable> = HashMap::new();
-        let mut dc_tables: HashMap<u8, HuffmanTable> = HashMap::new();
+        let mut tables: Vec<HuffmanTable> = vec![];
 
-        let end_of_table = reader.position() + struct_size as u64;
-        while reader.position() != end_of_table {
+        let end_of_table = reader.position() + struct_size as usize;
+        while reader.position() < end_of_table {
             let table_info = reader.read_next_byte()?;
             let table_type = match table_info >> 4 {
                 0 => Ok(HuffmanTableType::Dc),
                 1 => Ok(HuffmanTableType::Ac),
-                _ => Err(Error::Malformed("Invalid table type")),
+                _ => Err(Error::Malformed("Invalid table type".into())),
             }?;
 
             let destination_id = table_info & 0x0F;
+            if destination_id > 3 {
+                return Err(Error::Malformed("Invalid Huffman table destination".into()));
+            }
 
             let mut bitcode_counts: [u8; 16] = [0; 16];
 
-            for i in 0..16 {
-                let count = reader.read_next_byte()?;
-                bitcode_counts[i] = count;
+            for count in bitcode_counts.iter_mut() {
+                *count = reader.read_next_byte()?;
             }
 
             let size: usize = bitcode_counts
@@ -233,8 +357,8 @@ impl HeaderInfo {
                 .fold(0, |total, elem| total + *elem as usize);
 
             let mut symbols = vec![0u8; size];
-            for i in 0..size {
-                symbols[i] = reader.read_next_byte()?;
+            for symbol in symbols.iter_mut() {
+                *symbol = reader.read_next_byte()?;
             }
 
             let mut table = HuffmanTable {
@@ -245,30 +369,53 @@ impl HeaderInfo {
                 codes: vec![],
             };
 
-            table.generate_codes();
+            table.generate_codes()?;
 
-            match table.table_type {
-                HuffmanTableType::Ac => ac_tables.insert(table.destination_id, table),
-                HuffmanTableType::Dc => dc_tables.insert(table.destination_id, table),
-            };
+            tables.push(table);
+        }
+        if reader.position() != end_of_table {
+            return Err(Error::Malformed(
+                "DHT tables overrun the segment length".into(),
+            ));
         }
 
-        Ok((ac_tables, dc_tables))
+        Ok(tables)
     }
 
-    /// Reads data from the scan header, leaving the cursor at the start of the scan stream.
-    fn read_start_of_scan(reader: &mut JPEGParser) -> Result<ScanInfo> {
-        let _struct_size = reader.read_next_word()? - 2;
+    /// Reads data from the scan header, leaving the cursor at the start of the scan stream. Each
+    /// component the scan selects has to be one of `frame_components`, selected only once.
+    fn read_start_of_scan(
+        reader: &mut JPEGParser,
+        frame_components: &[FrameComponent],
+    ) -> Result<ScanInfo> {
+        let _struct_size = reader.read_segment_length()?;
 
         let component_count = reader.read_next_byte()?;
 
-        let mut components = Vec::with_capacity(component_count as usize);
+        let mut components: Vec<ScanComponent> = Vec::with_capacity(component_count as usize);
         for _ in 0..component_count {
             let selector = reader.read_next_byte()?;
+            // Decoding indexes per component state by the selected frame component, so a bogus
+            // selector has to be caught here
+            let in_frame = frame_components
+                .iter()
+                .any(|component| component.identifier == selector);
+            let repeated = components
+                .iter()
+                .any(|component| component.selector == selector);
+            if !in_frame || repeated {
+                return Err(Error::Malformed(format!(
+                    "Scan selects component {} more than once or not in the frame",
+                    selector
+                )));
+            }
 
             let tables = reader.read_next_byte()?;
             let dc_table = tables >> 4;
             let ac_table = tables & 0x0F;
+            if dc_table > 3 || ac_table > 3 {
+                return Err(Error::Malformed("Invalid Huffman table destination".into()));
+            }
 
             components.push(ScanComponent {
                 selector,
@@ -289,45 +436,129 @@ impl HeaderInfo {
         })
     }
 
-    /// Reads header info from a given JPEGParser. The JPEGParser is expected to be at position 0
-    /// in a JPEG data stream. It returns when it find the start of scan marker, reads its header,
-    /// and leaves the cursor at the scan stream.
-    pub fn read_header_info(reader: &mut JPEGParser) -> Result<Self> {
-        {
-            let marker = reader.read_next_marker()?;
+    fn read_restart_interval(reader: &mut JPEGParser) -> Result<u16> {
+        if reader.read_segment_length()? != 2 {
+            return Err(Error::Malformed("DRI segment has the wrong length".into()));
+        }
+        reader.read_next_word()
+    }
 
-            if marker != JPEGMarker::SOI {
-                return Err(Error::Malformed(
-                    "This JPEG image does not have an SOI marker",
-                ));
+    fn read_start_of_image(reader: &mut JPEGParser) -> Result<()> {
+        let marker = reader.read_next_marker()?;
+
+        if marker != JPEGMarker::SOI {
+            return Err(Error::Malformed(
+                "This JPEG image does not have an SOI marker".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the tables from a given JPEGParser, which is expected to be at the SOI marker. This
+    /// handles both abbreviated table specifications, which end at the EOI marker, and full
+    /// images, where only the tables defined before the first scan are read.
+    pub fn read_tables(reader: &mut JPEGParser) -> Result<Tables> {
+        Self::read_start_of_image(reader)?;
+
+        let mut tables = Tables::default();
+        loop {
+            match reader.read_next_marker()? {
+                JPEGMarker::EOI | JPEGMarker::SOS => return Ok(tables),
+                JPEGMarker::DHT => tables.store_huffman_tables(Self::read_huffman_tables(reader)?),
+                JPEGMarker::DQT => {
+                    tables.store_quantization_tables(Self::read_quantization_tables(reader)?)
+                }
+                _ => reader.skip_marker_with_length()?,
             }
         }
+    }
 
-        let mut result: Self = Default::default();
+    /// Reads header info from a given JPEGParser. The JPEGParser is expected to be at the SOI
+    /// marker of a JPEG data stream. It returns when it find the start of scan marker, reads its header,
+    /// and leaves the cursor at the scan stream. Tables defined in the stream replace the ones in
+    /// `tables`. The index and payload of each APPn segment is passed to `app_segment`. Unless
+    /// `strict` is set, a scan using an undefined Huffman table gets the matching standard table.
+    pub fn read_header_info(
+        reader: &mut JPEGParser,
+        tables: &Tables,
+        strict: bool,
+        app_segment: &mut dyn FnMut(u8, &[u8]),
+    ) -> Result<Self> {
+        Self::read_start_of_image(reader)?;
+
+        let mut result = Self {
+            tables: tables.clone(),
+            ..Default::default()
+        };
 
         loop {
             let marker = reader.read_next_marker()?;
 
             match marker {
                 JPEGMarker::EOI => {
-                    return Err(Error::Malformed("Unexpected EOI marker encountered."));
+                    return Err(Error::Malformed(
+                        "Unexpected EOI marker encountered.".into(),
+                    ));
                 }
                 JPEGMarker::SOF0 => {
                     result.frame_info = Self::read_start_of_frame(reader)?;
                 }
+                JPEGMarker::SOF3 => {
+                    return Err(Error::UnsupportedFeature(
+                        "lossless JPEG (SOF3) not supported".into(),
+                    ));
+                }
+                // A hierarchical image is made of frames at several resolutions, each refining
+                // the last. B.3 Page 40
+                JPEGMarker::DHP | JPEGMarker::EXP => {
+                    return Err(Error::UnsupportedFeature(
+                        "hierarchical JPEG not supported".into(),
+                    ));
+                }
+                JPEGMarker::DAC => {
+                    return Err(Error::UnsupportedFeature(
+                        "arithmetic JPEG (DAC present)".into(),
+                    ));
+                }
+                JPEGMarker::DRI => {
+                    result.restart_interval = Self::read_restart_interval(reader)?;
+                }
                 JPEGMarker::DHT => {
                     let tables = Self::read_huffman_tables(reader)?;
-                    result.ac_huff_tables.extend(tables.0);
-                    result.dc_huff_tables.extend(tables.1);
+                    result.tables.store_huffman_tables(tables);
                 }
                 JPEGMarker::DQT => {
-                    result
-                        .quant_tables
-                        .extend(Self::read_quantization_tables(reader)?);
+                    let tables = Self::read_quantization_tables(reader)?;
+                    result.tables.store_quantization_tables(tables);
+                }
+                JPEGMarker::COM => {
+                    result.comments.push(reader.read_segment()?.to_vec());
                 }
                 JPEGMarker::SOS => {
-                    result.scan_info = Self::read_start_of_scan(reader)?;
-                    result.header_length = reader.position() as usize;
+                    // The MCU layout comes from the frame's sampling factors
+                    if result.frame_info.components.is_empty() {
+                        return Err(Error::Malformed("SOS before SOF".into()));
+                    }
+
+                    result.scan_info =
+                        Self::read_start_of_scan(reader, &result.frame_info.components)?;
+                    result.header_length = reader.position();
+
+                    // A baseline scan codes every coefficient in one pass. Other values would be
+                    // decoded as if they were baseline, so only lenient mode lets them through,
+                    // the way libjpeg only warns about them
+                    let scan = &result.scan_info;
+                    if strict
+                        && (scan.spectral_selection != (0, 63)
+                            || scan.successive_approximation != 0)
+                    {
+                        return Err(Error::Malformed(format!(
+                            "Baseline scan must have spectral selection 0 to 63 and successive approximation 0, got {} to {} and {:#04x}",
+                            scan.spectral_selection.0,
+                            scan.spectral_selection.1,
+                            scan.successive_approximation
+                        )));
+                    }
 
                     {
                         result.mcu_info.max_xy_sampling_factor = result
@@ -356,28 +587,89 @@ impl HeaderInfo {
                         result.frame_info.padded_size =
                             pad(result.frame_info.image_size, result.mcu_info.mcu_size);
 
+                        // At least 8 pixels per MCU, so these always fit in 16 bits
                         result.mcu_info.mcu_padded_dimensions = (
-                            result.frame_info.padded_size.0 / result.mcu_info.mcu_size.0 as u16,
-                            result.frame_info.padded_size.1 / result.mcu_info.mcu_size.1 as u16,
+                            (result.frame_info.padded_size.0 / result.mcu_info.mcu_size.0 as u32)
+                                as u16,
+                            (result.frame_info.padded_size.1 / result.mcu_info.mcu_size.1 as u32)
+                                as u16,
                         );
                     }
 
                     {
                         if result.frame_info.components.len() != result.scan_info.components.len() {
-                            return Err(Error::Malformed("Different number of components specified in scan header than frame header"));
+                            return Err(Error::Malformed("Different number of components specified in scan header than frame header".into()));
                         }
 
-                        result.components =
-                            vec![Default::default(); result.frame_info.components.len()];
+                        if result.frame_info.components.len() > 3 {
+                            return Err(Error::UnsupportedFeature(
+                                "Only images with up to 3 components are supported".into(),
+                            ));
+                        }
 
-                        for i in 0..result.components.len() {
-                            result.components[i].frame = result.frame_info.components[i].clone();
-                            result.components[i].scan = result.scan_info.components[i].clone();
+                        // Components stay in frame order, since their position rather than their
+                        // identifier says which is Y, Cb, and Cr. The scan can select them by
+                        // identifier in any order.
+                        result.components = result
+                            .frame_info
+                            .components
+                            .iter()
+                            .map(|frame| Component {
+                                frame: frame.clone(),
+                                scan: Default::default(),
+                            })
+                            .collect();
+                        // The selectors were checked against the frame as the scan was read
+                        result.scan_order = Vec::with_capacity(result.components.len());
+                        for scan in &result.scan_info.components {
+                            if let Some(index) = result
+                                .components
+                                .iter()
+                                .position(|component| component.frame.identifier == scan.selector)
+                            {
+                                result.components[index].scan = scan.clone();
+                                result.scan_order.push(index);
+                            }
+                        }
+                    }
+
+                    // Abbreviated streams can leave tables out, so make sure every table the scan
+                    // uses was defined somewhere
+                    for (index, component) in result.components.iter().enumerate() {
+                        let tables = &mut result.tables;
+                        let dc_slot = &mut tables.dc_huff_tables[component.scan.dc_table as usize];
+                        let ac_slot = &mut tables.ac_huff_tables[component.scan.ac_table as usize];
+                        if dc_slot.is_none() || ac_slot.is_none() {
+                            if strict {
+                                return Err(Error::Malformed(
+                                    "Scan uses an undefined Huffman table".into(),
+                                ));
+                            }
+                            // Fall back to the Annex K tables most encoders use anyway
+                            let luma = index == 0;
+                            dc_slot.get_or_insert_with(|| {
+                                if luma {
+                                    HuffmanTable::standard_luma_dc()
+                                } else {
+                                    HuffmanTable::standard_chroma_dc()
+                                }
+                            });
+                            ac_slot.get_or_insert_with(|| {
+                                if luma {
+                                    HuffmanTable::standard_luma_ac()
+                                } else {
+                                    HuffmanTable::standard_chroma_ac()
+                                }
+                            });
                         }
                     }
 
                     return Ok(result);
                 }
+                marker if marker >= JPEGMarker::APP0 && marker <= JPEGMarker::APP15 => {
+                    let payload = reader.read_segment()?;
+                    app_segment((marker as u16 - JPEGMarker::APP0 as u16) as u8, payload);
+                }
                 _ => {
                     reader.skip_marker_with_length()?; // Skip unkown markers
                 }
@@ -386,26 +678,373 @@ impl HeaderInfo {
     }
 }
 
-fn pad(unpadded: (u16, u16), block_size: (u8, u8)) -> (u16, u16) {
+/// Rounds the size up to a multiple of the block size. This is done in 32 bits since padding can
+/// take sizes close to 65535 past it.
+fn pad(unpadded: (u16, u16), block_size: (u8, u8)) -> (u32, u32) {
     let mut result = (0, 0);
     {
-        let remainder = unpadded.0 % block_size.0 as u16;
+        let unpadded = unpadded.0 as u32;
+        let remainder = unpadded % block_size.0 as u32;
 
         if remainder == 0 {
-            result.0 = unpadded.0;
+            result.0 = unpadded;
         } else {
-            result.0 = unpadded.0 + block_size.0 as u16 - remainder;
+            result.0 = unpadded + block_size.0 as u32 - remainder;
         }
     }
     {
-        let remainder = unpadded.1 % block_size.1 as u16;
+        let unpadded = unpadded.1 as u32;
+        let remainder = unpadded % block_size.1 as u32;
 
         if remainder == 0 {
-            result.1 = unpadded.1;
+            result.1 = unpadded;
         } else {
-            result.1 = unpadded.1 + block_size.1 as u16 - remainder;
+            result.1 = unpadded + block_size.1 as u32 - remainder;
         }
     }
 
     result
 }
+
+#[test]
+fn generate_codes() {
+    let mut table = HuffmanTable {
+        bitcode_counts: [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+        ..Default::default()
+    };
+    assert!(table.generate_codes().is_ok());
+    assert_eq!(&table.codes[..4], &[0b00, 0b010, 0b011, 0b100]);
+
+    // Three codes can't fit in one bit
+    let mut table = HuffmanTable {
+        bitcode_counts: [3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ..Default::default()
+    };
+    assert!(matches!(
+        table.generate_codes(),
+        Err(Error::Malformed(message)) if message == "invalid Huffman table"
+    ));
+
+    // Two codes fill the one bit space, so there's no room left for longer codes
+    let mut table = HuffmanTable {
+        bitcode_counts: [2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+        ..Default::default()
+    };
+    assert!(table.generate_codes().is_err());
+}
+
+#[test]
+fn standard_huffman_tables() {
+    let table = HuffmanTable::standard_luma_dc();
+    assert_eq!(
+        table.codes,
+        [
+            0b00,
+            0b010,
+            0b011,
+            0b100,
+            0b101,
+            0b110,
+            0b1110,
+            0b11110,
+            0b111110,
+            0b1111110,
+            0b11111110,
+            0b111111110
+        ]
+    );
+
+    // The AC tables use every code up to 16 bits except all ones
+    let table = HuffmanTable::standard_luma_ac();
+    assert_eq!(table.codes.len(), 162);
+    assert_eq!(table.codes[..3], [0b00, 0b01, 0b100]);
+    assert_eq!(*table.codes.last().unwrap(), 0xFFFE);
+    assert_eq!(
+        *HuffmanTable::standard_chroma_ac().codes.last().unwrap(),
+        0xFFFE
+    );
+
+    // test2.jpg was written with the standard tables
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test2.jpg");
+    let tables = HeaderInfo::read_tables(&mut JPEGParser::new(image_data)).unwrap();
+    let standard = Tables::standard_huffman();
+    for (slots, standard_slots) in [
+        (&tables.dc_huff_tables, &standard.dc_huff_tables),
+        (&tables.ac_huff_tables, &standard.ac_huff_tables),
+    ] {
+        for (table, standard_table) in slots.iter().zip(standard_slots) {
+            let (table, standard_table) = (table.as_ref(), standard_table.as_ref());
+            assert_eq!(
+                table.map(|table| (&table.bitcode_counts, &table.symbols, &table.codes)),
+                standard_table.map(|table| (&table.bitcode_counts, &table.symbols, &table.codes))
+            );
+        }
+    }
+}
+
+#[test]
+fn read_quantization_precision() {
+    let mut segment = vec![0, 2 + 1 + 128, 0x11];
+    segment.extend((0..64u16).flat_map(|i| (1000 * i + 1).to_be_bytes()));
+
+    let tables = HeaderInfo::read_quantization_tables(&mut JPEGParser::new(&segment)).unwrap();
+    assert_eq!(tables.len(), 1);
+    assert_eq!(tables[0].precision, 1);
+    assert_eq!(tables[0].destination_id, 1);
+    // Zigzag index 1 is the first row, second column, and 63 is the last sample
+    assert_eq!(tables[0].table[0][1], 1001);
+    assert_eq!(tables[0].table[7][7], 63001);
+
+    segment[2] = 0x20;
+    assert!(matches!(
+        HeaderInfo::read_quantization_tables(&mut JPEGParser::new(&segment)),
+        Err(Error::Malformed(message)) if message == "Invalid precision value"
+    ));
+}
+
+#[test]
+fn reject_zero_dimensions() {
+    #[rustfmt::skip]
+    let mut header = [
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xC0, // Start of frame
+        0, 11,          // Length
+        8,              // Precision
+        0, 16,          // Height
+        0, 0,           // Width
+        1,              // Component count
+        1, 0x11, 0,     // Component data
+    ];
+
+    let result = HeaderInfo::read_header_info(
+        &mut JPEGParser::new(&header),
+        &Tables::default(),
+        true,
+        &mut |_, _| {},
+    );
+    assert!(matches!(
+        result,
+        Err(Error::Malformed(message)) if message == "image has zero dimension"
+    ));
+
+    header[7..11].copy_from_slice(&[0, 0, 0, 16]);
+    let result = HeaderInfo::read_header_info(
+        &mut JPEGParser::new(&header),
+        &Tables::default(),
+        true,
+        &mut |_, _| {},
+    );
+    assert!(matches!(result, Err(Error::UnsupportedFeature(_))));
+}
+
+#[test]
+fn reject_invalid_sampling_factor() {
+    #[rustfmt::skip]
+    let header = [
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xC0, // Start of frame
+        0, 11,          // Length
+        8,              // Precision
+        0, 16,          // Height
+        0, 16,          // Width
+        1,              // Component count
+        1, 0x51, 0,     // Component data
+    ];
+
+    let result = HeaderInfo::read_header_info(
+        &mut JPEGParser::new(&header),
+        &Tables::default(),
+        true,
+        &mut |_, _| {},
+    );
+    assert!(matches!(
+        result,
+        Err(Error::Malformed(message)) if message.ends_with("got 5x1")
+    ));
+}
+
+#[test]
+fn reject_sample_precision() {
+    #[rustfmt::skip]
+    let header = [
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xC0, // Start of frame
+        0, 11,          // Length
+        16,             // Precision
+        0, 16,          // Height
+        0, 16,          // Width
+        1,              // Component count
+        1, 0x11, 0,     // Component data
+    ];
+
+    let result = HeaderInfo::read_header_info(
+        &mut JPEGParser::new(&header),
+        &Tables::default(),
+        true,
+        &mut |_, _| {},
+    );
+    assert!(matches!(
+        result,
+        Err(Error::UnsupportedFeature(message)) if message == "unsupported sample precision: 16 bits"
+    ));
+}
+
+#[test]
+fn reject_invalid_qtable_destination() {
+    #[rustfmt::skip]
+    let header = [
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xC0, // Start of frame
+        0, 11,          // Length
+        8,              // Precision
+        0, 16,          // Height
+        0, 16,          // Width
+        1,              // Component count
+        1, 0x11, 7,     // Component data
+    ];
+
+    let result = HeaderInfo::read_header_info(
+        &mut JPEGParser::new(&header),
+        &Tables::default(),
+        true,
+        &mut |_, _| {},
+    );
+    assert!(matches!(
+        result,
+        Err(Error::Malformed(message)) if message == "Invalid quantization table destination 7"
+    ));
+}
+
+#[test]
+fn reject_hierarchical() {
+    #[rustfmt::skip]
+    let header = [
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xDE, // Define hierarchical progression
+        0, 11,          // Length
+        8,              // Precision
+        0, 16,          // Height
+        0, 16,          // Width
+        1,              // Component count
+        1, 0x11, 0,     // Component data
+    ];
+
+    let result = HeaderInfo::read_header_info(
+        &mut JPEGParser::new(&header),
+        &Tables::default(),
+        true,
+        &mut |_, _| {},
+    );
+    assert!(matches!(
+        result,
+        Err(Error::UnsupportedFeature(message)) if message == "hierarchical JPEG not supported"
+    ));
+
+    // An expand reference components marker on its own is rejected the same way
+    let header = [0xFF, 0xD8, 0xFF, 0xDF, 0, 3, 0x11];
+    let result = HeaderInfo::read_header_info(
+        &mut JPEGParser::new(&header),
+        &Tables::default(),
+        false,
+        &mut |_, _| {},
+    );
+    assert!(matches!(
+        result,
+        Err(Error::UnsupportedFeature(message)) if message == "hierarchical JPEG not supported"
+    ));
+}
+
+#[test]
+fn read_packed_tables() {
+    // Two 8 bit quantization tables in one DQT segment
+    let mut segment = vec![0, 2 + 2 * 65];
+    for (info, value) in [(0x00, 2u8), (0x01, 3u8)] {
+        segment.push(info);
+        segment.extend([value; 64]);
+    }
+
+    let tables = HeaderInfo::read_quantization_tables(&mut JPEGParser::new(&segment)).unwrap();
+    assert_eq!(tables.len(), 2);
+    assert_eq!(tables[0].destination_id, 0);
+    assert_eq!(tables[0].table, [[2; 8]; 8]);
+    assert_eq!(tables[1].destination_id, 1);
+    assert_eq!(tables[1].table, [[3; 8]; 8]);
+
+    // Destinations 2 and 3 are as valid as 0 and 1, but there are only four
+    segment[2] = 0x03;
+    segment[2 + 65] = 0x02;
+    let tables = HeaderInfo::read_quantization_tables(&mut JPEGParser::new(&segment)).unwrap();
+    assert_eq!(tables[0].destination_id, 3);
+    assert_eq!(tables[1].destination_id, 2);
+    segment[2] = 0x04;
+    assert!(matches!(
+        HeaderInfo::read_quantization_tables(&mut JPEGParser::new(&segment)),
+        Err(Error::Malformed(message)) if message == "Invalid quantization table destination"
+    ));
+    segment[2] = 0x00;
+
+    // A length that ends partway through the second table
+    segment[1] = 2 + 65 + 10;
+    assert!(matches!(
+        HeaderInfo::read_quantization_tables(&mut JPEGParser::new(&segment)),
+        Err(Error::Malformed(message)) if message == "DQT tables overrun the segment length"
+    ));
+
+    // A DC and an AC Huffman table in one DHT segment, each with a single one bit code
+    let mut segment = vec![0, 2 + 2 * 18];
+    for (info, symbol) in [(0x00, 5u8), (0x10, 0x01u8)] {
+        segment.push(info);
+        segment.extend([1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        segment.push(symbol);
+    }
+
+    let tables = HeaderInfo::read_huffman_tables(&mut JPEGParser::new(&segment)).unwrap();
+    assert_eq!(tables.len(), 2);
+    assert!(matches!(tables[0].table_type, HuffmanTableType::Dc));
+    assert_eq!(tables[0].symbols, [5]);
+    assert!(matches!(tables[1].table_type, HuffmanTableType::Ac));
+    assert_eq!(tables[1].symbols, [0x01]);
+
+    segment[1] = 2 + 18 + 1;
+    assert!(matches!(
+        HeaderInfo::read_huffman_tables(&mut JPEGParser::new(&segment)),
+        Err(Error::Malformed(message)) if message == "DHT tables overrun the segment length"
+    ));
+}
+
+#[test]
+fn reject_bogus_scan_selector() {
+    let frame_components: Vec<FrameComponent> = (1..=3)
+        .map(|identifier| FrameComponent {
+            identifier,
+            xy_sampling_factor: (1, 1),
+            qtable_id: 0,
+        })
+        .collect();
+    let scan = |selectors: [u8; 3]| {
+        let mut segment = vec![0, 12, 3];
+        for selector in selectors {
+            segment.extend([selector, 0x00]);
+        }
+        segment.extend([0, 63, 0]);
+        HeaderInfo::read_start_of_scan(&mut JPEGParser::new(&segment), &frame_components)
+    };
+
+    let scan_info = scan([3, 1, 2]).unwrap();
+    let selectors: Vec<u8> = scan_info
+        .components
+        .iter()
+        .map(|component| component.selector)
+        .collect();
+    assert_eq!(selectors, [3, 1, 2]);
+
+    // A selector past the component count, and one selected twice
+    assert!(matches!(
+        scan([1, 2, 200]),
+        Err(Error::Malformed(message)) if message.contains("component 200")
+    ));
+    assert!(matches!(
+        scan([1, 2, 1]),
+        Err(Error::Malformed(message)) if message.contains("component 1")
+    ));
+}
@@ -1,276 +1,1242 @@
-use std::f32::consts::PI;
+use alloc::{format, vec, vec::Vec};
+use core::ops::ControlFlow;
 
 use crate::{
     bitstream::Bitstream,
     error::Result,
-    image::Bitmap,
+    image::{Bitmap, BitmapF32, ColorSpace},
     jpeg::jpeg_reader::{JPEGMarker, JPEGParser},
 };
 use crate::{error::Error, jpeg::header::*};
 
-#[rustfmt::skip]
-pub const ZIGZAG_MAP: &'static [(u8, u8)] = 
-    &[(0, 0), (0, 1), (1, 0), (2, 0), (1, 1), (0, 2), (0, 3), (1, 2),
-          (2, 1), (3, 0), (4, 0), (3, 1), (2, 2), (1, 3), (0, 4), (0, 5),
-          (1, 4), (2, 3), (3, 2), (4, 1), (5, 0), (6, 0), (5, 1), (4, 2),
-          (3, 3), (2, 4), (1, 5), (0, 6), (0, 7), (1, 6), (2, 5), (3, 4),
-          (4, 3), (5, 2), (6, 1), (7, 0), (7, 1), (6, 2), (5, 3), (4, 4),
-          (3, 5), (2, 6), (1, 7), (2, 7), (3, 6), (4, 5), (5, 4), (6, 3),
-          (7, 2), (7, 3), (6, 4), (5, 5), (4, 6), (3, 7), (4, 7), (5, 6),
-          (6, 5), (7, 4), (7, 5), (6, 6), (5, 7), (6, 7), (7, 6), (7, 7)];
+use super::zigzag::ZIGZAG_MAP;
+#[cfg(feature = "std")]
+use super::DecodeStats;
+use super::{
+    coefficients::CoefficientPlane, color, dct, ChannelOrder, CoefficientData,
+    ComponentCoefficients, DecodeOptions, IdctMethod, Plane, SegmentInfo, Upsampling,
+};
+
+/// A decoded AC Huffman symbol. F.1.2.2.1 Page 89
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum AcSymbol {
+    /// End of block, the remaining coefficients are all zero
+    Eob,
+    /// Zero run length, sixteen zero coefficients
+    Zrl,
+    /// `run` zero coefficients followed by a nonzero coefficient of `size` bits
+    Coefficient { run: u8, size: u8 },
+}
+
+impl AcSymbol {
+    /// Splits the Huffman value into its run length and size nibbles.
+    fn parse(value: u8) -> Result<Self> {
+        let (run, size) = (value >> 4, value & 0x0F);
+        match (run, size) {
+            (0, 0) => Ok(Self::Eob),
+            (15, 0) => Ok(Self::Zrl),
+            (_, 1..=10) => Ok(Self::Coefficient { run, size }),
+            _ => Err(Error::Malformed("Invalid AC coefficient size".into())),
+        }
+    }
+}
+
+/// How far into the data to look for the SOI marker when skipping leading garbage
+const LEADING_GARBAGE_WINDOW: usize = 4096;
 
 pub struct JPEGDecoder<'data> {
     reader: JPEGParser<'data>,
+    options: DecodeOptions,
+    tables: &'data Tables,
+    dc_predictions: Vec<i16>,
+    scratch: Scratch,
+}
+
+/// Buffers left over from a decode that the next decode can reuse, so decoding a run of frames
+/// of the same size, such as Motion JPEG, doesn't reallocate them for every frame.
+#[derive(Debug, Default)]
+pub struct Scratch {
     dc_predictions: Vec<i16>,
+    planes: Vec<CoefficientPlane>,
+    blocks: Vec<Vec<Macroblock>>,
 }
 
 impl<'data> JPEGDecoder<'data> {
-    pub fn new(data: &'data [u8]) -> Self {
+    pub fn new(data: &'data [u8], options: &DecodeOptions, tables: &'data Tables) -> Self {
         Self {
             reader: JPEGParser::new(data),
+            options: options.clone(),
+            tables,
             dc_predictions: vec![],
+            scratch: Scratch::default(),
         }
     }
 
+    /// Decodes with the buffers a previous decoder gave up with `into_scratch`
+    pub fn with_scratch(mut self, mut scratch: Scratch) -> Self {
+        self.dc_predictions = core::mem::take(&mut scratch.dc_predictions);
+        self.scratch = scratch;
+        self
+    }
+
+    /// Gives up the buffers of the last decode, for the next decoder to reuse
+    pub fn into_scratch(mut self) -> Scratch {
+        self.scratch.dc_predictions = self.dc_predictions;
+        self.scratch
+    }
+
     pub fn parse(&mut self) -> Result<HeaderInfo> {
-        HeaderInfo::read_header_info(&mut self.reader)
+        self.parse_with_app_segments(&mut |_, _| {})
+    }
+
+    /// Parses the header like `parse`, calling `app_segment` with the index and payload of each
+    /// APPn segment.
+    pub fn parse_with_app_segments(
+        &mut self,
+        app_segment: &mut dyn FnMut(u8, &[u8]),
+    ) -> Result<HeaderInfo> {
+        self.skip_leading_garbage()?;
+        HeaderInfo::read_header_info(
+            &mut self.reader,
+            self.tables,
+            self.options.strict,
+            app_segment,
+        )
+    }
+
+    /// Reads the tables defined before the first scan, or up to the EOI marker of an abbreviated
+    /// table specification.
+    pub fn parse_tables(&mut self) -> Result<Tables> {
+        self.skip_leading_garbage()?;
+        HeaderInfo::read_tables(&mut self.reader)
+    }
+
+    /// Walks the marker segments from the SOI marker to the EOI marker without decoding them.
+    pub fn read_segments(&mut self) -> Result<Vec<SegmentInfo>> {
+        self.skip_leading_garbage()?;
+
+        let mut segments = vec![];
+        loop {
+            let offset = self.reader.position();
+            let marker = self.reader.read_next_marker()?;
+            if segments.is_empty() && marker != JPEGMarker::SOI {
+                return Err(Error::Malformed(
+                    "This JPEG image does not have an SOI marker".into(),
+                ));
+            }
+
+            let length = match marker {
+                JPEGMarker::SOI | JPEGMarker::EOI => 0,
+                _ => {
+                    let start = self.reader.position();
+                    self.reader.skip_marker_with_length()?;
+                    if marker == JPEGMarker::SOS {
+                        self.reader.skip_entropy_coded_data();
+                    }
+                    self.reader.position() - start
+                }
+            };
+            segments.push(SegmentInfo {
+                marker,
+                offset: offset as u64,
+                length,
+            });
+
+            if marker == JPEGMarker::EOI {
+                return Ok(segments);
+            }
+        }
+    }
+
+    fn skip_leading_garbage(&mut self) -> Result<()> {
+        if self.options.skip_leading_garbage {
+            self.reader.seek_start_of_image(LEADING_GARBAGE_WINDOW)?;
+        }
+        Ok(())
     }
 
     pub fn read_scan(&mut self, header: &HeaderInfo) -> Result<Bitmap> {
-        let huffman_data = self.read_huffman_data()?;
-        let mut bitstream = Bitstream::new(&huffman_data.as_slice());
-        self.dc_predictions = vec![0; header.scan_info.components.len() + 1];
+        self.read_scan_with_progress(header, &mut |_| ControlFlow::Continue(()))
+    }
 
-        let mut blocks = vec![
-            vec![
-                Macroblock::new(header.mcu_info.max_xy_sampling_factor);
-                header.mcu_info.mcu_padded_dimensions.0 as usize
+    /// Reads the scan like `read_scan`, calling `progress` after each MCU row is reconstructed
+    /// with the fraction of rows done. Returns `Error::Cancelled` if `progress` breaks.
+    pub fn read_scan_with_progress(
+        &mut self,
+        header: &HeaderInfo,
+        progress: &mut dyn FnMut(f32) -> ControlFlow<()>,
+    ) -> Result<Bitmap> {
+        let mut blocks = self.read_blocks(header, false, progress)?;
+        let bitmap = Self::blocks_to_bitmap(
+            &mut blocks,
+            header,
+            header.frame_info.image_size,
+            self.options.channel_order,
+        );
+        self.scratch.blocks = blocks;
+        bitmap
+    }
+
+    /// Reads the scan like `read_scan`, but keeps the color converted samples as floats.
+    pub fn read_scan_f32(&mut self, header: &HeaderInfo) -> Result<BitmapF32> {
+        let mut blocks = self.read_blocks(header, false, &mut |_| ControlFlow::Continue(()))?;
+        Self::blocks_to_bitmap_f32(&mut blocks, header, self.options.channel_order)
+    }
+
+    /// Reads the scan like `read_scan`, timing each stage of the decode.
+    #[cfg(feature = "std")]
+    pub fn read_scan_with_stats(&mut self, header: &HeaderInfo) -> Result<(Bitmap, DecodeStats)> {
+        use std::time::Instant;
+
+        let mcu_dimensions = header.mcu_info.mcu_padded_dimensions;
+        let mcus = self.mcu_count(header)?;
+        let blocks_per_mcu: usize = header
+            .components
+            .iter()
+            .map(|component| {
+                let (h_factor, v_factor) = component.frame.xy_sampling_factor;
+                h_factor as usize * v_factor as usize
+            })
+            .sum();
+        let mut stats = DecodeStats {
+            mcus,
+            blocks: mcus * blocks_per_mcu,
+            ..Default::default()
+        };
+
+        let start = Instant::now();
+        let planes = self.decode_planes(header)?;
+        stats.entropy_decode = start.elapsed();
+
+        // The stages normally run one MCU at a time, but are run over the whole image here so
+        // each can be timed on its own
+        let start = Instant::now();
+        let mut blocks: Vec<Vec<Macroblock>> = (0..mcu_dimensions.1 as usize)
+            .map(|vert| {
+                (0..mcu_dimensions.0 as usize)
+                    .map(|horiz| {
+                        let mut block = Macroblock::new(header.mcu_info.max_xy_sampling_factor);
+                        self.transform_block(&mut block, &planes, header, (horiz, vert), false);
+                        block
+                    })
+                    .collect()
+            })
+            .collect();
+        stats.idct = start.elapsed();
+
+        let start = Instant::now();
+        for block in blocks.iter_mut().flatten() {
+            self.upsample_block(block, header, false);
+        }
+        stats.upsampling = start.elapsed();
+
+        let start = Instant::now();
+        let bitmap = Self::blocks_to_bitmap(
+            &mut blocks,
+            header,
+            header.frame_info.image_size,
+            self.options.channel_order,
+        )?;
+        stats.color_conversion = start.elapsed();
+
+        Ok((bitmap, stats))
+    }
+
+    /// Reads the scan like `read_scan`, but stops after the MCU row holding pixel row
+    /// `max_rows - 1` and returns a bitmap cut off at that row.
+    pub fn read_scan_rows(&mut self, mut header: HeaderInfo, max_rows: u16) -> Result<Bitmap> {
+        // Baseline scans are interleaved in raster order, so ending early just leaves out the
+        // bottom MCU rows
+        let mcu_height = header.mcu_info.mcu_size.1 as u16;
+        let mcu_rows = max_rows
+            .div_ceil(mcu_height)
+            .min(header.mcu_info.mcu_padded_dimensions.1);
+        header.mcu_info.mcu_padded_dimensions.1 = mcu_rows;
+        // The padded height can be past 65535, so compare in 32 bits
+        let rows = mcu_rows as u32 * mcu_height as u32;
+        header.frame_info.image_size.1 = (header.frame_info.image_size.1 as u32).min(rows) as u16;
+
+        self.read_scan(&header)
+    }
+
+    /// Reads the scan like `read_scan`, but only reconstructs the luma component and returns a
+    /// single channel bitmap.
+    pub fn read_luma_scan(&mut self, header: &HeaderInfo) -> Result<Bitmap> {
+        let mut blocks = self.read_blocks(header, true, &mut |_| ControlFlow::Continue(()))?;
+        Ok(Self::blocks_to_luma_bitmap(&mut blocks, header))
+    }
+
+    fn read_blocks(
+        &mut self,
+        header: &HeaderInfo,
+        luma_only: bool,
+        progress: &mut dyn FnMut(f32) -> ControlFlow<()>,
+    ) -> Result<Vec<Vec<Macroblock>>> {
+        let planes = self.decode_planes(header)?;
+        let blocks = self.reconstruct_blocks(&planes, header, luma_only, progress);
+        self.scratch.planes = planes;
+        blocks
+    }
+
+    /// Reconstructs every MCU of the image from the coefficient planes, calling `progress` after
+    /// each MCU row.
+    fn reconstruct_blocks(
+        &mut self,
+        planes: &[CoefficientPlane],
+        header: &HeaderInfo,
+        luma_only: bool,
+        progress: &mut dyn FnMut(f32) -> ControlFlow<()>,
+    ) -> Result<Vec<Vec<Macroblock>>> {
+        let mcu_dimensions = header.mcu_info.mcu_padded_dimensions;
+        let max_factor = header.mcu_info.max_xy_sampling_factor;
+        let block_size = (8 * max_factor.0 as usize, 8 * max_factor.1 as usize);
+        // Every block is overwritten, so blocks left from a decode of the same size can be reused
+        let mut blocks = core::mem::take(&mut self.scratch.blocks);
+        let reusable = blocks.len() == mcu_dimensions.1 as usize
+            && blocks.iter().all(|row| {
+                row.len() == mcu_dimensions.0 as usize
+                    && row.iter().all(|block| block.size() == block_size)
+            });
+        if !reusable {
+            blocks = vec![
+                vec![Macroblock::new(max_factor); mcu_dimensions.0 as usize];
+                mcu_dimensions.1 as usize
             ];
-            header.mcu_info.mcu_padded_dimensions.1 as usize
-        ];
+        }
 
         for vert in 0..header.mcu_info.mcu_padded_dimensions.1 {
             for horiz in 0..header.mcu_info.mcu_padded_dimensions.0 {
-                blocks[vert as usize][horiz as usize] =
-                    self.decode_block(&mut bitstream, header)?;
+                // Reconstruct in place, so no MCU is allocated twice
+                self.reconstruct_block(
+                    &mut blocks[vert as usize][horiz as usize],
+                    planes,
+                    header,
+                    (horiz as usize, vert as usize),
+                    luma_only,
+                );
+            }
+
+            let fraction = (vert + 1) as f32 / header.mcu_info.mcu_padded_dimensions.1 as f32;
+            if progress(fraction).is_break() {
+                return Err(Error::Cancelled);
             }
         }
 
-        Ok(Self::blocks_to_bitmap(&mut blocks, header))
+        Ok(blocks)
     }
 
-    fn blocks_to_bitmap(blocks: &mut Vec<Vec<Macroblock>>, header: &HeaderInfo) -> Bitmap {
-        let channels = header.components.len() as u8;
-        let size = header.frame_info.image_size;
-        let mut data = vec![0u8; size.0 as usize * size.1 as usize * channels as usize];
-        for y in 0..size.1 {
-            for x in 0..size.0 {
-                let block_y = y / (8 * header.mcu_info.max_xy_sampling_factor.1 as u16);
-                let block_x = x / (8 * header.mcu_info.max_xy_sampling_factor.0 as u16);
-                let pixel_y = y % (8 * header.mcu_info.max_xy_sampling_factor.1 as u16);
-                let pixel_x = x % (8 * header.mcu_info.max_xy_sampling_factor.0 as u16);
+    pub fn read_coefficients(&mut self, header: &HeaderInfo) -> Result<CoefficientData> {
+        let planes = self.decode_planes(header)?;
+        Ok(Self::dequantize_planes(&planes, header))
+    }
 
-                let block = &mut blocks[block_y as usize][block_x as usize];
-                // TODO: Support greyscale
-                let y_cb_cr = (
-                    block.get_component(1)[pixel_y as usize][pixel_x as usize],
-                    block.get_component(2)[pixel_y as usize][pixel_x as usize],
-                    block.get_component(3)[pixel_y as usize][pixel_x as usize],
-                );
+    /// Reads the scan like `read_scan`, also returning the dequantized coefficients the pixels
+    /// were reconstructed from.
+    pub fn read_scan_with_coefficients(
+        &mut self,
+        header: &HeaderInfo,
+    ) -> Result<(Bitmap, CoefficientData)> {
+        let planes = self.decode_planes(header)?;
+        let mut blocks =
+            self.reconstruct_blocks(&planes, header, false, &mut |_| ControlFlow::Continue(()))?;
+        let bitmap = Self::blocks_to_bitmap(
+            &mut blocks,
+            header,
+            header.frame_info.image_size,
+            self.options.channel_order,
+        )?;
+        Ok((bitmap, Self::dequantize_planes(&planes, header)))
+    }
 
-                let rgb = Self::ycbcr_to_rgb(y_cb_cr);
+    /// Dequantizes every block of the coefficient planes, keeping them in natural order.
+    fn dequantize_planes(planes: &[CoefficientPlane], header: &HeaderInfo) -> CoefficientData {
+        let components = header
+            .components
+            .iter()
+            .zip(planes)
+            .map(|(component, plane)| {
+                let qtable = Self::component_qtable(header, component);
+                ComponentCoefficients {
+                    identifier: component.frame.identifier,
+                    block_dimensions: (
+                        plane.block_dimensions.0 as u16,
+                        plane.block_dimensions.1 as u16,
+                    ),
+                    blocks: plane
+                        .blocks()
+                        .iter()
+                        .map(|coefficients| Self::dequantize(coefficients, &qtable))
+                        .collect(),
+                }
+            })
+            .collect();
+
+        CoefficientData { components }
+    }
+
+    /// Reads the scan and returns the samples of each component as a separate plane, in frame
+    /// order. Subsampled components are stretched to the image size unless the options ask to
+    /// skip upsampling. Each row is zero padded to a multiple of `row_align` bytes.
+    pub fn read_component_planes(
+        &mut self,
+        header: &HeaderInfo,
+        row_align: usize,
+    ) -> Result<Vec<Plane>> {
+        let coefficient_planes = self.decode_planes(header)?;
+        let image_size = header.frame_info.image_size;
+        let max_factor = header.mcu_info.max_xy_sampling_factor;
+
+        let mut planes = Vec::with_capacity(header.components.len());
+        for (component, coefficients) in header.components.iter().zip(&coefficient_planes) {
+            let qtable = Self::component_qtable(header, component);
+            let (width, height) = coefficients.block_dimensions;
 
-                let data_index = ((y as usize * size.0 as usize) + x as usize) * channels as usize;
-                data[data_index + 0] = rgb.0;
-                data[data_index + 1] = rgb.1;
-                data[data_index + 2] = rgb.2;
+            let mut samples = vec![vec![0i16; width * 8]; height * 8];
+            for block_y in 0..height {
+                for block_x in 0..width {
+                    let dequantized =
+                        Self::dequantize(coefficients.block(block_x, block_y), &qtable);
+                    for (row, coefficient_row) in dequantized.iter().enumerate() {
+                        samples[block_y * 8 + row][block_x * 8..block_x * 8 + 8]
+                            .copy_from_slice(coefficient_row);
+                    }
+                    self.inverse_dct(&mut samples, block_y * 8, block_x * 8);
+                }
             }
+
+            // A component covers ceil(size * factor / max_factor) samples. A.1.1 Page 24
+            let factor = component.frame.xy_sampling_factor;
+            let size = if self.options.skip_upsampling {
+                (
+                    (image_size.0 as u32 * factor.0 as u32).div_ceil(max_factor.0 as u32) as u16,
+                    (image_size.1 as u32 * factor.1 as u32).div_ceil(max_factor.1 as u32) as u16,
+                )
+            } else {
+                image_size
+            };
+            // Stretch with the same mapping as `upsample` uses within each MCU, but over the
+            // whole plane, so interpolation carries on across MCU edges
+            let samples = if self.options.skip_upsampling {
+                samples
+            } else {
+                Self::resample(
+                    &samples,
+                    (width * 8, height * 8),
+                    (size.0 as usize, size.1 as usize),
+                    factor,
+                    max_factor,
+                    self.options.upsampling,
+                )
+            };
+
+            let stride = (size.0 as usize).next_multiple_of(row_align.max(1));
+            let mut data = vec![0u8; stride * size.1 as usize];
+            for y in 0..size.1 as usize {
+                let row = &mut data[y * stride..y * stride + size.0 as usize];
+                for (sample, source) in row.iter_mut().zip(&samples[y]) {
+                    *sample = (source + 128) as u8;
+                }
+            }
+
+            planes.push(Plane {
+                identifier: component.frame.identifier,
+                size,
+                stride,
+                data,
+            });
         }
-        Bitmap {
-            channels,
-            size,
-            data,
+        Ok(planes)
+    }
+
+    /// Entropy decodes the whole scan, checking that every MCU decodes, without reconstructing
+    /// any samples.
+    pub fn verify_scan(&mut self, header: &HeaderInfo) -> Result<()> {
+        self.decode_planes(header)?;
+        Ok(())
+    }
+
+    /// Entropy decodes the whole scan and returns an iterator that reconstructs one MCU at a time.
+    pub fn into_mcu_iter(mut self, header: HeaderInfo) -> Result<McuIter<'data>> {
+        let planes = self.decode_planes(&header)?;
+        Ok(McuIter {
+            decoder: self,
+            header,
+            planes,
+            next_mcu: 0,
+        })
+    }
+
+    /// Returns an iterator that decodes the scan one MCU row at a time. The entropy coded data is
+    /// read in place as each row is reached, so at most one MCU row of coefficients and samples
+    /// is held at once.
+    pub fn into_row_iter(mut self, header: HeaderInfo) -> Result<RowIter<'data>> {
+        if header.components.len() != 3 {
+            return Err(Error::UnsupportedFeature(
+                "Only images with 3 components can be decoded to RGB".into(),
+            ));
         }
+        self.mcu_count(&header)?;
+        Self::check_tables(&header)?;
+        self.reset_dc_predictions(&header);
+
+        Ok(RowIter {
+            planes: Self::coefficient_planes(&header, 1),
+            blocks: vec![
+                Macroblock::new(header.mcu_info.max_xy_sampling_factor);
+                header.mcu_info.mcu_padded_dimensions.0 as usize
+            ],
+            bitstream: self.entropy_coded_data(),
+            decoder: self,
+            header,
+            next_row: 0,
+            complete: true,
+        })
     }
 
-    fn ycbcr_to_rgb(y_cb_cr: (i16, i16, i16)) -> (u8, u8, u8) {
-        let lum = y_cb_cr.0 as f32;
-        let cb = y_cb_cr.1 as f32;
-        let cr = y_cb_cr.2 as f32;
+    /// Entropy decodes the scan into a coefficient plane for each component, in frame order.
+    fn decode_planes(&mut self, header: &HeaderInfo) -> Result<Vec<CoefficientPlane>> {
+        // Check the size and tables before allocating anything for them
+        self.mcu_count(header)?;
+        Self::check_tables(header)?;
+        self.reset_dc_predictions(header);
+        let mut bitstream = self.entropy_coded_data();
 
-        let red = (cr * (2f32 - 2f32 * 0.299)) + lum;
-        let blue = (cb * (2f32 - 2f32 * 0.114)) + lum;
-        let green = (lum - (0.114 * blue) - (0.299 * red)) / 0.587;
+        let mcu_dimensions = header.mcu_info.mcu_padded_dimensions;
+        let mut planes = core::mem::take(&mut self.scratch.planes);
+        let reusable = planes.len() == header.components.len()
+            && planes
+                .iter()
+                .zip(Self::plane_dimensions(header, mcu_dimensions.1 as usize))
+                .all(|(plane, dimensions)| plane.block_dimensions == dimensions);
+        if reusable {
+            planes.iter_mut().for_each(CoefficientPlane::clear);
+        } else {
+            planes = Self::coefficient_planes(header, mcu_dimensions.1 as usize);
+        }
+        for mcu_y in 0..mcu_dimensions.1 as usize {
+            if !self.decode_mcu_row(&mut bitstream, header, mcu_y, &mut planes, mcu_y)? {
+                break;
+            }
+        }
 
-        (
-            (red + 128f32) as u8,
-            (green + 128f32) as u8,
-            (blue + 128f32) as u8,
-        )
+        self.finish_scan(&mut bitstream)?;
+        Ok(planes)
+    }
+
+    /// Returns a bitstream over the entropy coded data following the SOS segment. The data is
+    /// read in place, ending at the first marker other than a restart marker the decode expects.
+    fn entropy_coded_data(&self) -> Bitstream<'data> {
+        Bitstream::new(self.reader.remaining())
+            .with_byte_stuffing(true)
+            .with_fill_past_end(self.options.fill_missing_bits)
+    }
+
+    /// Moves the reader past the entropy coded data `bitstream` has decoded, then reads up to the
+    /// EOI marker. Later scans aren't decoded, but are still counted against
+    /// `DecodeOptions::max_scans`.
+    fn finish_scan(&mut self, bitstream: &mut Bitstream) -> Result<()> {
+        bitstream.align_to_byte();
+        self.reader.skip(bitstream.get_cursor_position() / 8);
+
+        // The header parser already read the first scan's SOS
+        let mut scan_count = 1;
+        loop {
+            // Anything left before the next marker, like the data of later scans, is skipped
+            self.reader.skip_entropy_coded_data();
+            let Some(_) = self.read_entropy_coded_byte()? else {
+                return Ok(());
+            };
+            let Some(mut identifier) = self.read_entropy_coded_byte()? else {
+                return Ok(());
+            };
+            // Any number of 0xFF fill bytes can come before a marker. B.1.1.2 Page 31
+            while identifier == 0xFF {
+                match self.read_entropy_coded_byte()? {
+                    Some(byte) => identifier = byte,
+                    None => return Ok(()),
+                }
+            }
+
+            match JPEGParser::to_marker(0xFF00 | identifier as u16)? {
+                JPEGMarker::EOI => return Ok(()),
+                JPEGMarker::SOS => {
+                    scan_count += 1;
+                    if scan_count > self.options.max_scans {
+                        return Err(Error::UnsupportedFeature("too many scans".into()));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Sets the DC prediction of every component to zero, keeping the allocation.
+    fn reset_dc_predictions(&mut self, header: &HeaderInfo) {
+        self.dc_predictions.clear();
+        self.dc_predictions.resize(header.components.len(), 0);
+    }
+
+    /// Allocates a coefficient plane for each component, `mcu_rows` MCUs tall.
+    fn coefficient_planes(header: &HeaderInfo, mcu_rows: usize) -> Vec<CoefficientPlane> {
+        Self::plane_dimensions(header, mcu_rows)
+            .map(CoefficientPlane::new)
+            .collect()
+    }
+
+    /// The block dimensions of each component's coefficient plane, `mcu_rows` MCUs tall.
+    fn plane_dimensions(
+        header: &HeaderInfo,
+        mcu_rows: usize,
+    ) -> impl Iterator<Item = (usize, usize)> + '_ {
+        header.components.iter().map(move |component| {
+            (
+                header.mcu_info.mcu_padded_dimensions.0 as usize
+                    * component.frame.xy_sampling_factor.0 as usize,
+                mcu_rows * component.frame.xy_sampling_factor.1 as usize,
+            )
+        })
     }
 
-    fn decode_block(
+    /// Entropy decodes MCU row `mcu_y` of the scan into MCU row `plane_y` of the coefficient
+    /// planes. Returns `false` if the scan broke off in lenient mode, leaving the rest of the
+    /// row untouched.
+    fn decode_mcu_row(
         &mut self,
         bitstream: &mut Bitstream,
         header: &HeaderInfo,
-    ) -> Result<Macroblock> {
-        let mut block = Macroblock::new(header.mcu_info.max_xy_sampling_factor);
+        mcu_y: usize,
+        planes: &mut [CoefficientPlane],
+        plane_y: usize,
+    ) -> Result<bool> {
+        let mcus_x = header.mcu_info.mcu_padded_dimensions.0 as usize;
+        for mcu_x in 0..mcus_x {
+            // Each restart interval is coded on its own, starting on a byte boundary. The last
+            // interval can be short, since no marker follows the final MCU
+            let mcu_index = mcu_y * mcus_x + mcu_x;
+            let interval = header.restart_interval as usize;
+            if interval > 0 {
+                if mcu_index > 0 && mcu_index.is_multiple_of(interval) {
+                    // The markers count up from RST0, wrapping after RST7. B.2.1 Page 34
+                    let expected = 0xD0 + ((mcu_index / interval - 1) % 8) as u8;
+                    match bitstream.read_marker() {
+                        Some(marker) if marker == expected => {}
+                        Some(0xD0..=0xD7) if !self.options.strict => {}
+                        Some(marker @ 0xD0..=0xD7) => {
+                            return Err(Error::Malformed(format!(
+                                "Expected RST{} at the end of a restart interval, got RST{}",
+                                expected - 0xD0,
+                                marker - 0xD0
+                            )))
+                        }
+                        _ => {
+                            return Err(Error::Malformed(
+                                "Restart marker missing at the end of a restart interval".into(),
+                            ))
+                        }
+                    }
+                    self.dc_predictions.fill(0);
+                }
+            } else if let Some(0xD0..=0xD7) = bitstream.next_marker() {
+                // Without a DRI segment the interval length isn't known, so move on to the
+                // next interval once only the padding of the current one is left
+                bitstream.read_marker();
+                self.dc_predictions.fill(0);
+            }
 
-        // Decode each MCU
-        for component in &header.components {
-            let dc_table = header.dc_huff_tables.get(&component.scan.dc_table).unwrap();
-            let ac_table = header.ac_huff_tables.get(&component.scan.ac_table).unwrap();
-            let qtable = header
-                .quant_tables
-                .get(&component.frame.qtable_id)
-                .unwrap()
-                .table;
-
-            let component_block = block.get_component(component.scan.selector);
-
-            for mcu_row in 0..component.frame.xy_sampling_factor.1 {
-                for mcu_col in 0..component.frame.xy_sampling_factor.0 {
-                    let base_y = mcu_row as usize * 8;
-                    let base_x = mcu_col as usize * 8;
-
-                    let mut dct_coefficients = vec![0i16; 64];
-
-                    // Calculate DC coefficient
-                    // https://www.w3.org/Graphics/JPEG/itu-t81.pdf
-                    // F.2.2.1 Page 104
-                    let (dc_code, _) = self.decode_next_value(bitstream, dc_table)?; // DECODE
-                    let mut diff = bitstream.read_bits(dc_code as usize)? as i16; // RECEIVE
-
-                    if dc_code != 0 && diff < (1 << (dc_code - 1)) {
-                        diff -= (1 << dc_code) - 1; // EXTEND, If MSB is 0 then negative. 1 is positive
+            for &component_index in &header.scan_order {
+                let component = &header.components[component_index];
+                let plane = &mut planes[component_index];
+                let (dc_table, ac_table) = Self::component_huffman_tables(header, component);
+                let (h_factor, v_factor) = component.frame.xy_sampling_factor;
+
+                for row in 0..v_factor as usize {
+                    for col in 0..h_factor as usize {
+                        let block_x = mcu_x * h_factor as usize + col;
+                        let block_y = plane_y * v_factor as usize + row;
+
+                        match self.decode_data_unit(bitstream, component_index, dc_table, ac_table)
+                        {
+                            Ok(coefficients) => *plane.block_mut(block_x, block_y) = coefficients,
+                            // Keep what was decoded before the scan broke off and leave the
+                            // remaining blocks gray
+                            Err(_) if !self.options.strict => return Ok(false),
+                            Err(error) => return Err(error),
+                        }
                     }
+                }
+            }
+        }
 
-                    let dc_coefficient =
-                        self.dc_predictions[component.scan.selector as usize] + diff;
+        Ok(true)
+    }
 
-                    self.dc_predictions[component.scan.selector as usize] = dc_coefficient;
+    /// Returns the number of MCUs in the image, including padding MCUs past its edges. Fails if
+    /// the padded image has more pixels than `DecodeOptions::max_pixels`, or too many to count.
+    fn mcu_count(&self, header: &HeaderInfo) -> Result<usize> {
+        let (mcus_x, mcus_y) = header.mcu_info.mcu_padded_dimensions;
+        let (mcu_width, mcu_height) = header.mcu_info.mcu_size;
+        let mcus = (mcus_x as usize).checked_mul(mcus_y as usize);
+        let pixels = mcus
+            .and_then(|mcus| mcus.checked_mul(mcu_width as usize * mcu_height as usize))
+            .filter(|&pixels| pixels as u64 <= self.options.max_pixels);
+        match (mcus, pixels) {
+            (Some(mcus), Some(_)) => Ok(mcus),
+            _ => Err(Error::UnsupportedFeature(format!(
+                "image is too large ({}x{} pixels padded, the limit is {} pixels)",
+                mcus_x as u32 * mcu_width as u32,
+                mcus_y as u32 * mcu_height as u32,
+                self.options.max_pixels
+            ))),
+        }
+    }
 
-                    dct_coefficients[0] = dc_coefficient;
+    /// Reads the scan like `read_scan`, but writes the pixels into `data`, reusing its
+    /// allocation, and returns the image size and channel count.
+    pub fn read_scan_into(
+        &mut self,
+        header: &HeaderInfo,
+        data: &mut Vec<u8>,
+    ) -> Result<((u16, u16), u8)> {
+        let mut blocks = self.read_blocks(header, false, &mut |_| ControlFlow::Continue(()))?;
+        let pixels = Self::blocks_to_pixels(
+            &mut blocks,
+            header,
+            header.frame_info.image_size,
+            self.options.channel_order,
+            data,
+        );
+        self.scratch.blocks = blocks;
+        pixels?;
+        Ok((
+            header.frame_info.image_size,
+            self.options.channel_order.channels() as u8,
+        ))
+    }
 
-                    // Calculate AC coefficients
-                    // https://www.w3.org/Graphics/JPEG/itu-t81.pdf
-                    // F.13 Page 106
+    fn blocks_to_bitmap(
+        blocks: &mut [Vec<Macroblock>],
+        header: &HeaderInfo,
+        size: (u16, u16),
+        channel_order: ChannelOrder,
+    ) -> Result<Bitmap> {
+        let mut data = Vec::new();
+        Self::blocks_to_pixels(blocks, header, size, channel_order, &mut data)?;
+        Ok(Bitmap {
+            channels: channel_order.channels() as u8,
+            color_space: channel_order.color_space(),
+            bit_depth: 8,
+            size,
+            data,
+        })
+    }
 
-                    let mut k = 0;
-                    while k != 63 {
-                        k += 1;
+    /// Converts the top `size` pixels of the blocks to interleaved pixels in `channel_order`,
+    /// replacing the contents of `data`. Only YCbCr images, with three components, can be
+    /// converted so far.
+    fn blocks_to_pixels(
+        blocks: &mut [Vec<Macroblock>],
+        header: &HeaderInfo,
+        size: (u16, u16),
+        channel_order: ChannelOrder,
+        data: &mut Vec<u8>,
+    ) -> Result<()> {
+        let row_length = size.0 as usize * channel_order.channels();
+        data.clear();
+        data.resize(row_length * size.1 as usize, 0);
 
-                        let (huffman_val, _) = self.decode_next_value(bitstream, ac_table)?;
+        Self::for_each_ycbcr_row(blocks, header, size, |y, lum, cb, cr| {
+            let row = &mut data[y * row_length..(y + 1) * row_length];
+            match channel_order {
+                ChannelOrder::Rgb => color::ycbcr_row_to_rgb(lum, cb, cr, row),
+                ChannelOrder::Bgr => {
+                    color::ycbcr_row_to_rgb(lum, cb, cr, row);
+                    row.chunks_exact_mut(3).for_each(|pixel| pixel.swap(0, 2));
+                }
+                ChannelOrder::Rgba => color::ycbcr_row_to_rgba(lum, cb, cr, row),
+            }
+        })
+    }
 
-                        match huffman_val {
-                            0x00 => {
-                                break;
-                            }
-                            0xF0 => {
-                                k += 15; // Skip 15+1(top of loop) zeroes.
-                                continue;
-                            }
-                            _ => {
-                                let run_length = huffman_val >> 4;
-                                k += run_length;
+    /// Converts the blocks to interleaved float samples in `channel_order`, without rounding or
+    /// clamping them to bytes.
+    fn blocks_to_bitmap_f32(
+        blocks: &mut [Vec<Macroblock>],
+        header: &HeaderInfo,
+        channel_order: ChannelOrder,
+    ) -> Result<BitmapF32> {
+        let size = header.frame_info.image_size;
+        let pixel_length = channel_order.channels();
+        let row_length = size.0 as usize * pixel_length;
+        let mut data = vec![0f32; row_length * size.1 as usize];
 
-                                if k > 64 {
-                                    return Err(Error::Malformed("Run length exceeds max K of 64"));
-                                }
+        Self::for_each_ycbcr_row(blocks, header, size, |y, lum, cb, cr| {
+            let row = &mut data[y * row_length..(y + 1) * row_length];
+            for (x, pixel) in row.chunks_exact_mut(pixel_length).enumerate() {
+                let (red, green, blue) =
+                    color::ycbcr_to_rgb_f32((lum[x] as f32, cb[x] as f32, cr[x] as f32));
+                match channel_order {
+                    ChannelOrder::Rgb => pixel.copy_from_slice(&[red, green, blue]),
+                    ChannelOrder::Bgr => pixel.copy_from_slice(&[blue, green, red]),
+                    ChannelOrder::Rgba => pixel.copy_from_slice(&[red, green, blue, 255.0]),
+                }
+            }
+        })?;
 
-                                let code_length = huffman_val & 0b1111;
-                                let mut value = bitstream.read_bits(code_length as usize)? as i16;
+        Ok(BitmapF32 {
+            channels: pixel_length as u8,
+            color_space: channel_order.color_space(),
+            size,
+            data,
+        })
+    }
 
-                                // EXTEND
-                                if value < (1 << (code_length - 1)) {
-                                    value -= (1 << code_length) - 1;
-                                }
+    /// Gathers the Y, Cb, and Cr samples of each of the top `size.1` pixel rows of the blocks and
+    /// passes them to `convert` with the row's index.
+    fn for_each_ycbcr_row(
+        blocks: &mut [Vec<Macroblock>],
+        header: &HeaderInfo,
+        size: (u16, u16),
+        mut convert: impl FnMut(usize, &[i16], &[i16], &[i16]),
+    ) -> Result<()> {
+        if header.components.len() != 3 {
+            return Err(Error::UnsupportedFeature(
+                "Only images with 3 components can be decoded to RGB".into(),
+            ));
+        }
 
-                                dct_coefficients[k as usize] = value;
-                            }
-                        }
-                    }
+        let mut lum = vec![0i16; size.0 as usize];
+        let mut cb = vec![0i16; size.0 as usize];
+        let mut cr = vec![0i16; size.0 as usize];
+        let mcu_size = (
+            header.mcu_info.mcu_size.0 as usize,
+            header.mcu_info.mcu_size.1 as usize,
+        );
+        for y in 0..size.1 as usize {
+            for x in 0..size.0 as usize {
+                let (pixel_x, pixel_y) = (x % mcu_size.0, y % mcu_size.1);
 
-                    // Dequantize and unzigzag
-                    for i in 0..64 {
-                        let (row, col) = ZIGZAG_MAP[i];
-                        component_block[row as usize + base_y][col as usize + base_x] =
-                            dct_coefficients[i] * qtable[row as usize][col as usize] as i16;
-                    }
+                let block = &mut blocks[y / mcu_size.1][x / mcu_size.0];
+                // TODO: Support greyscale
+                lum[x] = block.get_component(0)[pixel_y][pixel_x];
+                cb[x] = block.get_component(1)[pixel_y][pixel_x];
+                cr[x] = block.get_component(2)[pixel_y][pixel_x];
+            }
 
-                    // Perform the IDCT
-                    // https://www.w3.org/Graphics/JPEG/itu-t81.pdf
-                    // A.3.3 Page 27
-                    let mut idct_block = component_block.clone();
-                    for y in 0..8 {
-                        for x in 0..8 {
-                            let mut value = 0.0f32;
-                            for u in 0..8 {
-                                for v in 0..8 {
-                                    let cu = if u == 0 {
-                                        1f32 / f32::sqrt(2.0f32)
-                                    } else {
-                                        1.0f32
-                                    };
-                                    let cv = if v == 0 {
-                                        1f32 / f32::sqrt(2.0f32)
-                                    } else {
-                                        1f32
-                                    };
-                                    let idct_val = cu as f32
-                                        * cv as f32
-                                        * f32::cos(
-                                            ((2.0f32 * x as f32 + 1.0f32) * u as f32 * PI)
-                                                / 16.0f32,
-                                        )
-                                        * f32::cos(
-                                            ((2.0f32 * y as f32 + 1.0f32) * v as f32 * PI)
-                                                / 16.0f32,
-                                        );
-
-                                    let coeff = component_block[base_y + v][base_x + u] as f32;
-                                    value += idct_val * coeff;
-                                }
-                            }
-
-                            value /= 4.0f32;
-
-                            idct_block[base_y + y][base_x + x] = value as i16;
-                        }
+            convert(y, &lum, &cb, &cr);
+        }
+        Ok(())
+    }
+
+    fn blocks_to_luma_bitmap(blocks: &mut [Vec<Macroblock>], header: &HeaderInfo) -> Bitmap {
+        let size = header.frame_info.image_size;
+        let mut data = vec![0u8; size.0 as usize * size.1 as usize];
+        let mcu_size = (
+            header.mcu_info.mcu_size.0 as usize,
+            header.mcu_info.mcu_size.1 as usize,
+        );
+        for y in 0..size.1 as usize {
+            for x in 0..size.0 as usize {
+                let (pixel_x, pixel_y) = (x % mcu_size.0, y % mcu_size.1);
+
+                let block = &mut blocks[y / mcu_size.1][x / mcu_size.0];
+                let lum = block.get_component(0)[pixel_y][pixel_x];
+
+                data[(y * size.0 as usize) + x] = (lum + 128) as u8;
+            }
+        }
+        Bitmap {
+            channels: 1,
+            color_space: ColorSpace::Gray,
+            bit_depth: 8,
+            size,
+            data,
+        }
+    }
+
+    /// Dequantizes and reconstructs the MCU at `mcu` from the coefficient planes into `block`.
+    /// When `luma_only` is set the chroma components are left untouched.
+    fn reconstruct_block(
+        &self,
+        block: &mut Macroblock,
+        planes: &[CoefficientPlane],
+        header: &HeaderInfo,
+        mcu: (usize, usize),
+        luma_only: bool,
+    ) {
+        self.transform_block(block, planes, header, mcu, luma_only);
+        self.upsample_block(block, header, luma_only);
+    }
+
+    /// Dequantizes and inverse transforms the MCU at `mcu` into `block`, leaving subsampled
+    /// components in the top left of their blocks.
+    fn transform_block(
+        &self,
+        block: &mut Macroblock,
+        planes: &[CoefficientPlane],
+        header: &HeaderInfo,
+        mcu: (usize, usize),
+        luma_only: bool,
+    ) {
+        for (index, (component, plane)) in header.components.iter().zip(planes).enumerate() {
+            if luma_only && index != 0 {
+                continue;
+            }
+
+            let qtable = Self::component_qtable(header, component);
+            let component_block = block.get_component(index);
+            let (h_factor, v_factor) = component.frame.xy_sampling_factor;
+
+            for mcu_row in 0..v_factor as usize {
+                for mcu_col in 0..h_factor as usize {
+                    let base_y = mcu_row * 8;
+                    let base_x = mcu_col * 8;
+
+                    let coefficients = Self::dequantize(
+                        plane.block(
+                            mcu.0 * h_factor as usize + mcu_col,
+                            mcu.1 * v_factor as usize + mcu_row,
+                        ),
+                        &qtable,
+                    );
+                    for (row, coefficient_row) in coefficients.iter().enumerate() {
+                        component_block[base_y + row][base_x..base_x + 8]
+                            .copy_from_slice(coefficient_row);
                     }
 
-                    *component_block = idct_block;
+                    self.inverse_dct(component_block, base_y, base_x);
                 }
             }
+        }
+    }
+
+    /// Stretches the subsampled components of a transformed MCU to the correct size
+    fn upsample_block(&self, block: &mut Macroblock, header: &HeaderInfo, luma_only: bool) {
+        for (index, component) in header.components.iter().enumerate() {
+            if luma_only && index != 0 {
+                continue;
+            }
+            Self::upsample(
+                block.get_component(index),
+                component.frame.xy_sampling_factor,
+                header.mcu_info.max_xy_sampling_factor,
+                self.options.upsampling,
+            );
+        }
+    }
+
+    /// Performs the IDCT selected by the options in place on the 8x8 block at (base_x, base_y)
+    fn inverse_dct(&self, component_block: &mut [Vec<i16>], base_y: usize, base_x: usize) {
+        match self.options.idct {
+            IdctMethod::Float => dct::inverse_dct_in_place(component_block, base_y, base_x),
+            IdctMethod::Integer => {
+                dct::inverse_dct_integer_in_place(component_block, base_y, base_x)
+            }
+            IdctMethod::Fast => dct::inverse_dct_fast_in_place(component_block, base_y, base_x),
+        }
+    }
+
+    /// Stretches a component's samples, stored in the top left of `component_block`, to fill the
+    /// whole MCU. Samples past the edges of the MCU are treated as copies of the edge samples.
+    fn upsample(
+        component_block: &mut [Vec<i16>],
+        factor: (u8, u8),
+        max_factor: (u8, u8),
+        method: Upsampling,
+    ) {
+        if factor == max_factor {
+            return;
+        }
+
+        let size = (component_block[0].len(), component_block.len());
+        let source_size = (
+            size.0 * factor.0 as usize / max_factor.0 as usize,
+            size.1 * factor.1 as usize / max_factor.1 as usize,
+        );
+        // An MCU is at most 32 samples across, so each line is copied out to the stack rather
+        // than allocating for every MCU
+        let mut source = [0i16; 32];
+        if factor.0 != max_factor.0 {
+            for row in &mut component_block[..source_size.1] {
+                source[..source_size.0].copy_from_slice(&row[..source_size.0]);
+                Self::resample_line(
+                    &source[..source_size.0],
+                    row,
+                    factor.0,
+                    max_factor.0,
+                    method,
+                );
+            }
+        }
+        if factor.1 != max_factor.1 {
+            let mut column = [0i16; 32];
+            for x in 0..size.0 {
+                for (sample, row) in source.iter_mut().zip(&component_block[..source_size.1]) {
+                    *sample = row[x];
+                }
+                Self::resample_line(
+                    &source[..source_size.1],
+                    &mut column[..size.1],
+                    factor.1,
+                    max_factor.1,
+                    method,
+                );
+                for (row, sample) in component_block.iter_mut().zip(&column) {
+                    row[x] = *sample;
+                }
+            }
+        }
+    }
+
+    /// Stretches the top left `source_size` samples of a component by `max_factor / factor` to
+    /// `size`, first along the rows and then down the columns.
+    fn resample(
+        samples: &[Vec<i16>],
+        source_size: (usize, usize),
+        size: (usize, usize),
+        factor: (u8, u8),
+        max_factor: (u8, u8),
+        method: Upsampling,
+    ) -> Vec<Vec<i16>> {
+        let rows: Vec<Vec<i16>> = samples[..source_size.1]
+            .iter()
+            .map(|row| {
+                let mut stretched = vec![0; size.0];
+                Self::resample_line(
+                    &row[..source_size.0],
+                    &mut stretched,
+                    factor.0,
+                    max_factor.0,
+                    method,
+                );
+                stretched
+            })
+            .collect();
+
+        let mut result = vec![vec![0; size.0]; size.1];
+        let mut column = vec![0; source_size.1];
+        let mut stretched = vec![0; size.1];
+        for x in 0..size.0 {
+            for (sample, row) in column.iter_mut().zip(&rows) {
+                *sample = row[x];
+            }
+            Self::resample_line(&column, &mut stretched, factor.1, max_factor.1, method);
+            for (row, sample) in result.iter_mut().zip(&stretched) {
+                row[x] = *sample;
+            }
+        }
+        result
+    }
+
+    /// Fills `line` with `source` stretched by `max_factor / factor`. Nearest neighbor maps each
+    /// sample back to `dest * factor / max_factor`, so factors that don't evenly divide the
+    /// maximum, like 2 against 3, are still sampled correctly. The interpolating methods work from
+    /// sample centers, treating samples past either end as copies of the end samples.
+    fn resample_line(
+        source: &[i16],
+        line: &mut [i16],
+        factor: u8,
+        max_factor: u8,
+        method: Upsampling,
+    ) {
+        if factor == max_factor {
+            line.copy_from_slice(&source[..line.len()]);
+            return;
+        }
+
+        let last = source.len() as i64 - 1;
+        let at = |index: i64| source[index.clamp(0, last) as usize] as i64;
+        // Rounds to the nearest integer, with halves rounded up
+        let divide = |value: i64, divisor: i64| (value + divisor / 2).div_euclid(divisor);
+        let (factor, max_factor) = (factor as i64, max_factor as i64);
+
+        for (dest, sample) in line.iter_mut().enumerate() {
+            let dest = dest as i64;
+            // The center of the destination sample lies at `index + offset / scale` in source
+            // samples, kept as a fraction so the weights are exact
+            let scale = 2 * max_factor;
+            let position = (2 * dest + 1) * factor - max_factor;
+            let (index, offset) = (position.div_euclid(scale), position.rem_euclid(scale));
+
+            *sample = match method {
+                Upsampling::Nearest => at(dest * factor / max_factor),
+                Upsampling::Bilinear => {
+                    divide(at(index) * (scale - offset) + at(index + 1) * offset, scale)
+                }
+                Upsampling::CatmullRom => {
+                    // The Catmull-Rom weights for t = offset / scale, multiplied by 2 * scale^3
+                    let (t, s) = (offset, scale);
+                    let weights = [
+                        -t * t * t + 2 * t * t * s - t * s * s,
+                        3 * t * t * t - 5 * t * t * s + 2 * s * s * s,
+                        -3 * t * t * t + 4 * t * t * s + t * s * s,
+                        t * t * t - t * t * s,
+                    ];
+                    let sum: i64 = (index - 1..=index + 2)
+                        .zip(weights)
+                        .map(|(source_index, weight)| at(source_index) * weight)
+                        .sum();
+                    // The curve can overshoot at edges, so keep to the range the IDCT produces
+                    divide(sum, 2 * s * s * s).clamp(-128, 127)
+                }
+            } as i16;
+        }
+    }
+
+    /// Checks that every table the scan's components refer to is defined, so looking them up
+    /// while decoding can't fail.
+    fn check_tables(header: &HeaderInfo) -> Result<()> {
+        let tables = &header.tables;
+        for component in &header.components {
+            let qtable = tables.quant_tables.get(component.frame.qtable_id as usize);
+            if !matches!(qtable, Some(Some(_))) {
+                return Err(Error::Malformed(format!(
+                    "Component {} uses undefined quantization table {}",
+                    component.frame.identifier, component.frame.qtable_id
+                )));
+            }
+            let dc_table = tables.dc_huff_tables.get(component.scan.dc_table as usize);
+            let ac_table = tables.ac_huff_tables.get(component.scan.ac_table as usize);
+            if !matches!((dc_table, ac_table), (Some(Some(_)), Some(Some(_)))) {
+                return Err(Error::Malformed(format!(
+                    "Component {} uses an undefined Huffman table",
+                    component.frame.identifier
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks up the DC and AC Huffman tables used by a component.
+    fn component_huffman_tables<'header>(
+        header: &'header HeaderInfo,
+        component: &Component,
+    ) -> (&'header HuffmanTable, &'header HuffmanTable) {
+        let dc_table = header.tables.dc_huff_tables[component.scan.dc_table as usize]
+            .as_ref()
+            .unwrap();
+        let ac_table = header.tables.ac_huff_tables[component.scan.ac_table as usize]
+            .as_ref()
+            .unwrap();
+
+        (dc_table, ac_table)
+    }
+
+    /// Looks up the quantization table used by a component.
+    fn component_qtable(header: &HeaderInfo, component: &Component) -> [[u16; 8]; 8] {
+        header.tables.quant_tables[component.frame.qtable_id as usize]
+            .as_ref()
+            .unwrap()
+            .table
+    }
+
+    /// Dequantizes a block of zigzag ordered coefficients, returning them in natural
+    /// (row, column) order. 16 bit tables can hold values past `i16::MAX`, so the product is
+    /// taken in 32 bits and saturated.
+    fn dequantize(coefficients: &[i16; 64], qtable: &[[u16; 8]; 8]) -> [[i16; 8]; 8] {
+        let mut dequantized = [[0i16; 8]; 8];
+        for (i, coefficient) in coefficients.iter().enumerate() {
+            let (row, col) = ZIGZAG_MAP[i];
+            let value = *coefficient as i32 * qtable[row as usize][col as usize] as i32;
+            dequantized[row as usize][col as usize] =
+                value.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+        }
+        dequantized
+    }
+
+    /// Entropy decodes a single 8x8 data unit and returns its quantized coefficients in zigzag
+    /// order.
+    fn decode_data_unit(
+        &mut self,
+        bitstream: &mut Bitstream,
+        component_index: usize,
+        dc_table: &HuffmanTable,
+        ac_table: &HuffmanTable,
+    ) -> Result<[i16; 64]> {
+        let mut dct_coefficients = [0i16; 64];
+
+        // Calculate DC coefficient
+        // https://www.w3.org/Graphics/JPEG/itu-t81.pdf
+        // F.2.2.1 Page 104
+        // DECODE
+        let Some((dc_code, _)) = self.decode_next_value(bitstream, dc_table)? else {
+            // Keep the predicted DC and end the block as if it were empty
+            self.invalid_code()?;
+            dct_coefficients[0] = self.dc_predictions[component_index];
+            return Ok(dct_coefficients);
+        };
+        if dc_code > 11 {
+            return Err(Error::Malformed(
+                "DC difference is longer than 11 bits".into(),
+            ));
+        }
+        let diff = bitstream.read_bits(dc_code as usize)?; // RECEIVE
+        let diff = Self::extend(diff, dc_code) as i16;
+
+        // Corrupt data can push the prediction out of range, which shouldn't panic
+        let dc_coefficient = self.dc_predictions[component_index].wrapping_add(diff);
+
+        self.dc_predictions[component_index] = dc_coefficient;
+
+        dct_coefficients[0] = dc_coefficient;
+
+        // Calculate AC coefficients
+        // https://www.w3.org/Graphics/JPEG/itu-t81.pdf
+        // F.13 Page 106
 
-            // Stretch subsampled components to the correct size
-            let horiz_ratio =
-                header.mcu_info.max_xy_sampling_factor.0 / component.frame.xy_sampling_factor.0;
-            let vert_ratio =
-                header.mcu_info.max_xy_sampling_factor.1 / component.frame.xy_sampling_factor.1;
-
-            if horiz_ratio > 1 || vert_ratio > 1 {
-                let mut stretched_block = component_block.clone();
-                for y in 0..(8 * header.mcu_info.max_xy_sampling_factor.1) {
-                    for x in 0..(8 * header.mcu_info.max_xy_sampling_factor.0) {
-                        let source_y = y as usize / vert_ratio as usize;
-                        let source_x = x as usize / horiz_ratio as usize;
-
-                        stretched_block[y as usize][x as usize] =
-                            component_block[source_y][source_x];
+        let mut k = 0;
+        while k != 63 {
+            k += 1;
+
+            let Some((huffman_val, _)) = self.decode_next_value(bitstream, ac_table)? else {
+                // End the block here, as if the code were an EOB
+                self.invalid_code()?;
+                break;
+            };
+
+            match AcSymbol::parse(huffman_val)? {
+                AcSymbol::Eob => {
+                    break;
+                }
+                AcSymbol::Zrl => {
+                    k += 15; // Skip 15+1(top of loop) zeroes.
+                    continue;
+                }
+                AcSymbol::Coefficient { run, size } => {
+                    k += run;
+
+                    if k > 63 {
+                        return Err(Error::Malformed("Run length exceeds max K of 63".into()));
                     }
+
+                    let value = bitstream.read_bits(size as usize)?;
+
+                    dct_coefficients[k as usize] = Self::extend(value, size) as i16;
                 }
-                *component_block = stretched_block;
             }
         }
-        Ok(block)
+
+        Ok(dct_coefficients)
+    }
+
+    /// EXTEND from F.2.2.1 Page 105. Converts the `category` bits received for a value to the
+    /// signed value, where a leading 0 bit means it's negative. Baseline scans never go past
+    /// category 11, which the callers check, but this covers the full range up to 15 used by
+    /// extended 12-bit and lossless scans. That's done in 32 bits since the shifts overflow i16.
+    fn extend(value: u64, category: u8) -> i32 {
+        let value = value as i32;
+        if category != 0 && value < 1 << (category - 1) {
+            value - (1 << category) + 1
+        } else {
+            value
+        }
     }
 
+    /// Decodes the next Huffman coded symbol, returning `None` if no code of up to 16 bits matches.
     fn decode_next_value(
         &mut self,
         bitstream: &mut Bitstream,
         table: &HuffmanTable,
-    ) -> Result<(u8, u8)> {
+    ) -> Result<Option<(u8, u8)>> {
         let mut code: i32 = 0;
         let mut code_cursor: usize = 0;
 
@@ -279,66 +1245,395 @@ impl<'data> JPEGDecoder<'data> {
             code = (code << 1) | bit;
             for _ in 0..table.bitcode_counts[i as usize] {
                 if code == table.codes[code_cursor] as i32 {
-                    return Ok((table.symbols[code_cursor], i));
+                    return Ok(Some((table.symbols[code_cursor], i)));
                 }
                 code_cursor += 1;
             }
         }
 
-        Err(Error::UnsupportedFeature(
-            "JPEG has code longer than the 16 bit maximum for baseline JPEGs.",
+        Ok(None)
+    }
+
+    /// Handles a Huffman code that matched no symbol within 16 bits. In strict mode this fails the
+    /// decode. Otherwise the rest of the block is taken to be zero, as libjpeg does, so one
+    /// corrupt block doesn't end the scan. The 16 bits are consumed, and the decoder resyncs at
+    /// the next restart marker if it doesn't sooner.
+    fn invalid_code(&self) -> Result<()> {
+        if self.options.strict {
+            return Err(Error::UnsupportedFeature(
+                "JPEG has code longer than the 16 bit maximum for baseline JPEGs.".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Reads the next byte of the entropy coded data. Outside strict mode running out of data is
+    /// treated like an EOI marker, returning `None`.
+    fn read_entropy_coded_byte(&mut self) -> Result<Option<u8>> {
+        match self.reader.read_next_byte() {
+            Ok(byte) => Ok(Some(byte)),
+            Err(Error::UnexpectedEof) if !self.options.strict => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+/// Reconstructs the MCUs of a decoded scan in raster order, yielding each with the pixel
+/// coordinates of its top left corner.
+pub struct McuIter<'data> {
+    decoder: JPEGDecoder<'data>,
+    header: HeaderInfo,
+    planes: Vec<CoefficientPlane>,
+    next_mcu: usize,
+}
+
+impl Iterator for McuIter<'_> {
+    type Item = ((u32, u32), Macroblock);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (mcus_x, mcus_y) = self.header.mcu_info.mcu_padded_dimensions;
+        if self.next_mcu >= mcus_x as usize * mcus_y as usize {
+            return None;
+        }
+
+        let mcu = (
+            self.next_mcu % mcus_x as usize,
+            self.next_mcu / mcus_x as usize,
+        );
+        self.next_mcu += 1;
+
+        let mut block = Macroblock::new(self.header.mcu_info.max_xy_sampling_factor);
+        self.decoder
+            .reconstruct_block(&mut block, &self.planes, &self.header, mcu, false);
+        let (mcu_width, mcu_height) = self.header.mcu_info.mcu_size;
+        Some((
+            (
+                mcu.0 as u32 * mcu_width as u32,
+                mcu.1 as u32 * mcu_height as u32,
+            ),
+            block,
         ))
     }
 
-    fn read_huffman_data(&mut self) -> Result<Vec<u8>> {
-        let mut huffman_data: Vec<u8> = vec![];
-        let mut current_byte = self.reader.read_next_byte()?;
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (mcus_x, mcus_y) = self.header.mcu_info.mcu_padded_dimensions;
+        let remaining = mcus_x as usize * mcus_y as usize - self.next_mcu;
+        (remaining, Some(remaining))
+    }
+}
 
-        loop {
-            let last_byte = current_byte;
-            current_byte = self.reader.read_next_byte()?;
+impl ExactSizeIterator for McuIter<'_> {}
 
-            if last_byte == 0xFF {
-                if current_byte == 0x00 {
-                    current_byte = self.reader.read_next_byte()?;
-                    huffman_data.push(last_byte);
-                    continue;
-                }
+/// Decodes a scan one MCU row at a time, yielding each row as a bitmap as wide as the image and
+/// one MCU tall, or shorter for the last row. Stops after the first error.
+pub struct RowIter<'data> {
+    decoder: JPEGDecoder<'data>,
+    header: HeaderInfo,
+    bitstream: Bitstream<'data>,
+    /// The coefficients of the current MCU row, reused for every row
+    planes: Vec<CoefficientPlane>,
+    /// The reconstructed MCUs of the current row, reused for every row
+    blocks: Vec<Macroblock>,
+    next_row: u16,
+    /// Cleared once a lenient decode breaks off, after which the remaining rows are left gray
+    complete: bool,
+}
 
-                let marker_data = 0xFF00 | current_byte as u16;
-                let marker = JPEGParser::to_marker(marker_data)?;
+impl RowIter<'_> {
+    fn decode_row(&mut self) -> Result<Bitmap> {
+        let mcu_y = self.next_row as usize;
+        let header = &self.header;
+        for plane in &mut self.planes {
+            plane.clear();
+        }
+        if self.complete {
+            self.complete = self.decoder.decode_mcu_row(
+                &mut self.bitstream,
+                header,
+                mcu_y,
+                &mut self.planes,
+                0,
+            )?;
+        }
+        if mcu_y + 1 == header.mcu_info.mcu_padded_dimensions.1 as usize {
+            self.decoder.finish_scan(&mut self.bitstream)?;
+        }
 
-                if marker == JPEGMarker::EOI {
-                    return Ok(huffman_data);
-                }
-            } else {
-                huffman_data.push(last_byte);
-            }
+        for (horiz, block) in self.blocks.iter_mut().enumerate() {
+            self.decoder
+                .reconstruct_block(block, &self.planes, header, (horiz, 0), false);
         }
+
+        let (width, height) = header.frame_info.image_size;
+        let mcu_height = header.mcu_info.mcu_size.1 as u32;
+        let rows = (height as u32 - mcu_y as u32 * mcu_height).min(mcu_height) as u16;
+        JPEGDecoder::blocks_to_bitmap(
+            core::slice::from_mut(&mut self.blocks),
+            header,
+            (width, rows),
+            self.decoder.options.channel_order,
+        )
     }
 }
 
+impl Iterator for RowIter<'_> {
+    type Item = Result<Bitmap>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Padding MCU rows below the image hold no pixels
+        let mcu_height = self.header.mcu_info.mcu_size.1 as u32;
+        if self.next_row as u32 * mcu_height >= self.header.frame_info.image_size.1 as u32 {
+            return None;
+        }
+
+        let row = self.decode_row();
+        self.next_row = if row.is_ok() {
+            self.next_row + 1
+        } else {
+            u16::MAX
+        };
+        Some(row)
+    }
+}
+
+/// The reconstructed samples of one MCU. Every component is stretched to the full MCU size and
+/// stored as `[row][column]`, level shifted so samples range from -128 to 127.
 #[derive(Debug, Clone)]
-struct Macroblock {
+pub struct Macroblock {
     y: Vec<Vec<i16>>,
     cb: Vec<Vec<i16>>,
     cr: Vec<Vec<i16>>,
 }
 
 impl Macroblock {
-    pub fn new(block_sample_size: (u8, u8)) -> Self {
+    pub(crate) fn new(block_sample_size: (u8, u8)) -> Self {
         Self {
             y: vec![vec![0; 8 * block_sample_size.0 as usize]; 8 * block_sample_size.1 as usize],
             cb: vec![vec![0; 8 * block_sample_size.0 as usize]; 8 * block_sample_size.1 as usize],
             cr: vec![vec![0; 8 * block_sample_size.0 as usize]; 8 * block_sample_size.1 as usize],
         }
     }
-    pub fn get_component(&mut self, selector: u8) -> &mut Vec<Vec<i16>> {
-        match selector {
-            1 => &mut self.y,
-            2 => &mut self.cb,
-            3 => &mut self.cr,
-            _ => panic!("Invalid component selector"),
+    /// The width and height of each component, in samples
+    fn size(&self) -> (usize, usize) {
+        (self.y[0].len(), self.y.len())
+    }
+
+    pub(crate) fn get_component(&mut self, index: usize) -> &mut Vec<Vec<i16>> {
+        match index {
+            0 => &mut self.y,
+            1 => &mut self.cb,
+            2 => &mut self.cr,
+            _ => panic!("Invalid component index"),
+        }
+    }
+
+    /// Returns the samples of the component at `index` in the frame header, 0 for Y, 1 for Cb,
+    /// and 2 for Cr, or `None` for any other index. Components are told apart by position, not
+    /// by their identifiers, which encoders choose freely.
+    pub fn component(&self, index: usize) -> Option<&[Vec<i16>]> {
+        match index {
+            0 => Some(&self.y),
+            1 => Some(&self.cb),
+            2 => Some(&self.cr),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn dequantize_wide_values() {
+    let mut coefficients = [0i16; 64];
+    coefficients[0] = 2;
+    coefficients[1] = -3;
+    coefficients[2] = 1;
+    let mut qtable = [[1u16; 8]; 8];
+    qtable[0][0] = 40000;
+    qtable[0][1] = 12000;
+    qtable[1][0] = 65535;
+
+    let dequantized = JPEGDecoder::dequantize(&coefficients, &qtable);
+    assert_eq!(dequantized[0][0], i16::MAX);
+    assert_eq!(dequantized[0][1], i16::MIN);
+    assert_eq!(dequantized[1][0], i16::MAX);
+}
+
+#[test]
+fn extend_categories() {
+    assert_eq!(JPEGDecoder::extend(0, 0), 0);
+    assert_eq!(JPEGDecoder::extend(0, 1), -1);
+    assert_eq!(JPEGDecoder::extend(1, 1), 1);
+    assert_eq!(JPEGDecoder::extend(0b010, 3), -5);
+    assert_eq!(JPEGDecoder::extend(0b110, 3), 6);
+
+    // Category 15 covers -32767 to -16384 and 16384 to 32767
+    assert_eq!(JPEGDecoder::extend(0, 15), -32767);
+    assert_eq!(JPEGDecoder::extend(0x3FFF, 15), -16384);
+    assert_eq!(JPEGDecoder::extend(0x4000, 15), 16384);
+    assert_eq!(JPEGDecoder::extend(0x7FFF, 15), 32767);
+}
+
+#[test]
+fn parse_ac_symbols() {
+    assert_eq!(AcSymbol::parse(0x00).unwrap(), AcSymbol::Eob);
+    assert_eq!(AcSymbol::parse(0xF0).unwrap(), AcSymbol::Zrl);
+    assert_eq!(
+        AcSymbol::parse(0x01).unwrap(),
+        AcSymbol::Coefficient { run: 0, size: 1 }
+    );
+    assert_eq!(
+        AcSymbol::parse(0xFA).unwrap(),
+        AcSymbol::Coefficient { run: 15, size: 10 }
+    );
+
+    // Zero sized values other than EOB and ZRL are progressive EOBn codes, and sizes above 10
+    // don't exist for 8 bit samples
+    for value in [0x10, 0xE0, 0x0B, 0x3F] {
+        assert!(matches!(
+            AcSymbol::parse(value),
+            Err(Error::Malformed(message)) if message == "Invalid AC coefficient size"
+        ));
+    }
+}
+
+#[test]
+fn upsample_uneven_factors() {
+    // A 3x1 MCU, so 24x8 samples, with a component sampled at 1x1 and one at 2x1
+    let one_to_three: Vec<Vec<i16>> = (0..8)
+        .map(|y| {
+            (0..24)
+                .map(|x| if x < 8 { y * 8 + x } else { -1 })
+                .collect()
+        })
+        .collect();
+    let mut block = one_to_three.clone();
+    JPEGDecoder::upsample(&mut block, (1, 1), (3, 1), Upsampling::Nearest);
+    for (y, row) in block.iter().enumerate() {
+        for (x, sample) in row.iter().enumerate() {
+            assert_eq!(*sample, one_to_three[y][x / 3]);
+        }
+    }
+
+    let two_to_three: Vec<Vec<i16>> = (0..8)
+        .map(|y| {
+            (0..24)
+                .map(|x| if x < 16 { y * 16 + x } else { -1 })
+                .collect()
+        })
+        .collect();
+    let mut block = two_to_three.clone();
+    JPEGDecoder::upsample(&mut block, (2, 1), (3, 1), Upsampling::Nearest);
+    for (y, row) in block.iter().enumerate() {
+        for (x, sample) in row.iter().enumerate() {
+            assert_eq!(*sample, two_to_three[y][x * 2 / 3]);
         }
+        assert_eq!(row[23], two_to_three[y][15]);
     }
 }
+
+#[test]
+fn upsample_sharp_edge() {
+    // A 2x1 MCU with a 1x1 chroma component that steps from -100 to 100 halfway across
+    let edge: Vec<Vec<i16>> = (0..8)
+        .map(|_| {
+            (0..16)
+                .map(|x| match x {
+                    0..4 => -100,
+                    4..8 => 100,
+                    _ => 0,
+                })
+                .collect()
+        })
+        .collect();
+    let upsample = |method| {
+        let mut block = edge.clone();
+        JPEGDecoder::upsample(&mut block, (1, 2), (2, 2), method);
+        block
+    };
+    let steepest_step = |row: &[i16]| {
+        row.windows(2)
+            .map(|pair| (pair[1] - pair[0]).abs())
+            .max()
+            .unwrap()
+    };
+
+    let nearest = upsample(Upsampling::Nearest);
+    let bilinear = upsample(Upsampling::Bilinear);
+    let catmull_rom = upsample(Upsampling::CatmullRom);
+    for block in [&nearest, &bilinear, &catmull_rom] {
+        // Every row is the same, and the flat areas away from the edge stay flat
+        assert!(block.iter().all(|row| row == &block[0]));
+        assert_eq!((block[0][0], block[0][15]), (-100, 100));
+    }
+
+    assert_eq!(&nearest[0][6..10], &[-100, -100, 100, 100]);
+    assert_eq!(steepest_step(&nearest[0]), 200);
+    // Bilinear weights the two nearest samples 3:1
+    assert_eq!(&bilinear[0][6..10], &[-100, -50, 50, 100]);
+    assert_eq!(steepest_step(&bilinear[0]), 100);
+    // Catmull-Rom ramps across the same samples, but stays steeper and rings a little either side
+    assert_eq!(&catmull_rom[0][5..11], &[-105, -114, -59, 59, 114, 105]);
+    let step = steepest_step(&catmull_rom[0]);
+    assert!(step > 100 && step < 200, "{}", step);
+}
+
+#[test]
+fn upsample_in_place_matches_resample() {
+    // Upsampling an MCU works in place on stack buffers, while planes go through `resample`. Both
+    // must give the same samples for every factor and method.
+    let methods = [
+        Upsampling::Nearest,
+        Upsampling::Bilinear,
+        Upsampling::CatmullRom,
+    ];
+    for max_factor in [(2, 2), (3, 1), (4, 4), (1, 4)] {
+        for factor in [(1, 1), (2, 1), (1, 2), (2, 2)] {
+            if factor.0 > max_factor.0 || factor.1 > max_factor.1 {
+                continue;
+            }
+            let size = (8 * max_factor.0 as usize, 8 * max_factor.1 as usize);
+            let source_size = (8 * factor.0 as usize, 8 * factor.1 as usize);
+            // A pattern with sharp and gradual changes, so every method has something to do
+            let block: Vec<Vec<i16>> = (0..size.1)
+                .map(|y| {
+                    (0..size.0)
+                        .map(|x| ((x * 37 + y * 91) % 256) as i16 - 128)
+                        .collect()
+                })
+                .collect();
+
+            for method in methods {
+                let mut upsampled = block.clone();
+                JPEGDecoder::upsample(&mut upsampled, factor, max_factor, method);
+                let resampled =
+                    JPEGDecoder::resample(&block, source_size, size, factor, max_factor, method);
+                assert_eq!(
+                    upsampled, resampled,
+                    "{:?} {:?} {:?}",
+                    factor, max_factor, method
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn reject_undefined_tables() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let tables = Tables::default();
+    let options = DecodeOptions::default();
+    let decode = |edit: &dyn Fn(&mut HeaderInfo)| {
+        let mut decoder = JPEGDecoder::new(image_data, &options, &tables);
+        let mut header = decoder.parse().unwrap();
+        edit(&mut header);
+        decoder.read_scan(&header)
+    };
+
+    assert!(matches!(
+        decode(&|header| header.components[1].frame.qtable_id = 3),
+        Err(Error::Malformed(message)) if message == "Component 2 uses undefined quantization table 3"
+    ));
+    assert!(matches!(
+        decode(&|header| header.components[0].scan.ac_table = 2),
+        Err(Error::Malformed(message)) if message.ends_with("undefined Huffman table")
+    ));
+}
@@ -1,11 +1,11 @@
-use byteorder::{BigEndian, ReadBytesExt};
+use core::{cmp::min, fmt};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
-use std::io::{Cursor, Seek, SeekFrom};
 
 use crate::error::{Error, Result};
 
-#[allow(non_camel_case_types)]
+/// A JPEG marker, named as in the specification. `RESERVEDn` are the JPGn extension markers.
+#[allow(non_camel_case_types, clippy::upper_case_acronyms, missing_docs)]
 #[derive(PartialEq, PartialOrd, FromPrimitive, Debug, Clone, Copy)]
 pub enum JPEGMarker {
     APP0 = 0xFFE0,
@@ -25,19 +25,21 @@ pub enum JPEGMarker {
     APP14 = 0xFFEE,
     APP15 = 0xFFEF,
 
-    RESERVED1 = 0xFF1,
-    RESERVED2 = 0xFF2,
-    RESERVED3 = 0xFF3,
-    RESERVED4 = 0xFF4,
-    RESERVED5 = 0xFF5,
-    RESERVED6 = 0xFF6,
-    RESERVED7 = 0xFF7,
-    RESERVED8 = 0xFF8,
-    RESERVED9 = 0xFF9,
-    RESERVED10 = 0xFFA,
-    RESERVED11 = 0xFFB,
-    RESERVED12 = 0xFFC,
-    RESERVED13 = 0xFFD,
+    // JPGn, reserved for JPEG extensions
+    RESERVED0 = 0xFFF0,
+    RESERVED1 = 0xFFF1,
+    RESERVED2 = 0xFFF2,
+    RESERVED3 = 0xFFF3,
+    RESERVED4 = 0xFFF4,
+    RESERVED5 = 0xFFF5,
+    RESERVED6 = 0xFFF6,
+    RESERVED7 = 0xFFF7,
+    RESERVED8 = 0xFFF8,
+    RESERVED9 = 0xFFF9,
+    RESERVED10 = 0xFFFA,
+    RESERVED11 = 0xFFFB,
+    RESERVED12 = 0xFFFC,
+    RESERVED13 = 0xFFFD,
 
     RST0 = 0xFFD0,
     RST1 = 0xFFD1,
@@ -51,31 +53,64 @@ pub enum JPEGMarker {
     DHP = 0xFFDE,
     EXP = 0xFFDF,
 
+    DAC = 0xFFCC, // Arithmetic coding conditioning, recognized only so it can be rejected clearly
     DHT = 0xFFC4,
     DQT = 0xFFDB,
     EOI = 0xFFD9,
-    RST = 0xFFDD,
+    DRI = 0xFFDD,
     SOF0 = 0xFFC0, // Only support baseline DCT for now, I may add progressive later.
+    SOF3 = 0xFFC3, // Lossless, recognized only so it can be rejected clearly
     SOI = 0xFFD8,
     SOS = 0xFFDA,
     COM = 0xFFFE,
 }
 
+impl fmt::Display for JPEGMarker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let value = *self as u16;
+        if *self >= JPEGMarker::APP0 && *self <= JPEGMarker::APP15 {
+            return write!(f, "APP{}", value - JPEGMarker::APP0 as u16);
+        }
+        if *self >= JPEGMarker::RST0 && *self <= JPEGMarker::RST7 {
+            return write!(f, "RST{}", value - JPEGMarker::RST0 as u16);
+        }
+        if *self >= JPEGMarker::RESERVED0 && *self <= JPEGMarker::RESERVED13 {
+            return write!(f, "JPG{}", value - JPEGMarker::RESERVED0 as u16);
+        }
+
+        let name = match self {
+            JPEGMarker::SOF0 => "SOF0 (baseline DCT)",
+            JPEGMarker::SOF3 => "SOF3 (lossless)",
+            JPEGMarker::DHT => "DHT",
+            JPEGMarker::DQT => "DQT",
+            JPEGMarker::DAC => "DAC",
+            JPEGMarker::DRI => "DRI",
+            JPEGMarker::DHP => "DHP",
+            JPEGMarker::EXP => "EXP",
+            JPEGMarker::SOI => "SOI",
+            JPEGMarker::SOS => "SOS",
+            JPEGMarker::EOI => "EOI",
+            JPEGMarker::COM => "COM",
+            _ => unreachable!("Ranged markers are handled above"),
+        };
+        f.write_str(name)
+    }
+}
+
 pub struct JPEGParser<'data> {
-    cursor: Cursor<&'data [u8]>,
+    data: &'data [u8],
+    position: usize,
 }
 
 impl<'data> JPEGParser<'data> {
     pub fn new(data: &'data [u8]) -> Self {
-        Self {
-            cursor: Cursor::new(data),
-        }
+        Self { data, position: 0 }
     }
 
     pub fn to_marker(word: u16) -> Result<JPEGMarker> {
         if let Some(marker) = FromPrimitive::from_u16(word) {
             // Unfortunately these can't be matched as ranges even though they're PartialOrd, only numbers support ranges.
-            if marker >= JPEGMarker::RESERVED1 && marker <= JPEGMarker::RESERVED13 {
+            if marker >= JPEGMarker::RESERVED0 && marker <= JPEGMarker::RESERVED13 {
                 return Ok(marker);
             }
             if marker >= JPEGMarker::RST0 && marker <= JPEGMarker::RST7 {
@@ -89,78 +124,147 @@ impl<'data> JPEGParser<'data> {
                 | JPEGMarker::DHP
                 | JPEGMarker::EXP
                 | JPEGMarker::EOI
+                | JPEGMarker::DAC
                 | JPEGMarker::DHT
                 | JPEGMarker::DQT
-                | JPEGMarker::RST
+                | JPEGMarker::DRI
                 | JPEGMarker::SOF0 // Only support baseline DCT for now
+                | JPEGMarker::SOF3
                 | JPEGMarker::SOI
                 | JPEGMarker::SOS => return Ok(marker),
                 _ => {}
             };
 
-            return Err(Error::Malformed(
-                "Marker not supported. Newly added marker may need to be implemented.",
-            ));
+            Err(Error::Malformed(
+                "Marker not supported. Newly added marker may need to be implemented.".into(),
+            ))
         } else {
-            return Err(Error::Malformed("Marker not supported"));
+            Err(Error::Malformed("Marker not supported".into()))
         }
     }
 
     pub fn read_next_word(&mut self) -> Result<u16> {
-        match self.cursor.read_u16::<BigEndian>() {
-            Ok(val) => return Ok(val),
-            Err(_) => return Err(Error::Malformed("Unexpected end of input")),
+        match self.data.get(self.position..self.position + 2) {
+            Some(bytes) => {
+                self.position += 2;
+                Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+            }
+            None => Err(Error::UnexpectedEof),
         }
     }
 
     pub fn read_next_byte(&mut self) -> Result<u8> {
-        match self.cursor.read_u8() {
-            Ok(val) => return Ok(val),
-            Err(_) => return Err(Error::Malformed("Unexpected end of input")),
+        match self.data.get(self.position) {
+            Some(byte) => {
+                self.position += 1;
+                Ok(*byte)
+            }
+            None => Err(Error::UnexpectedEof),
         }
     }
 
     pub fn read_next_marker(&mut self) -> Result<JPEGMarker> {
-        let word = self.read_next_word()?;
-        match Self::to_marker(word) {
-            Err(_) => {
-                // Try to find another valid marker later in the stream
-                if word != 0xFFFF {
-                    return Err(Error::Malformed("Invalid JPEG file"));
-                }
-
-                loop {
-                    let next = self.read_next_byte()?;
-                    if next == 0x00 {
-                        return Err(Error::Malformed("Invalid JPEG file"));
-                    }
-
-                    if word == 0xFF {
-                        let next = 0xFF00 | next as u16;
-                        let result = Self::to_marker(next);
-                        if let Ok(marker) = result {
-                            return Ok(marker);
-                        } else if let Err(msg) = result {
-                            return Err(msg);
-                        }
-                    }
-                }
-            }
-            Ok(marker) => return Ok(marker),
-        };
+        if self.read_next_byte()? != 0xFF {
+            return Err(Error::Malformed("Invalid JPEG file".into()));
+        }
+
+        // Any number of 0xFF fill bytes can come before the marker's identifier
+        let mut identifier = self.read_next_byte()?;
+        while identifier == 0xFF {
+            identifier = self.read_next_byte()?;
+        }
+        if identifier == 0x00 {
+            return Err(Error::Malformed("Invalid JPEG file".into()));
+        }
+
+        Self::to_marker(0xFF00 | identifier as u16)
+    }
+
+    /// Reads the length field at the start of a marker segment and returns the number of bytes
+    /// that follow it.
+    pub fn read_segment_length(&mut self) -> Result<u16> {
+        match self.read_next_word()?.checked_sub(2) {
+            Some(length) => Ok(length),
+            None => Err(Error::Malformed(
+                "JPEG marker length is shorter than the length field itself".into(),
+            )),
+        }
     }
 
     pub fn skip_marker_with_length(&mut self) -> Result<()> {
-        let byte_length = self.read_next_word()? - 2;
-        if let Ok(_) = self.cursor.seek(SeekFrom::Current(byte_length as i64)) {
-            Ok(())
-        } else {
-            Err(Error::Malformed("JPEG marker with length contained a length longer than the remaining size of the JPEG file"))
+        self.read_segment().map(|_| ())
+    }
+
+    /// Reads the length of a marker segment and returns its payload, leaving the cursor at the
+    /// next marker.
+    pub fn read_segment(&mut self) -> Result<&'data [u8]> {
+        let byte_length = self.read_segment_length()?;
+        if byte_length == 0 {
+            // A segment with an empty payload, the next marker follows the length straight away
+            return Ok(&[]);
+        }
+        match self
+            .data
+            .get(self.position..self.position + byte_length as usize)
+        {
+            Some(payload) => {
+                self.position += byte_length as usize;
+                Ok(payload)
+            }
+            None => Err(Error::Malformed("JPEG marker with length contained a length longer than the remaining size of the JPEG file".into())),
+        }
+    }
+
+    /// Skips entropy coded data, including stuffed bytes and RSTn markers, leaving the cursor on
+    /// the next marker or at the end of the data.
+    pub fn skip_entropy_coded_data(&mut self) {
+        while let Some(offset) = self.data[self.position..]
+            .iter()
+            .position(|&byte| byte == 0xFF)
+        {
+            self.position += offset;
+            match self.data.get(self.position + 1) {
+                Some(0x00 | 0xD0..=0xD7) => self.position += 2,
+                _ => return,
+            }
+        }
+        self.position = self.data.len();
+    }
+
+    /// Moves the cursor to the first SOI marker found within `max_skip` bytes of the current
+    /// position, leaving it ready to read the marker.
+    pub fn seek_start_of_image(&mut self, max_skip: usize) -> Result<()> {
+        let start = self.position;
+        let end = min(start.saturating_add(max_skip + 2), self.data.len());
+
+        let offset = self
+            .data
+            .get(start..end)
+            .and_then(|window| window.windows(2).position(|bytes| bytes == [0xFF, 0xD8]));
+
+        match offset {
+            Some(offset) => {
+                self.position = start + offset;
+                Ok(())
+            }
+            None => Err(Error::Malformed(
+                "This JPEG image does not have an SOI marker".into(),
+            )),
         }
     }
 
-    pub fn position(&self) -> u64 {
-        self.cursor.position()
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the data after the cursor
+    pub fn remaining(&self) -> &'data [u8] {
+        &self.data[self.position..]
+    }
+
+    /// Moves the cursor forward by `length` bytes, stopping at the end of the data
+    pub fn skip(&mut self, length: usize) {
+        self.position = min(self.position.saturating_add(length), self.data.len());
     }
 }
 
@@ -197,6 +301,80 @@ fn read_markers() {
     let mut reader = JPEGParser::new(&TEST_HEADER);
     assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::SOI);
     assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::SOF0);
-    assert!(!reader.skip_marker_with_length().is_err());
+    assert!(reader.skip_marker_with_length().is_ok());
+    assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::COM);
+}
+
+#[test]
+fn read_past_end() {
+    let mut reader = JPEGParser::new(&[0xFF, 0xD8, 0xFF]);
+    assert_eq!(reader.read_next_word().unwrap(), 0xFFD8);
+    assert!(matches!(reader.read_next_word(), Err(Error::UnexpectedEof)));
+
+    let mut reader = JPEGParser::new(&[]);
+    assert!(matches!(reader.read_next_byte(), Err(Error::UnexpectedEof)));
+}
+
+#[test]
+fn seek_start_of_image() {
+    let data = [0x00, 0xFF, 0x00, 0xFF, 0xD8, 0xFF, 0xD9];
+
+    let mut reader = JPEGParser::new(&data);
+    assert!(reader.seek_start_of_image(2).is_err());
+
+    reader.seek_start_of_image(3).unwrap();
+    assert_eq!(reader.position(), 3);
+    assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::SOI);
+}
+
+#[test]
+fn read_marker_after_fill_bytes() {
+    // A comment segment preceded by 0xFFFF padding
+    let data = [0xFF, 0xD8, 0xFF, 0xFF, 0xFF, 0xFE, 0, 3, 65, 0xFF, 0xD9];
+
+    let mut reader = JPEGParser::new(&data);
+    assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::SOI);
     assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::COM);
+    reader.skip_marker_with_length().unwrap();
+    assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::EOI);
+}
+
+#[test]
+fn read_reserved_markers() {
+    for identifier in 0xF0..=0xFD {
+        let data = [0xFF, identifier, 0, 4, 1, 2, 0xFF, 0xD9];
+        let mut reader = JPEGParser::new(&data);
+        assert!(reader.read_next_marker().is_ok(), "0xFF{:X}", identifier);
+        reader.skip_marker_with_length().unwrap();
+        assert_eq!(reader.read_next_marker().unwrap(), JPEGMarker::EOI);
+    }
+    assert_eq!(
+        JPEGParser::to_marker(0xFFF1).unwrap(),
+        JPEGMarker::RESERVED1
+    );
+}
+
+#[test]
+fn marker_names() {
+    use alloc::string::ToString;
+
+    assert_eq!(JPEGMarker::SOI.to_string(), "SOI");
+    assert_eq!(JPEGMarker::SOF0.to_string(), "SOF0 (baseline DCT)");
+    assert_eq!(JPEGMarker::DHT.to_string(), "DHT");
+    assert_eq!(JPEGMarker::APP0.to_string(), "APP0");
+    assert_eq!(JPEGMarker::APP14.to_string(), "APP14");
+    assert_eq!(JPEGMarker::RST7.to_string(), "RST7");
+    assert_eq!(JPEGMarker::RESERVED13.to_string(), "JPG13");
+}
+
+#[test]
+fn skip_entropy_coded_data() {
+    let data = [0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD3, 0x56, 0xFF, 0xD9];
+    let mut reader = JPEGParser::new(&data);
+    reader.skip_entropy_coded_data();
+    assert_eq!(reader.position(), 7);
+
+    let mut reader = JPEGParser::new(&data[..6]);
+    reader.skip_entropy_coded_data();
+    assert_eq!(reader.position(), 6);
 }
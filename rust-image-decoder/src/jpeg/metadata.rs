@@ -0,0 +1,174 @@
+use alloc::vec::Vec;
+
+use super::{standard_tables::LUMA_QUANTIZATION, tiff::TiffReader};
+
+/// The signature at the start of an APP0 segment holding a JFIF header
+const JFIF_SIGNATURE: &[u8] = b"JFIF\0";
+/// The signature at the start of an APP1 segment holding EXIF data
+const EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+/// The signature at the start of each APP2 segment holding part of an ICC profile
+const ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+
+/// The EXIF tag holding the orientation of the image
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Information about a JPEG image that isn't part of its pixels, gathered while parsing the
+/// header. Returned by `JPEGDecoder::decode_with_metadata`.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JPEGMetadata {
+    /// The pixel density from the JFIF header, if the image has one
+    pub density: Option<Density>,
+    /// The EXIF orientation, from 1 to 8, if the image has one. 1 means the pixels are stored
+    /// upright.
+    pub orientation: Option<u16>,
+    /// Whether the image embeds an ICC color profile
+    pub has_icc_profile: bool,
+    /// The contents of every COM segment before the first scan
+    pub comments: Vec<Vec<u8>>,
+    /// The quality, from 1 to 100, that the luma quantization table most likely came from,
+    /// assuming it's a scaled version of the Annex K table like libjpeg and most encoders write
+    pub quality: Option<u8>,
+}
+
+/// The pixel density given by a JFIF header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Density {
+    /// What the densities are measured in
+    pub unit: DensityUnit,
+    /// Horizontal pixels per unit
+    pub x: u16,
+    /// Vertical pixels per unit
+    pub y: u16,
+}
+
+/// The unit of a JFIF pixel density
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DensityUnit {
+    /// No unit, the densities only give the pixel aspect ratio
+    AspectRatio,
+    /// Pixels per inch
+    Inch,
+    /// Pixels per centimeter
+    Centimeter,
+}
+
+impl JPEGMetadata {
+    /// Picks out the metadata held in an APPn segment. Segments that aren't recognized, or are
+    /// too short, are ignored.
+    pub fn read_app_segment(&mut self, index: u8, payload: &[u8]) {
+        match index {
+            0 => {
+                if let Some(header) = payload.strip_prefix(JFIF_SIGNATURE) {
+                    self.density = self.density.or(read_density(header));
+                }
+            }
+            1 => {
+                if let Some(exif) = payload.strip_prefix(EXIF_SIGNATURE) {
+                    self.orientation = self.orientation.or(read_orientation(exif));
+                }
+            }
+            2 => self.has_icc_profile |= payload.starts_with(ICC_SIGNATURE),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the density from a JFIF header, after its signature: a two byte version, the unit, and
+/// the horizontal and vertical densities.
+fn read_density(header: &[u8]) -> Option<Density> {
+    let unit = match header.get(2)? {
+        0 => DensityUnit::AspectRatio,
+        1 => DensityUnit::Inch,
+        2 => DensityUnit::Centimeter,
+        _ => return None,
+    };
+    let x = u16::from_be_bytes([*header.get(3)?, *header.get(4)?]);
+    let y = u16::from_be_bytes([*header.get(5)?, *header.get(6)?]);
+    Some(Density { unit, x, y })
+}
+
+/// Reads the orientation out of the first IFD of EXIF data, after its signature
+fn read_orientation(exif: &[u8]) -> Option<u16> {
+    let tiff = TiffReader::new(exif)?;
+    let field = tiff.find_field(ORIENTATION_TAG)?;
+    // A single short, stored in the first two bytes of the value
+    let orientation = tiff.u16_at(field + 8)?;
+    (1..=8).contains(&orientation).then_some(orientation)
+}
+
+/// Estimates the quality a luma quantization table was made with, by inverting libjpeg's
+/// scaling of the Annex K table. A quality of `q` scales the table by `5000 / q` percent below 50
+/// and `200 - 2q` percent from 50 up.
+pub fn estimate_quality(table: &[[u16; 8]; 8]) -> u8 {
+    // Low qualities clip values at 255, which would hide how far the table was scaled
+    let (sum, standard) = table
+        .iter()
+        .flatten()
+        .zip(LUMA_QUANTIZATION.iter().flatten())
+        .filter(|(&q, _)| q < 255)
+        .fold((0, 0), |(sum, standard), (&q, &k)| {
+            (sum + q as u32, standard + k as u32)
+        });
+    if standard == 0 {
+        return 1;
+    }
+    let scale = (sum * 100 + standard / 2) / standard;
+
+    let quality = if scale <= 100 {
+        (200 - scale).div_ceil(2)
+    } else {
+        (5000 + scale / 2) / scale
+    };
+    quality.clamp(1, 100) as u8
+}
+
+#[test]
+fn read_app_segments() {
+    let mut metadata = JPEGMetadata::default();
+    metadata.read_app_segment(0, b"JFIF\0\x01\x02\x02\x00\x76\x00\x3B\0\0");
+    assert_eq!(
+        metadata.density,
+        Some(Density {
+            unit: DensityUnit::Centimeter,
+            x: 118,
+            y: 59
+        })
+    );
+
+    // Little endian EXIF with an orientation field after another field
+    let mut exif = b"Exif\0\0II\x2A\0\x08\0\0\0\x02\0".to_vec();
+    exif.extend([0x0F, 0x01, 2, 0, 4, 0, 0, 0, 0, 0, 0, 0]);
+    exif.extend([0x12, 0x01, 3, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+    metadata.read_app_segment(1, &exif);
+    assert_eq!(metadata.orientation, Some(6));
+
+    metadata.read_app_segment(2, b"MPF\0");
+    assert!(!metadata.has_icc_profile);
+    metadata.read_app_segment(2, b"ICC_PROFILE\0\x01\x01");
+    assert!(metadata.has_icc_profile);
+
+    // Broken segments are skipped
+    let mut metadata = JPEGMetadata::default();
+    metadata.read_app_segment(0, b"JFIF\0\x01\x02\x05\x00\x76\x00\x3B\0\0");
+    metadata.read_app_segment(0, b"JFIF\0\x01");
+    metadata.read_app_segment(1, &exif[..exif.len() - 4]);
+    assert_eq!(metadata, JPEGMetadata::default());
+}
+
+#[test]
+fn estimate_standard_quality() {
+    let scaled = |scale: u32| {
+        LUMA_QUANTIZATION
+            .map(|row| row.map(|q| ((q as u32 * scale + 50) / 100).clamp(1, 255) as u16))
+    };
+    for quality in [10, 25, 50, 75, 90, 95] {
+        let scale = if quality < 50 {
+            5000 / quality
+        } else {
+            200 - 2 * quality
+        };
+        assert_eq!(estimate_quality(&scaled(scale)), quality as u8);
+    }
+    assert_eq!(estimate_quality(&[[1; 8]; 8]), 99);
+    assert_eq!(estimate_quality(&[[255; 8]; 8]), 1);
+}
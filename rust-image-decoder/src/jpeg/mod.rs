@@ -1,26 +1,2363 @@
+mod coefficients;
+mod color;
+pub mod dct;
+#[cfg(feature = "std")]
+mod encoder;
 mod header;
 mod jpeg_core;
 mod jpeg_reader;
+mod metadata;
+mod mpf;
+mod standard_tables;
+mod tiff;
+pub mod zigzag;
+
+#[cfg(feature = "std")]
+pub use encoder::{ChromaSubsampling, JPEGEncoder};
+pub use jpeg_core::{Macroblock, McuIter, RowIter};
+pub use jpeg_reader::JPEGMarker;
+pub use metadata::{Density, DensityUnit, JPEGMetadata};
+
+use alloc::{boxed::Box, vec, vec::Vec};
+use core::ops::{ControlFlow, Deref};
 
 use crate::{
-    error::Result,
-    image::{Bitmap, ImageDecoder},
+    error::{Error, Result},
+    image::{Bitmap, BitmapF32, ColorSpace, ImageDecoder},
 };
 
 /// Contains JPEG image data
 pub struct JPEGDecoder<'data> {
-    image_data: &'data [u8],
+    image_data: ImageData<'data>,
+    options: DecodeOptions,
+    tables: JPEGTables,
+    app_segment_callback: Option<AppSegmentCallback<'data>>,
+    /// Buffers kept between calls to `decode_reusing_buffers`, so a decoder reused with `reset`
+    /// doesn't reallocate them
+    scratch: jpeg_core::Scratch,
+}
+
+/// Receives the APPn index and payload of each application segment in the header
+type AppSegmentCallback<'data> = Box<dyn Fn(u8, &[u8]) + Send + Sync + 'data>;
+
+// A decoder can be shared between threads by reference or moved to a worker, so it has to stay
+// Send and Sync
+const _: () = {
+    const fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<JPEGDecoder<'static>>();
+};
+
+/// A JPEG decoder that owns its image data, so it's `Send`, `Sync`, and `'static` and can be
+/// handed to a worker thread or stored. Create one with `JPEGDecoder::from_vec`.
+pub type OwnedJPEGDecoder = JPEGDecoder<'static>;
+
+/// Huffman and quantization tables that can be shared between images. Motion JPEG frames and other
+/// abbreviated streams often leave their tables out and rely on ones defined once for the stream.
+#[derive(Debug, Default, Clone)]
+pub struct JPEGTables(header::Tables);
+
+impl JPEGTables {
+    /// The example Huffman tables from Annex K of the JPEG spec, with the luma tables in slot 0
+    /// and the chroma tables in slot 1. Motion JPEG frames use these without defining them. No
+    /// quantization tables are included.
+    pub fn standard() -> Self {
+        Self(header::Tables::standard_huffman())
+    }
+}
+
+/// The bytes a decoder reads from, either borrowed from the caller or owned by the decoder
+enum ImageData<'data> {
+    Borrowed(&'data [u8]),
+    Owned(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl Deref for ImageData<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            ImageData::Borrowed(data) => data,
+            ImageData::Owned(data) => data,
+            #[cfg(feature = "mmap")]
+            ImageData::Mapped(mapping) => mapping,
+        }
+    }
+}
+
+/// Options controlling how a JPEG image is decoded
+#[derive(Debug, Clone)]
+pub struct DecodeOptions {
+    /// Scan past junk bytes before the SOI marker instead of requiring the image to start with it.
+    /// The search is bounded to the first few kilobytes of the data.
+    pub skip_leading_garbage: bool,
+    /// The IDCT implementation used to reconstruct samples, which also trades precision for speed
+    pub idct: IdctMethod,
+    /// The order color channels are stored in the decoded bitmap
+    pub channel_order: ChannelOrder,
+    /// Have `decode_planes` return subsampled components at their native resolution instead of
+    /// stretching them to the image size. Bitmaps are always upsampled.
+    pub skip_upsampling: bool,
+    /// How subsampled components are stretched to the image size
+    pub upsampling: Upsampling,
+    /// The most scans an image can have before decoding fails with `Error::UnsupportedFeature`,
+    /// so a malicious image can't waste time with a huge number of tiny scans. Defaults to 64.
+    pub max_scans: u16,
+    /// The most pixels an image can have, counting the padding out to whole MCUs, before decoding
+    /// fails with `Error::UnsupportedFeature`. The header alone sets the size, so this stops a
+    /// tiny file from asking for a huge allocation. Defaults to 2^28, a 16384x16384 image.
+    pub max_pixels: u64,
+    /// Supply 1 bits when the entropy coded data of a scan runs out early instead of failing, as
+    /// libjpeg does, to tolerate encoders that don't pad the last byte
+    pub fill_missing_bits: bool,
+    /// Fail on any deviation from the spec. When unset, the decoder tolerates the malformed files
+    /// browsers accept: a missing EOI marker, scans using undefined Huffman tables, which get the
+    /// standard tables instead, invalid Huffman codes, which end their block as if they were an
+    /// EOB, and truncated or corrupt scans, whose undecodable blocks are left gray. Defaults to
+    /// true.
+    pub strict: bool,
+}
+
+impl Default for DecodeOptions {
+    fn default() -> Self {
+        Self {
+            skip_leading_garbage: false,
+            idct: Default::default(),
+            channel_order: Default::default(),
+            skip_upsampling: false,
+            upsampling: Default::default(),
+            max_scans: 64,
+            max_pixels: 1 << 28,
+            fill_missing_bits: false,
+            strict: true,
+        }
+    }
+}
+
+/// Selects the order of the color channels of each decoded pixel
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOrder {
+    /// Red, green, blue
+    #[default]
+    Rgb,
+    /// Blue, green, red, as used by OpenCV and Windows bitmaps
+    Bgr,
+    /// Red, green, blue, and an opaque alpha of 255, the layout GPU textures are usually uploaded
+    /// in. The alpha is written in the same pass as the color, rather than added afterwards.
+    Rgba,
+}
+
+impl ChannelOrder {
+    /// The number of channels in each decoded pixel
+    pub(crate) fn channels(self) -> usize {
+        match self {
+            ChannelOrder::Rgb | ChannelOrder::Bgr => 3,
+            ChannelOrder::Rgba => 4,
+        }
+    }
+
+    pub(crate) fn color_space(self) -> ColorSpace {
+        match self {
+            ChannelOrder::Rgb => ColorSpace::Rgb,
+            ChannelOrder::Bgr => ColorSpace::Bgr,
+            ChannelOrder::Rgba => ColorSpace::Rgba,
+        }
+    }
+}
+
+/// Selects how the inverse DCT is computed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IdctMethod {
+    /// Direct floating point evaluation of the IDCT formula
+    #[default]
+    Float,
+    /// Fixed point evaluation. Slightly less precise, but gives identical output on every platform.
+    Integer,
+    /// A reduced precision fixed point transform that skips blocks with no AC coefficients.
+    /// Samples can be off by a couple of levels.
+    Fast,
+}
+
+/// Selects how subsampled chroma is stretched to the size of the image
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Upsampling {
+    /// Repeat each sample. Fast, but leaves blocky edges on colors.
+    #[default]
+    Nearest,
+    /// Blend the two nearest samples, like libjpeg's fancy upsampling
+    Bilinear,
+    /// Catmull-Rom cubic interpolation over the four nearest samples. Keeps edges sharper than
+    /// bilinear while still smoothing out the blocks.
+    CatmullRom,
+}
+
+/// How long each stage of a decode took, and how much work it did. Returned by
+/// `JPEGDecoder::decode_with_stats`.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone)]
+pub struct DecodeStats {
+    /// Time spent parsing the entropy coded data into quantized coefficients
+    pub entropy_decode: std::time::Duration,
+    /// Time spent dequantizing and running the inverse DCT
+    pub idct: std::time::Duration,
+    /// Time spent stretching subsampled components to the MCU size
+    pub upsampling: std::time::Duration,
+    /// Time spent converting YCbCr to the output pixel format
+    pub color_conversion: std::time::Duration,
+    /// The number of MCUs decoded, including padding MCUs past the image edges
+    pub mcus: usize,
+    /// The number of 8x8 blocks decoded, over all components
+    pub blocks: usize,
+}
+
+/// The dequantized DCT coefficients of every block in a JPEG image, before the IDCT is applied.
+#[derive(Debug, Default, PartialEq)]
+pub struct CoefficientData {
+    /// The coefficients for each component, in the order they appear in the frame header.
+    pub components: Vec<ComponentCoefficients>,
+}
+
+/// The dequantized DCT coefficients of a single component.
+#[derive(Debug, Default, PartialEq)]
+pub struct ComponentCoefficients {
+    /// The component identifier from the frame header
+    pub identifier: u8,
+    /// The number of 8x8 blocks horizontally and vertically, including MCU padding
+    pub block_dimensions: (u16, u16),
+    /// The blocks in raster order. Each block is indexed as `[row][column]` in natural, not
+    /// zigzag, order.
+    pub blocks: Vec<[[i16; 8]; 8]>,
+}
+
+/// The position and size of a marker segment in a JPEG file
+#[derive(Debug, Clone, PartialEq)]
+pub struct SegmentInfo {
+    /// The marker that starts the segment
+    pub marker: JPEGMarker,
+    /// The offset of the marker from the start of the data
+    pub offset: u64,
+    /// The number of bytes after the marker, including the length field. For SOS this also covers
+    /// the entropy coded data that follows the header. SOI and EOI have no payload.
+    pub length: usize,
+}
+
+/// The samples of a single component, without any color conversion
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Plane {
+    /// The component identifier from the frame header
+    pub identifier: u8,
+    /// The width and height of the plane in samples
+    pub size: (u16, u16),
+    /// The number of bytes from the start of one row to the start of the next
+    pub stride: usize,
+    /// The samples in raster order, with each row zero padded out to `stride` bytes
+    pub data: Vec<u8>,
+}
+
+impl JPEGDecoder<'static> {
+    /// Takes ownership of the JPEG image data, so the decoder can outlive the buffer it was read
+    /// into
+    pub fn from_vec(image_data: Vec<u8>) -> Self {
+        Self {
+            image_data: ImageData::Owned(image_data),
+            options: Default::default(),
+            tables: Default::default(),
+            app_segment_callback: None,
+            scratch: Default::default(),
+        }
+    }
+
+    /// Memory maps the file at `path` and decodes from the mapping instead of reading the file into
+    /// memory.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this or any other process, while the decoder
+    /// exists. The decoder reads the mapping as an ordinary byte slice, so a change underneath it
+    /// is undefined behavior.
+    #[cfg(feature = "mmap")]
+    pub unsafe fn from_path(path: &str) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        // Safety: The caller guarantees the file isn't changed while it's mapped
+        let mapping = unsafe { memmap2::Mmap::map(&file)? };
+
+        Ok(Self {
+            image_data: ImageData::Mapped(mapping),
+            options: Default::default(),
+            tables: Default::default(),
+            app_segment_callback: None,
+            scratch: Default::default(),
+        })
+    }
+}
+
+impl<'data> JPEGDecoder<'data> {
+    /// Replaces the options used when decoding
+    pub fn with_options(mut self, options: DecodeOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Provides tables for images that don't define their own. Tables the image does define still
+    /// take precedence over these.
+    pub fn with_tables(mut self, tables: JPEGTables) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    /// Calls `callback` with the index and payload of every APPn segment in the header, such as
+    /// JFIF in APP0 or EXIF in APP1, each time the header is parsed. The payload starts after the
+    /// segment's length field.
+    ///
+    /// The callback is `Fn` and `Send + Sync` so the decoder can still be shared between threads.
+    /// Collect into a `Mutex` or an atomic to keep state between calls.
+    pub fn on_app_segment(&mut self, callback: impl Fn(u8, &[u8]) + Send + Sync + 'data) {
+        self.app_segment_callback = Some(Box::new(callback));
+    }
+
+    /// Points the decoder at the next image, such as the next frame of a Motion JPEG stream,
+    /// keeping its options, tables, and APPn callback. The buffers kept by
+    /// `decode_reusing_buffers` are kept too, so decoding frames of the same size with it doesn't
+    /// reallocate them.
+    pub fn reset(&mut self, image_data: &'data [u8]) {
+        self.image_data = ImageData::Borrowed(image_data);
+    }
+
+    /// Decodes the image like `decode`, but keeps the decoder's internal buffers afterwards so
+    /// the next call can reuse them. Pair it with `reset` to decode a stream of frames without
+    /// reallocating for each one.
+    pub fn decode_reusing_buffers(&mut self) -> Result<Bitmap> {
+        let scratch = core::mem::take(&mut self.scratch);
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0)
+                .with_scratch(scratch);
+        let header = self.parse(&mut decoder)?;
+        let bitmap = decoder.read_scan(&header);
+        self.scratch = decoder.into_scratch();
+        bitmap
+    }
+
+    /// Parses the header with the core decoder, passing APPn segments to the callback if one was
+    /// set.
+    fn parse(&self, decoder: &mut jpeg_core::JPEGDecoder) -> Result<header::HeaderInfo> {
+        match &self.app_segment_callback {
+            Some(callback) => {
+                decoder.parse_with_app_segments(&mut |index, payload| callback(index, payload))
+            }
+            None => decoder.parse(),
+        }
+    }
+
+    /// Reads the Huffman and quantization tables defined before the first scan, so they can be
+    /// given to decoders for abbreviated images with `with_tables`. The data can be a full image or
+    /// an abbreviated table specification, which holds only tables between its SOI and EOI
+    /// markers. Tables given with `with_tables` aren't included.
+    pub fn read_tables(&self) -> Result<JPEGTables> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        Ok(JPEGTables(decoder.parse_tables()?))
+    }
+
+    /// Decodes the image up to, but not including, the IDCT and returns the dequantized
+    /// coefficients of every block.
+    pub fn decode_coefficients(&self) -> Result<CoefficientData> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_coefficients(&header)
+    }
+
+    /// Decodes the image like `decode`, also returning the dequantized coefficients it was
+    /// reconstructed from, as `decode_coefficients` would. Tools that edit and re-encode the
+    /// coefficients can use them directly instead of recovering them from the lossy pixels.
+    pub fn decode_with_coefficients(&self) -> Result<(Bitmap, CoefficientData)> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_scan_with_coefficients(&header)
+    }
+
+    /// Decodes the image like `decode`, also returning the density, orientation, comments, and
+    /// other metadata found while parsing the header, so the file isn't parsed twice. The
+    /// callback set with `on_app_segment` still sees every APPn segment.
+    pub fn decode_with_metadata(&self) -> Result<(Bitmap, JPEGMetadata)> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let mut metadata = JPEGMetadata::default();
+        let header = decoder.parse_with_app_segments(&mut |index, payload| {
+            metadata.read_app_segment(index, payload);
+            if let Some(callback) = &self.app_segment_callback {
+                callback(index, payload);
+            }
+        })?;
+
+        metadata.comments = header.comments.clone();
+        // The luma table says the most about quality, and the first component is luma
+        metadata.quality = header
+            .components
+            .first()
+            .and_then(|luma| header.tables.quant_tables[luma.frame.qtable_id as usize].as_ref())
+            .map(|qtable| metadata::estimate_quality(&qtable.table));
+
+        let bitmap = decoder.read_scan(&header)?;
+        Ok((bitmap, metadata))
+    }
+
+    /// Checks that the image is a well formed baseline JPEG without decoding any pixels. The
+    /// headers are parsed and every MCU is Huffman decoded, but the IDCT and color conversion are
+    /// skipped. Returns the first error found.
+    pub fn verify(&self) -> Result<()> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.verify_scan(&header)
+    }
+
+    /// Lists the marker segments of the image, from SOI to EOI, with their offsets and lengths.
+    /// Nothing is decoded, so this also works for images the decoder doesn't support.
+    pub fn segments(&self) -> Result<Vec<SegmentInfo>> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        decoder.read_segments()
+    }
+
+    /// Returns the offset just past the EOI marker of the main image. Anything from there on, such
+    /// as a trailing thumbnail or another MPF image, isn't part of the main image.
+    pub fn primary_image_end(&self) -> Result<u64> {
+        let segments = self.segments()?;
+        // `segments` always ends with the EOI marker, which is two bytes long
+        Ok(segments.last().map_or(0, |eoi| eoi.offset + 2))
+    }
+
+    /// Returns a decoder for each image in a Multi-Picture Format file, as listed by the MPF index
+    /// in the main image's APP2 segment, starting with the main image. Files without an index
+    /// just hold the main image. The decoders share this one's options and tables.
+    pub fn images(&self) -> Result<Vec<JPEGDecoder<'_>>> {
+        let image_decoder = |data| JPEGDecoder {
+            image_data: ImageData::Borrowed(data),
+            options: self.options.clone(),
+            tables: self.tables.clone(),
+            app_segment_callback: None,
+            scratch: Default::default(),
+        };
+
+        let index = self.segments()?.into_iter().find_map(|segment| {
+            // The payload starts after the marker and length field
+            let start = segment.offset as usize + 4;
+            let payload = self.image_data.get(start..start + segment.length - 2)?;
+            let is_index =
+                segment.marker == JPEGMarker::APP2 && payload.starts_with(mpf::MPF_SIGNATURE);
+            is_index.then_some((start + mpf::MPF_SIGNATURE.len(), payload))
+        });
+        let Some((base, payload)) = index else {
+            return Ok(vec![image_decoder(&self.image_data)]);
+        };
+
+        mpf::read_mp_entries(&payload[mpf::MPF_SIGNATURE.len()..])?
+            .into_iter()
+            .map(|entry| {
+                // Offsets are relative to the index, except the main image's, which is 0
+                let start = match entry.offset {
+                    0 => 0,
+                    offset => base + offset as usize,
+                };
+                self.image_data
+                    .get(start..)
+                    .and_then(|rest| rest.get(..entry.size as usize))
+                    .map(image_decoder)
+                    .ok_or_else(|| Error::Malformed("MPF image lies outside the data".into()))
+            })
+            .collect()
+    }
+
+    /// Reads the image header and returns its quantization tables indexed by destination id. Each
+    /// table is indexed as `[row][column]` in natural, not zigzag, order.
+    pub fn quantization_tables(&self) -> Result<[Option<[[u16; 8]; 8]>; 4]> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        Ok(header
+            .tables
+            .quant_tables
+            .map(|qtable| qtable.map(|qtable| qtable.table)))
+    }
+
+    /// Decodes the image like `decode`, calling `progress` with the fraction of the image done,
+    /// from 0.0 to 1.0, after each row of MCUs. Decoding stops with `Error::Cancelled` as soon as
+    /// `progress` returns `ControlFlow::Break`.
+    pub fn decode_with_progress(
+        &self,
+        mut progress: impl FnMut(f32) -> ControlFlow<()>,
+    ) -> Result<Bitmap> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_scan_with_progress(&header, &mut progress)
+    }
+
+    /// Decodes the image like `decode`, timing each stage of the decode. The stages are run over
+    /// the whole image one after another rather than MCU by MCU, so this is a little slower than
+    /// `decode`.
+    #[cfg(feature = "std")]
+    pub fn decode_with_stats(&self) -> Result<(Bitmap, DecodeStats)> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_scan_with_stats(&header)
+    }
+
+    /// Decodes the image like `decode`, but keeps the color converted samples as floats instead of
+    /// truncating them to bytes, for processing that needs more than 8 bits of precision.
+    pub fn decode_f32(&self) -> Result<BitmapF32> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_scan_f32(&header)
+    }
+
+    /// Decodes the image one MCU at a time, in raster order. The scan is entropy decoded up front,
+    /// but each MCU is only reconstructed when the iterator reaches it. MCUs along the right and
+    /// bottom edges may extend past the image and include padding.
+    pub fn mcu_iter(&self) -> Result<McuIter<'_>> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.into_mcu_iter(header)
+    }
+
+    /// Decodes the image one MCU row at a time, top to bottom, yielding each as a bitmap as wide
+    /// as the image and one MCU tall, with the last row cut off at the bottom of the image. Each
+    /// row is entropy decoded, reconstructed, and color converted only when the iterator reaches
+    /// it, so the pixels can be streamed on without holding the whole image.
+    pub fn row_iter(&self) -> Result<RowIter<'_>> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.into_row_iter(header)
+    }
+
+    /// Decodes the image like `decode`, but writes the pixels into `buf` instead of a new bitmap.
+    /// `buf` is cleared and resized to fit the image, so its allocation is reused when decoding
+    /// many images of the same size. Returns the width, height, and channel count.
+    pub fn decode_into(&self, buf: &mut Vec<u8>) -> Result<(u16, u16, u8)> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        let ((width, height), channels) = decoder.read_scan_into(&header, buf)?;
+        Ok((width, height, channels))
+    }
+
+    /// Decodes only the top of the image, for previews where the rest isn't visible yet. Decoding
+    /// stops after the MCU row holding pixel row `max_rows - 1`, so the bitmap is `max_rows`
+    /// rounded up to a whole number of MCUs tall, or the full image if that's shorter.
+    pub fn decode_rows(&self, max_rows: u16) -> Result<Bitmap> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_scan_rows(header, max_rows)
+    }
+
+    /// Decodes the image and draws a 1 pixel magenta line along the top and left edge of every
+    /// MCU. This is meant for debugging, to check where block boundaries fall with different
+    /// sampling factors, so it's only built with the `debug-overlay` feature.
+    #[cfg(feature = "debug-overlay")]
+    pub fn decode_with_mcu_grid(&self) -> Result<Bitmap> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        let mut bitmap = decoder.read_scan(&header)?;
+
+        let (mcu_width, mcu_height) = header.mcu_info.mcu_size;
+        let width = bitmap.size.0 as usize;
+        let channels = bitmap.channels as usize;
+        for (index, pixel) in bitmap.data.chunks_exact_mut(channels).enumerate() {
+            let (x, y) = (index % width, index / width);
+            if x % mcu_width as usize == 0 || y % mcu_height as usize == 0 {
+                // Magenta in both RGB and BGR order. Alpha is left opaque.
+                pixel[..3].copy_from_slice(&[255, 0, 255]);
+            }
+        }
+        Ok(bitmap)
+    }
+
+    /// Decodes each component into its own plane of samples, in frame order, without converting
+    /// to RGB. Subsampled components are stretched to the image size unless
+    /// `DecodeOptions::skip_upsampling` is set.
+    pub fn decode_planes(&self) -> Result<Vec<Plane>> {
+        self.decode_planes_aligned(1)
+    }
+
+    /// Decodes the planes like `decode_planes`, but pads every row of each plane with zeros to a
+    /// multiple of `row_align` bytes, as hardware encoders often require. A `row_align` of 0 or 1
+    /// leaves the rows tightly packed.
+    pub fn decode_planes_aligned(&self, row_align: usize) -> Result<Vec<Plane>> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_component_planes(&header, row_align)
+    }
+
+    /// Decodes only the luma channel of the image into a single channel grayscale bitmap. The
+    /// chroma components are still entropy decoded, but aren't reconstructed.
+    pub fn decode_luma(&self) -> Result<Bitmap> {
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
+        decoder.read_luma_scan(&header)
+    }
+}
+
+impl From<Vec<u8>> for JPEGDecoder<'static> {
+    fn from(image_data: Vec<u8>) -> Self {
+        Self::from_vec(image_data)
+    }
 }
 
 impl<'data> ImageDecoder<'data> for JPEGDecoder<'data> {
     /// Initializes the JPEG decoder from a byte slice
     fn new(image_data: &'data [u8]) -> Self {
-        Self { image_data }
+        Self {
+            image_data: ImageData::Borrowed(image_data),
+            options: Default::default(),
+            tables: Default::default(),
+            app_segment_callback: None,
+            scratch: Default::default(),
+        }
     }
 
     fn decode(&self) -> Result<Bitmap> {
-        let mut decoder = jpeg_core::JPEGDecoder::new(self.image_data);
-        let header = decoder.parse()?;
+        let mut decoder =
+            jpeg_core::JPEGDecoder::new(&self.image_data, &self.options, &self.tables.0);
+        let header = self.parse(&mut decoder)?;
         decoder.read_scan(&header)
     }
 }
+
+#[test]
+fn decode_coefficients() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let coefficients = JPEGDecoder::new(image_data).decode_coefficients().unwrap();
+
+    assert_eq!(coefficients.components.len(), 3);
+
+    let luma = &coefficients.components[0];
+    assert_eq!(luma.identifier, 1);
+    assert_eq!(luma.block_dimensions, (14, 20));
+    assert_eq!(luma.blocks.len(), 20 * 14);
+    // DC / 8 + 128 is the mean of the block, which is ~76.7 in the decoded image
+    assert_eq!(luma.blocks[0][0][0], -410);
+
+    let chroma = &coefficients.components[1];
+    assert_eq!(chroma.block_dimensions, (7, 10));
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn owned_decoder() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    fn assert_send_sync<T: Send + Sync + 'static>(_: &T) {}
+
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let expected = JPEGDecoder::new(image_data).decode().unwrap();
+
+    let segments = std::sync::Arc::new(AtomicUsize::new(0));
+    let mut owned: OwnedJPEGDecoder = JPEGDecoder::from_vec(image_data.to_vec());
+    let counter = segments.clone();
+    owned.on_app_segment(move |_, _| {
+        counter.fetch_add(1, Ordering::Relaxed);
+    });
+    let owned = owned.with_options(DecodeOptions {
+        strict: false,
+        ..Default::default()
+    });
+    assert_send_sync(&owned);
+    let worker = std::thread::spawn(move || (owned.decode().unwrap(), owned));
+    let (bitmap, owned) = worker.join().unwrap();
+    assert_eq!(bitmap, expected);
+    // JFIF in APP0 and Photoshop data in APP13
+    assert_eq!(segments.load(Ordering::Relaxed), 2);
+
+    // The decoder keeps its options and callback after moving back
+    assert_eq!(owned.decode_luma().unwrap().size, (105, 150));
+    assert_eq!(segments.load(Ordering::Relaxed), 4);
+}
+
+#[test]
+fn decode_rgba() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let options = DecodeOptions {
+        channel_order: ChannelOrder::Rgba,
+        ..Default::default()
+    };
+    let decoder = JPEGDecoder::new(image_data).with_options(options);
+
+    let rgba = decoder.decode().unwrap();
+    assert_eq!(
+        rgba,
+        JPEGDecoder::new(image_data)
+            .decode()
+            .unwrap()
+            .to_rgba(255)
+            .unwrap()
+    );
+
+    let mut buf = vec![];
+    assert_eq!(decoder.decode_into(&mut buf).unwrap(), (105, 150, 4));
+    assert_eq!(buf, rgba.data);
+    assert_eq!(decoder.decode_f32().unwrap().to_bitmap(), rgba);
+}
+
+#[test]
+fn decode_with_coefficients() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decoder = JPEGDecoder::new(image_data);
+    let (bitmap, coefficients) = decoder.decode_with_coefficients().unwrap();
+
+    assert_eq!(bitmap, decoder.decode().unwrap());
+    assert_eq!(coefficients, decoder.decode_coefficients().unwrap());
+}
+
+#[test]
+fn decode_with_metadata() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decoder = JPEGDecoder::new(image_data);
+    let (bitmap, metadata) = decoder.decode_with_metadata().unwrap();
+    assert_eq!(bitmap, decoder.decode().unwrap());
+    assert_eq!(
+        metadata,
+        JPEGMetadata {
+            density: Some(Density {
+                unit: DensityUnit::Inch,
+                x: 72,
+                y: 72,
+            }),
+            orientation: None,
+            has_icc_profile: false,
+            comments: vec![],
+            quality: Some(85),
+        }
+    );
+
+    // A comment and an EXIF orientation added after the JFIF header
+    let mut image_data = image_data[..20].to_vec();
+    image_data.extend([0xFF, 0xFE, 0, 7]);
+    image_data.extend(b"hello");
+    let mut exif = b"Exif\0\0MM\0\x2A\0\0\0\x08\0\x01".to_vec();
+    exif.extend([0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 8, 0, 0]);
+    image_data.extend([0xFF, 0xE1]);
+    image_data.extend((exif.len() as u16 + 2).to_be_bytes());
+    image_data.extend(exif);
+    image_data.extend(&include_bytes!("../../../image-decoder-app/resources/test.jpg")[20..]);
+
+    let segments = AtomicUsize::new(0);
+    let mut decoder = JPEGDecoder::new(&image_data);
+    decoder.on_app_segment(|_, _| {
+        segments.fetch_add(1, Ordering::Relaxed);
+    });
+    let (_, metadata) = decoder.decode_with_metadata().unwrap();
+    assert_eq!(metadata.orientation, Some(8));
+    assert_eq!(metadata.comments, [b"hello"]);
+    assert_eq!(segments.load(Ordering::Relaxed), 3);
+}
+
+#[test]
+fn decode_f32() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decoder = JPEGDecoder::new(image_data);
+    let bitmap = decoder.decode().unwrap();
+    let float = decoder.decode_f32().unwrap();
+    assert_eq!(float.to_bitmap(), bitmap);
+    // Some samples have a fraction left that the bytes lose
+    assert!(float
+        .data
+        .iter()
+        .any(|&sample| sample != sample as i32 as f32));
+}
+
+#[test]
+fn decode_bgr() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let rgb = JPEGDecoder::new(image_data).decode().unwrap();
+    let bgr = JPEGDecoder::new(image_data)
+        .with_options(DecodeOptions {
+            channel_order: ChannelOrder::Bgr,
+            ..Default::default()
+        })
+        .decode()
+        .unwrap();
+
+    assert_eq!(bgr.color_space, crate::image::ColorSpace::Bgr);
+    assert_ne!(rgb.data[0], rgb.data[2]);
+    assert_eq!(bgr.data[..3], [rgb.data[2], rgb.data[1], rgb.data[0]]);
+    for (bgr, rgb) in bgr.data.chunks(3).zip(rgb.data.chunks(3)) {
+        assert_eq!(bgr, [rgb[2], rgb[1], rgb[0]]);
+    }
+}
+
+#[test]
+fn decode_luma() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let luma = JPEGDecoder::new(image_data).decode_luma().unwrap();
+    let rgb = JPEGDecoder::new(image_data).decode().unwrap();
+
+    assert_eq!(luma.channels, 1);
+    assert_eq!(luma.color_space, crate::image::ColorSpace::Gray);
+    assert_eq!(luma.size, rgb.size);
+    assert_eq!(luma.data.len(), rgb.data.len() / 3);
+
+    // Pixels with a clipped channel no longer carry the original luma, so only compare the rest
+    for (lum, pixel) in luma
+        .data
+        .iter()
+        .zip(rgb.data.chunks(3))
+        .filter(|(_, pixel)| pixel.iter().all(|channel| *channel != 0 && *channel != 255))
+    {
+        let expected = 0.299 * pixel[0] as f32 + 0.587 * pixel[1] as f32 + 0.114 * pixel[2] as f32;
+        assert!(
+            (*lum as f32 - expected).abs() <= 3.0,
+            "{} vs {}",
+            lum,
+            expected
+        );
+    }
+}
+
+#[test]
+fn reject_lossless() {
+    #[rustfmt::skip]
+    let image_data = [
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xC3, // Start of frame, lossless
+        0, 11,          // Length
+        8,              // Precision
+        0, 16,          // Height
+        0, 16,          // Width
+        1,              // Component count
+        1, 0x11, 0,     // Component data
+    ];
+
+    let result = JPEGDecoder::new(&image_data).decode();
+    assert!(matches!(
+        result,
+        Err(crate::error::Error::UnsupportedFeature(message)) if message == "lossless JPEG (SOF3) not supported"
+    ));
+}
+
+#[test]
+fn reject_progressive_scan_parameters() {
+    let image_data = synthetic_jpeg((8, 8), &[(1, 1)], &[&[40]]);
+    let (scan, _) = entropy_segment(&image_data);
+    // Spectral selection and successive approximation follow the single component selector
+    let with_parameters = |parameters: [u8; 3]| {
+        let mut data = image_data.clone();
+        data[scan + 7..scan + 10].copy_from_slice(&parameters);
+        data
+    };
+
+    for parameters in [[1, 5, 0], [0, 0, 0], [0, 63, 0x01]] {
+        let data = with_parameters(parameters);
+        assert!(matches!(
+            JPEGDecoder::new(&data).decode_luma(),
+            Err(Error::Malformed(message)) if message.starts_with("Baseline scan must have")
+        ));
+    }
+    let data = with_parameters([1, 5, 0]);
+    let decoder = JPEGDecoder::new(&data).with_options(DecodeOptions {
+        strict: false,
+        ..Default::default()
+    });
+    assert_eq!(
+        decoder.decode_luma().unwrap(),
+        JPEGDecoder::new(&image_data).decode_luma().unwrap()
+    );
+}
+
+#[test]
+fn reject_arithmetic_coding() {
+    #[rustfmt::skip]
+    let image_data = [
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xCC, // Define arithmetic coding conditioning
+        0, 4,           // Length
+        0x00, 0x10,     // DC table 0, L = 0, U = 1
+        0xFF, 0xC0, // Start of frame
+        0, 11,          // Length
+        8,              // Precision
+        0, 16,          // Height
+        0, 16,          // Width
+        1,              // Component count
+        1, 0x11, 0,     // Component data
+    ];
+
+    let result = JPEGDecoder::new(&image_data).decode();
+    assert!(matches!(
+        result,
+        Err(crate::error::Error::UnsupportedFeature(message)) if message == "arithmetic JPEG (DAC present)"
+    ));
+}
+
+#[test]
+fn decode_color_space() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let bitmap = JPEGDecoder::new(image_data).decode().unwrap();
+
+    assert_eq!(bitmap.channels, 3);
+    assert_eq!(bitmap.color_space, crate::image::ColorSpace::Rgb);
+}
+
+#[test]
+fn skip_leading_garbage() {
+    let mut image_data = alloc::vec![0x00, 0xFF, 0x12, 0xD8, 0xFF];
+    image_data.extend_from_slice(include_bytes!(
+        "../../../image-decoder-app/resources/test.jpg"
+    ));
+
+    assert!(JPEGDecoder::new(&image_data).decode().is_err());
+
+    let bitmap = JPEGDecoder::new(&image_data)
+        .with_options(DecodeOptions {
+            skip_leading_garbage: true,
+            ..Default::default()
+        })
+        .decode()
+        .unwrap();
+    assert_eq!(bitmap.size, (105, 150));
+}
+
+#[test]
+fn quantization_tables() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let tables = JPEGDecoder::new(image_data).quantization_tables().unwrap();
+
+    assert_eq!(tables.iter().flatten().count(), 2);
+
+    let luma = tables[0].unwrap();
+    assert_eq!(luma[0][0], 5);
+    assert_eq!(luma[0][1], 3);
+    assert_eq!(luma[1][0], 4);
+    assert_eq!(luma[0][2], 3);
+
+    let chroma = tables[1].unwrap();
+    assert_eq!(chroma[0][0], 5);
+    assert_eq!(chroma[2][0], 7);
+    assert_eq!(chroma[1][1], 6);
+    assert_eq!(chroma[7][7], 30);
+}
+
+#[test]
+fn decode_with_quantization_table_3() {
+    let gray: &[i16] = &[80];
+    let image_data = synthetic_jpeg((8, 8), &[(1, 1)], &[gray]);
+    let expected = JPEGDecoder::new(&image_data).decode_luma().unwrap();
+
+    // Define the table in slot 3 instead of 0 and have the component use it
+    let mut slot_3 = image_data.clone();
+    let (dqt_info, frame_qtable) = (6, 83);
+    assert_eq!((slot_3[dqt_info], slot_3[frame_qtable]), (0, 0));
+    slot_3[dqt_info] = 3;
+    slot_3[frame_qtable] = 3;
+
+    let decoder = JPEGDecoder::new(&slot_3);
+    let tables = decoder.quantization_tables().unwrap();
+    assert!(tables[0].is_none());
+    assert_eq!(tables[3], Some([[1; 8]; 8]));
+    assert_eq!(decoder.decode_luma().unwrap(), expected);
+}
+
+#[test]
+fn decode_integer_idct() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let float = JPEGDecoder::new(image_data).decode().unwrap();
+    let integer = JPEGDecoder::new(image_data)
+        .with_options(DecodeOptions {
+            idct: IdctMethod::Integer,
+            ..Default::default()
+        })
+        .decode()
+        .unwrap();
+
+    assert_eq!(integer.size, float.size);
+    let max_difference = float
+        .data
+        .iter()
+        .zip(&integer.data)
+        .map(|(float, integer)| float.abs_diff(*integer))
+        .max()
+        .unwrap();
+    assert!(max_difference <= 3, "{}", max_difference);
+}
+
+#[test]
+fn decode_fast_idct() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decode = |idct| {
+        JPEGDecoder::new(image_data)
+            .with_options(DecodeOptions {
+                idct,
+                ..Default::default()
+            })
+            .decode()
+            .unwrap()
+    };
+
+    let accurate = decode(IdctMethod::Float);
+    assert_eq!(accurate, JPEGDecoder::new(image_data).decode().unwrap());
+
+    let fast = decode(IdctMethod::Fast);
+    let squared_error: f64 = accurate
+        .data
+        .iter()
+        .zip(&fast.data)
+        .map(|(a, b)| (*a as f64 - *b as f64) * (*a as f64 - *b as f64))
+        .sum();
+    let mean_squared_error = squared_error / accurate.data.len() as f64;
+    // A PSNR of over 40 dB
+    assert!(mean_squared_error < 6.5, "{}", mean_squared_error);
+}
+
+#[cfg(feature = "debug-overlay")]
+#[test]
+fn decode_with_mcu_grid() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let bitmap = JPEGDecoder::new(image_data).decode().unwrap();
+    let grid = JPEGDecoder::new(image_data).decode_with_mcu_grid().unwrap();
+    assert_eq!(grid.size, bitmap.size);
+
+    let pixel = |bitmap: &Bitmap, x: usize, y: usize| {
+        let offset = (y * 105 + x) * 3;
+        bitmap.data[offset..offset + 3].to_vec()
+    };
+    // 16x16 MCUs, so lines run along every 16th row and column
+    for (x, y) in [(0, 0), (16, 5), (32, 149), (104, 16), (50, 144)] {
+        assert_eq!(pixel(&grid, x, y), [255, 0, 255], "({}, {})", x, y);
+    }
+    for (x, y) in [(1, 1), (17, 15), (104, 149), (15, 17)] {
+        assert_eq!(pixel(&grid, x, y), pixel(&bitmap, x, y), "({}, {})", x, y);
+    }
+
+    let rgba = DecodeOptions {
+        channel_order: ChannelOrder::Rgba,
+        ..Default::default()
+    };
+    let grid = JPEGDecoder::new(image_data)
+        .with_options(rgba)
+        .decode_with_mcu_grid()
+        .unwrap();
+    assert_eq!(grid.data[..4], [255, 0, 255, 255]);
+    let offset = (17 * 105 + 17) * 4;
+    assert_eq!(
+        grid.data[offset..offset + 3],
+        bitmap.data[(17 * 105 + 17) * 3..][..3]
+    );
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn decode_with_stats() {
+    // 105x150 at 4:2:0, so 7x10 MCUs of four luma and two chroma blocks
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let (bitmap, stats) = JPEGDecoder::new(image_data).decode_with_stats().unwrap();
+    assert_eq!(bitmap, JPEGDecoder::new(image_data).decode().unwrap());
+    assert_eq!(stats.mcus, 7 * 10);
+    assert_eq!(stats.blocks, 7 * 10 * 6);
+    assert!(stats.entropy_decode > std::time::Duration::ZERO);
+}
+
+#[test]
+fn decode_planes() {
+    // 105x150 at 4:2:0
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let planes = JPEGDecoder::new(image_data).decode_planes().unwrap();
+    assert_eq!(planes.len(), 3);
+    assert!(planes.iter().all(|plane| plane.size == (105, 150)));
+
+    // The luma plane matches the luma only decode
+    let luma = JPEGDecoder::new(image_data).decode_luma().unwrap();
+    assert_eq!(planes[0].data, luma.data);
+
+    let native = JPEGDecoder::new(image_data)
+        .with_options(DecodeOptions {
+            skip_upsampling: true,
+            ..Default::default()
+        })
+        .decode_planes()
+        .unwrap();
+    assert_eq!(native[0], planes[0]);
+    assert!(planes.iter().all(|plane| plane.stride == 105));
+    for (chroma, stretched) in native[1..].iter().zip(&planes[1..]) {
+        assert_eq!(chroma.size, (105u16.div_ceil(2), 150 / 2));
+        assert_eq!(chroma.data.len(), 53 * 75);
+        // Each chroma sample was stretched over a 2x2 square
+        assert_eq!(chroma.data[53 + 10], stretched.data[2 * 105 + 20]);
+        assert_eq!(chroma.data[53 + 10], stretched.data[3 * 105 + 21]);
+    }
+}
+
+#[test]
+fn decode_planes_aligned() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let planes = JPEGDecoder::new(image_data).decode_planes().unwrap();
+    let aligned = JPEGDecoder::new(image_data)
+        .decode_planes_aligned(16)
+        .unwrap();
+
+    for (plane, aligned) in planes.iter().zip(&aligned) {
+        // 105 samples wide, padded to 112
+        assert_eq!(aligned.size, (105, 150));
+        assert_eq!(aligned.stride, 112);
+        assert_eq!(aligned.data.len(), 112 * 150);
+        for (row, aligned_row) in plane.data.chunks(105).zip(aligned.data.chunks(112)) {
+            assert_eq!(aligned_row[..105], *row);
+            assert!(aligned_row[105..].iter().all(|&padding| padding == 0));
+        }
+    }
+}
+
+#[test]
+fn verify() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    assert!(JPEGDecoder::new(image_data).verify().is_ok());
+
+    let truncated = &image_data[..image_data.len() / 2];
+    assert!(matches!(
+        JPEGDecoder::new(truncated).verify(),
+        Err(crate::error::Error::UnexpectedEof)
+    ));
+    assert!(JPEGDecoder::new(&image_data[..100]).verify().is_err());
+}
+
+#[test]
+fn decode_with_progress() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decoder = JPEGDecoder::new(image_data);
+
+    let mut reports = Vec::new();
+    let bitmap = decoder
+        .decode_with_progress(|fraction| {
+            reports.push(fraction);
+            ControlFlow::Continue(())
+        })
+        .unwrap();
+    assert_eq!(bitmap, decoder.decode().unwrap());
+    // 150 pixels tall in 16 pixel MCUs
+    assert_eq!(reports.len(), 10);
+    assert!(reports.windows(2).all(|pair| pair[0] < pair[1]));
+    assert_eq!(reports.last(), Some(&1.0));
+
+    let mut calls = 0;
+    let result = decoder.decode_with_progress(|_| {
+        calls += 1;
+        ControlFlow::Break(())
+    });
+    assert!(matches!(result, Err(crate::error::Error::Cancelled)));
+    assert_eq!(calls, 1);
+}
+
+#[test]
+fn decode_rows() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decoder = JPEGDecoder::new(image_data);
+    let bitmap = decoder.decode().unwrap();
+
+    // The top 16 rows are exactly the first row of 16x16 MCUs
+    let top = decoder.decode_rows(16).unwrap();
+    assert_eq!(top.size, (105, 16));
+    assert_eq!(top.data, bitmap.data[..105 * 16 * 3]);
+
+    // Partial MCU rows are rounded up, but never past the bottom of the image
+    assert_eq!(decoder.decode_rows(17).unwrap().size, (105, 32));
+    assert_eq!(decoder.decode_rows(1000).unwrap(), bitmap);
+}
+
+#[test]
+fn decode_into() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decoder = JPEGDecoder::new(image_data);
+    let bitmap = decoder.decode().unwrap();
+
+    let mut buf = vec![7; 10];
+    assert_eq!(decoder.decode_into(&mut buf).unwrap(), (105, 150, 3));
+    assert_eq!(buf, bitmap.data);
+
+    let (capacity, pointer) = (buf.capacity(), buf.as_ptr());
+    assert_eq!(decoder.decode_into(&mut buf).unwrap(), (105, 150, 3));
+    assert_eq!(buf, bitmap.data);
+    assert_eq!(buf.capacity(), capacity);
+    assert_eq!(buf.as_ptr(), pointer);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn reset() {
+    use crate::image::ImageEncoder;
+
+    let first = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let mut inverted = JPEGDecoder::new(first).decode().unwrap();
+    inverted
+        .data
+        .iter_mut()
+        .for_each(|sample| *sample = !*sample);
+    // A frame of the same size with different content, so stale buffers would show
+    let second = JPEGEncoder::new(&inverted).encode().unwrap();
+    let third = include_bytes!("../../../image-decoder-app/resources/test2.jpg");
+
+    let mut decoder = JPEGDecoder::new(first);
+    for frame in [&first[..], &second, &third[..], &first[..]] {
+        decoder.reset(frame);
+        let expected = JPEGDecoder::new(frame).decode().unwrap();
+        assert_eq!(decoder.decode_reusing_buffers().unwrap(), expected);
+        assert_eq!(decoder.decode().unwrap(), expected);
+    }
+
+    // Options and tables carry over to the next frame
+    let mut decoder = JPEGDecoder::new(first).with_options(DecodeOptions {
+        channel_order: ChannelOrder::Bgr,
+        ..Default::default()
+    });
+    decoder.reset(third);
+    assert_eq!(
+        decoder.decode().unwrap().color_space,
+        crate::image::ColorSpace::Bgr
+    );
+}
+
+#[test]
+fn decode_abbreviated_image() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+
+    // Split the image into its tables and everything else, like a Motion JPEG stream would
+    let mut table_segments = vec![0xFF, 0xD8];
+    let mut abbreviated = vec![0xFF, 0xD8];
+    let mut position = 2;
+    loop {
+        let marker = image_data[position + 1];
+        if marker == 0xDA {
+            abbreviated.extend(&image_data[position..]);
+            break;
+        }
+        let length = u16::from_be_bytes([image_data[position + 2], image_data[position + 3]]);
+        let segment = &image_data[position..position + 2 + length as usize];
+        match marker {
+            0xC4 | 0xDB => table_segments.extend(segment),
+            _ => abbreviated.extend(segment),
+        }
+        position += segment.len();
+    }
+    table_segments.extend([0xFF, 0xD9]);
+
+    assert!(matches!(
+        JPEGDecoder::new(&abbreviated).decode(),
+        Err(crate::error::Error::Malformed(_))
+    ));
+
+    let expected = JPEGDecoder::new(image_data).decode().unwrap();
+    let tables = JPEGDecoder::new(&table_segments).read_tables().unwrap();
+    let decoded = JPEGDecoder::new(&abbreviated)
+        .with_tables(tables)
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, expected);
+
+    // Tables read from a full image work too
+    let tables = JPEGDecoder::new(image_data).read_tables().unwrap();
+    let decoded = JPEGDecoder::new(&abbreviated)
+        .with_tables(tables)
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, expected);
+}
+
+#[test]
+fn decode_with_standard_tables() {
+    // This image uses the Annex K tables, so it still decodes with its DHT segments removed
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test2.jpg");
+
+    let mut without_dht = vec![0xFF, 0xD8];
+    let mut position = 2;
+    while image_data[position + 1] != 0xDA {
+        let length = u16::from_be_bytes([image_data[position + 2], image_data[position + 3]]);
+        let segment = &image_data[position..position + 2 + length as usize];
+        if image_data[position + 1] != 0xC4 {
+            without_dht.extend(segment);
+        }
+        position += segment.len();
+    }
+    without_dht.extend(&image_data[position..]);
+
+    let decoded = JPEGDecoder::new(&without_dht)
+        .with_tables(JPEGTables::standard())
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, JPEGDecoder::new(image_data).decode().unwrap());
+}
+
+#[test]
+fn mcu_iter() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let decoder = JPEGDecoder::new(image_data);
+    let bitmap = decoder.decode().unwrap();
+
+    let mcus = decoder.mcu_iter().unwrap();
+    // 105x150 in 16x16 MCUs is 7x10 MCUs once padded
+    assert_eq!(mcus.len(), 7 * 10);
+
+    let mut count = 0;
+    for (index, ((x, y), mcu)) in mcus.enumerate() {
+        assert_eq!((x, y), ((index as u32 % 7) * 16, (index as u32 / 7) * 16));
+        assert_eq!(mcu.component(0).unwrap().len(), 16);
+        assert_eq!(mcu.component(2).unwrap()[0].len(), 16);
+        assert!(mcu.component(3).is_none());
+
+        // The top left sample converts to the same pixel as the full decode
+        let (lum, cb, cr) = (
+            mcu.component(0).unwrap()[0][0],
+            mcu.component(1).unwrap()[0][0],
+            mcu.component(2).unwrap()[0][0],
+        );
+        let mut pixel = [0u8; 3];
+        color::ycbcr_row_to_rgb(&[lum], &[cb], &[cr], &mut pixel);
+        let offset = (y as usize * bitmap.width() as usize + x as usize) * 3;
+        assert_eq!(pixel, bitmap.data[offset..offset + 3]);
+
+        count += 1;
+    }
+    assert_eq!(count, 70);
+}
+
+#[test]
+fn row_iter() {
+    for image_data in [
+        &include_bytes!("../../../image-decoder-app/resources/test.jpg")[..],
+        &include_bytes!("../../../image-decoder-app/resources/test2.jpg")[..],
+    ] {
+        let decoder = JPEGDecoder::new(image_data);
+        let bitmap = decoder.decode().unwrap();
+
+        let mut data = vec![];
+        for row in decoder.row_iter().unwrap() {
+            let row = row.unwrap();
+            assert_eq!(row.size.0, bitmap.size.0);
+            data.extend(row.data);
+        }
+        assert_eq!(data, bitmap.data);
+    }
+
+    // An 8x16 image with a restart interval of one MCU, so the second row starts after a marker
+    let sampling = [(1, 1), (1, 1), (1, 1)];
+    let top = synthetic_jpeg((8, 8), &sampling, &[&[80], &[-20], &[10, 3]]);
+    let bottom = synthetic_jpeg((8, 8), &sampling, &[&[-40, 2], &[30], &[0]]);
+    let (scan, _) = entropy_segment(&top);
+    let mut image_data = top[..scan].to_vec();
+    image_data.extend([0xFF, 0xDD, 0, 4, 0, 1]);
+    image_data.extend(&top[scan..top.len() - 2]);
+    image_data.extend([0xFF, 0xD0]);
+    image_data.extend(entropy_segment(&bottom).1);
+    image_data.extend([0xFF, 0xD9]);
+    let frame = image_data
+        .windows(2)
+        .position(|bytes| bytes == [0xFF, 0xC0])
+        .unwrap();
+    image_data[frame + 5..frame + 7].copy_from_slice(&16u16.to_be_bytes());
+
+    let decoder = JPEGDecoder::new(&image_data);
+    let rows: Vec<Bitmap> = decoder.row_iter().unwrap().map(Result::unwrap).collect();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].data, JPEGDecoder::new(&top).decode().unwrap().data);
+    assert_eq!(
+        rows[1].data,
+        JPEGDecoder::new(&bottom).decode().unwrap().data
+    );
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn decode_from_path() {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/../image-decoder-app/resources/test.jpg"
+    );
+    // Safety: Nothing writes to the test images
+    let mapped = unsafe { JPEGDecoder::from_path(path) }
+        .unwrap()
+        .decode()
+        .unwrap();
+
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let bitmap = JPEGDecoder::new(image_data).decode().unwrap();
+    assert_eq!(mapped, bitmap);
+
+    assert!(matches!(
+        unsafe { JPEGDecoder::from_path("does/not/exist.jpg") },
+        Err(crate::error::Error::Io(_))
+    ));
+}
+
+/// Builds a baseline JPEG with a quantization table of all ones, so coefficients pass through
+/// unchanged. `data_units` holds the coefficients of each data unit in scan order, in zigzag order
+/// starting from the absolute DC value. Both Huffman tables code symbol `n` as `n` in 4 bits, so
+/// only DC differences and AC values up to 11 and 10 bits long, with no zero runs, can be used.
+#[cfg(test)]
+fn synthetic_jpeg(size: (u16, u16), sampling: &[(u8, u8)], data_units: &[&[i16]]) -> Vec<u8> {
+    fn put(bits: &mut Vec<bool>, value: u16, length: u8) {
+        bits.extend((0..length).rev().map(|bit| value >> bit & 1 == 1));
+    }
+    fn put_value(bits: &mut Vec<bool>, value: i16) {
+        let length = 16 - value.unsigned_abs().leading_zeros() as u8;
+        put(bits, length as u16, 4);
+        let extended = if value < 0 { value - 1 } else { value };
+        put(bits, extended as u16 & ((1 << length) - 1), length);
+    }
+
+    let mut bits = Vec::new();
+
+    // Scan order repeats each component's data units `h * v` times per MCU
+    let units_per_mcu: Vec<usize> = sampling
+        .iter()
+        .map(|(h, v)| *h as usize * *v as usize)
+        .collect();
+    let mut predictions = alloc::vec![0i16; sampling.len()];
+    let mut component_of_unit = units_per_mcu
+        .iter()
+        .enumerate()
+        .flat_map(|(component, count)| core::iter::repeat_n(component, *count))
+        .cycle();
+    for coefficients in data_units {
+        let component = component_of_unit.next().unwrap();
+        put_value(&mut bits, coefficients[0] - predictions[component]);
+        predictions[component] = coefficients[0];
+
+        for ac in &coefficients[1..] {
+            put_value(&mut bits, *ac);
+        }
+        if coefficients.len() < 64 {
+            put(&mut bits, 0, 4); // EOB
+        }
+    }
+
+    let mut image = alloc::vec![0xFF, 0xD8];
+
+    image.extend([0xFF, 0xDB, 0, 67, 0x00]);
+    image.extend([1; 64]);
+
+    image.extend([0xFF, 0xC0]);
+    image.extend((8 + 3 * sampling.len() as u16).to_be_bytes());
+    image.push(8);
+    image.extend(size.1.to_be_bytes());
+    image.extend(size.0.to_be_bytes());
+    image.push(sampling.len() as u8);
+    for (index, (h, v)) in sampling.iter().enumerate() {
+        image.extend([index as u8 + 1, h << 4 | v, 0]);
+    }
+
+    for (class, symbol_count) in [(0x00, 12), (0x10, 11)] {
+        image.extend([0xFF, 0xC4]);
+        image.extend((19 + symbol_count as u16).to_be_bytes());
+        image.extend([class, 0, 0, 0, symbol_count]);
+        image.extend([0; 12]);
+        image.extend(0..symbol_count);
+    }
+
+    image.extend([0xFF, 0xDA]);
+    image.extend((6 + 2 * sampling.len() as u16).to_be_bytes());
+    image.push(sampling.len() as u8);
+    for index in 0..sampling.len() {
+        image.extend([index as u8 + 1, 0x00]);
+    }
+    image.extend([0, 63, 0]);
+
+    // Pad the last byte with ones and stuff a zero after every 0xFF
+    for byte in bits.chunks(8) {
+        let byte = (0..8).fold(0u8, |acc, bit| {
+            acc << 1 | *byte.get(bit).unwrap_or(&true) as u8
+        });
+        image.push(byte);
+        if byte == 0xFF {
+            image.push(0x00);
+        }
+    }
+
+    image.extend([0xFF, 0xD9]);
+    image
+}
+
+/// Finds the SOS segment of an image from `synthetic_jpeg`, returning its offset and the
+/// entropy-coded data between it and the EOI marker.
+#[cfg(test)]
+fn entropy_segment(image: &[u8]) -> (usize, &[u8]) {
+    let scan = image
+        .windows(2)
+        .position(|bytes| bytes == [0xFF, 0xDA])
+        .unwrap();
+    let length = u16::from_be_bytes([image[scan + 2], image[scan + 3]]) as usize;
+    (scan, &image[scan + 2 + length..image.len() - 2])
+}
+
+#[test]
+fn decode_horizontal_subsampling() {
+    // 4:2:2, two 16x8 MCUs. Luma is flat, while Cb slopes down across each MCU from a different
+    // level in each, so every chroma column and the seam between MCUs can be told apart.
+    let flat: &[i16] = &[0];
+    let image_data = synthetic_jpeg(
+        (32, 8),
+        &[(2, 1), (1, 1), (1, 1)],
+        &[
+            flat,
+            flat,
+            &[160, 200],
+            flat,
+            flat,
+            flat,
+            &[-160, 200],
+            flat,
+        ],
+    );
+    let bitmap = JPEGDecoder::new(&image_data).decode().unwrap();
+    assert_eq!(bitmap.size, (32, 8));
+
+    let pixel = |x: usize, y: usize| &bitmap.data[(y * 32 + x) * 3..(y * 32 + x) * 3 + 3];
+    for y in 0..8 {
+        for mcu in 0..2 {
+            for column in 0..8 {
+                let x = mcu * 16 + column * 2;
+                // Each chroma sample covers exactly two pixels, up to the last column of the MCU
+                assert_eq!(pixel(x, y), pixel(x + 1, y), "({}, {})", x, y);
+                if column > 0 {
+                    assert!(pixel(x, y)[2] < pixel(x - 1, y)[2], "({}, {})", x, y);
+                }
+            }
+        }
+        assert!(pixel(15, y)[2] < pixel(16, y)[2]);
+    }
+}
+
+#[test]
+fn decode_411_subsampling() {
+    // 4:1:1, two 32x8 MCUs. Luma is flat and Cb slopes down across each MCU, so one chroma
+    // sample has to cover four pixel columns, and the MCU edges have to line up with the chroma.
+    let flat: &[i16] = &[0];
+    let image_data = synthetic_jpeg(
+        (64, 8),
+        &[(4, 1), (1, 1), (1, 1)],
+        &[
+            flat,
+            flat,
+            flat,
+            flat,
+            &[160, 200],
+            flat,
+            flat,
+            flat,
+            flat,
+            flat,
+            &[-160, 200],
+            flat,
+        ],
+    );
+    let decoder = JPEGDecoder::new(&image_data);
+    let bitmap = decoder.decode().unwrap();
+    assert_eq!(bitmap.size, (64, 8));
+
+    let pixel = |x: usize, y: usize| &bitmap.data[(y * 64 + x) * 3..(y * 64 + x) * 3 + 3];
+    for y in 0..8 {
+        for mcu in 0..2 {
+            for column in 0..8 {
+                let x = mcu * 32 + column * 4;
+                for offset in 1..4 {
+                    assert_eq!(pixel(x, y), pixel(x + offset, y), "({}, {})", x + offset, y);
+                }
+                if column > 0 {
+                    assert!(pixel(x, y)[2] < pixel(x - 1, y)[2], "({}, {})", x, y);
+                }
+            }
+        }
+        assert!(pixel(31, y)[2] < pixel(32, y)[2]);
+    }
+
+    // Interpolation stays within the chroma samples, weighted by distance from their centers,
+    // which fall between the second and third pixel of every four
+    let plane = |upsampling| {
+        let decoder = JPEGDecoder::new(&image_data).with_options(DecodeOptions {
+            upsampling,
+            skip_upsampling: upsampling == Upsampling::Nearest,
+            ..Default::default()
+        });
+        decoder.decode_planes().unwrap().remove(1)
+    };
+    let chroma = plane(Upsampling::Nearest);
+    let bilinear = plane(Upsampling::Bilinear);
+    assert_eq!((chroma.size, bilinear.size), ((16, 8), (64, 8)));
+    let sample = |x: usize| chroma.data[x] as i32;
+    for x in 0..64 {
+        let expected = match x % 4 {
+            _ if x < 2 => sample(0),
+            _ if x >= 62 => sample(15),
+            0 => (3 * sample(x / 4 - 1) + 5 * sample(x / 4) + 4) / 8,
+            1 => (sample(x / 4 - 1) + 7 * sample(x / 4) + 4) / 8,
+            2 => (7 * sample(x / 4) + sample(x / 4 + 1) + 4) / 8,
+            _ => (5 * sample(x / 4) + 3 * sample(x / 4 + 1) + 4) / 8,
+        };
+        assert_eq!(bilinear.data[x] as i32, expected, "{}", x);
+    }
+}
+
+#[test]
+fn decode_zero_based_component_ids() {
+    // A red 8x8 image, with its components numbered from 0 instead of 1
+    let image_data = synthetic_jpeg(
+        (8, 8),
+        &[(1, 1), (1, 1), (1, 1)],
+        &[&[-300], &[-340], &[1020]],
+    );
+    let expected = JPEGDecoder::new(&image_data).decode().unwrap();
+    let position = |marker: u8| {
+        image_data
+            .windows(2)
+            .position(|bytes| bytes == [0xFF, marker])
+            .unwrap()
+    };
+    let (frame, scan) = (position(0xC0), position(0xDA));
+
+    let mut renumbered = image_data.clone();
+    for index in 0..3 {
+        renumbered[frame + 10 + 3 * index] = index as u8;
+        renumbered[scan + 5 + 2 * index] = index as u8;
+    }
+    let bitmap = JPEGDecoder::new(&renumbered).decode().unwrap();
+    assert_eq!(bitmap, expected);
+    let pixel = &bitmap.data[..3];
+    assert!(
+        pixel[0] > 200 && pixel[1] < 50 && pixel[2] < 50,
+        "{:?}",
+        pixel
+    );
+
+    // The scan has to select components that are in the frame
+    renumbered[scan + 5 + 2 * 2] = 3;
+    assert!(matches!(
+        JPEGDecoder::new(&renumbered).decode(),
+        Err(Error::Malformed(message)) if message.contains("component 3")
+    ));
+}
+
+#[test]
+fn decode_maximum_width() {
+    // 65535 pixels wide pads to 65536, one past what fits in 16 bits
+    let flat: &[i16] = &[0];
+    let fast = DecodeOptions {
+        idct: IdctMethod::Fast,
+        ..Default::default()
+    };
+
+    let image_data = synthetic_jpeg((65535, 8), &[(1, 1)], &vec![flat; 8192]);
+    let luma = JPEGDecoder::new(&image_data)
+        .with_options(fast.clone())
+        .decode_luma()
+        .unwrap();
+    assert_eq!(luma.size, (65535, 8));
+    assert!(luma.data.iter().all(|sample| *sample == 128));
+
+    let image_data = synthetic_jpeg(
+        (65535, 16),
+        &[(2, 2), (1, 1), (1, 1)],
+        &vec![flat; 4096 * 6],
+    );
+    let decoder = JPEGDecoder::new(&image_data).with_options(fast);
+    let bitmap = decoder.decode().unwrap();
+    assert_eq!(bitmap.size, (65535, 16));
+    assert_eq!(bitmap.data.len(), 65535 * 16 * 3);
+    assert_eq!(decoder.decode_rows(65535).unwrap(), bitmap);
+}
+
+#[test]
+fn reject_single_component_rgb() {
+    let flat: &[i16] = &[0];
+    let image_data = synthetic_jpeg((8, 8), &[(1, 1)], &[flat]);
+    let decoder = JPEGDecoder::new(&image_data);
+
+    assert!(matches!(
+        decoder.decode(),
+        Err(crate::error::Error::UnsupportedFeature(_))
+    ));
+    assert!(matches!(
+        decoder.decode_into(&mut Vec::new()),
+        Err(crate::error::Error::UnsupportedFeature(_))
+    ));
+
+    // The luma alone can still be decoded
+    let luma = decoder.decode_luma().unwrap();
+    assert_eq!(luma.data, [128; 64]);
+}
+
+#[test]
+fn decode_restart_markers_without_interval() {
+    // Two single block images, which code their DC values relative to zero like the start of
+    // every restart interval
+    let flat: &[i16] = &[80];
+    let dark: &[i16] = &[-160];
+
+    // Join them into one 16x8 image with an RST0 marker in between and no DRI segment
+    let mut image_data = synthetic_jpeg((16, 8), &[(1, 1)], &[flat]);
+    image_data.truncate(image_data.len() - 2);
+    image_data.extend([0xFF, 0xD0]);
+    image_data.extend(entropy_segment(&synthetic_jpeg((8, 8), &[(1, 1)], &[dark])).1);
+    image_data.extend([0xFF, 0xD9]);
+
+    let bitmap = JPEGDecoder::new(&image_data).decode_luma().unwrap();
+    assert_eq!(bitmap.size, (16, 8));
+    for row in bitmap.data.chunks_exact(16) {
+        assert_eq!(row[..8], [137; 8]);
+        assert_eq!(row[8..], [109; 8]);
+    }
+}
+
+#[test]
+fn decode_short_last_restart_interval() {
+    let left = synthetic_jpeg((16, 8), &[(1, 1)], &[&[80], &[40, 3]]);
+    let right = synthetic_jpeg((8, 8), &[(1, 1)], &[&[-160]]);
+
+    // A 24x8 image with a restart interval of two MCUs, so the last interval only has one
+    let (scan, _) = entropy_segment(&left);
+    let mut image_data = left[..scan].to_vec();
+    image_data.extend([0xFF, 0xDD, 0, 4, 0, 2]);
+    image_data.extend(&left[scan..left.len() - 2]);
+    image_data.extend([0xFF, 0xD0]);
+    image_data.extend(entropy_segment(&right).1);
+    image_data.extend([0xFF, 0xD9]);
+    // Widen the frame from 16 to 24
+    let frame = image_data
+        .windows(2)
+        .position(|bytes| bytes == [0xFF, 0xC0])
+        .unwrap();
+    image_data[frame + 7..frame + 9].copy_from_slice(&24u16.to_be_bytes());
+
+    let bitmap = JPEGDecoder::new(&image_data).decode_luma().unwrap();
+    let left = JPEGDecoder::new(&left).decode_luma().unwrap();
+    let right = JPEGDecoder::new(&right).decode_luma().unwrap();
+    assert_eq!(bitmap.size, (24, 8));
+    for (y, row) in bitmap.data.chunks_exact(24).enumerate() {
+        assert_eq!(row[..16], left.data[y * 16..(y + 1) * 16]);
+        assert_eq!(row[16..], right.data[y * 8..(y + 1) * 8]);
+    }
+
+    // An interval of one MCU expects a marker after the first MCU, but there isn't one
+    let interval = image_data
+        .windows(2)
+        .position(|bytes| bytes == [0xFF, 0xDD])
+        .unwrap();
+    image_data[interval + 5] = 1;
+    assert!(JPEGDecoder::new(&image_data).decode_luma().is_err());
+}
+
+#[test]
+fn decode_restart_marker_sequence() {
+    let units: [&[i16]; 3] = [&[80, 3], &[-160], &[40, 0, -2]];
+    let intervals: Vec<Vec<u8>> = units
+        .iter()
+        .map(|unit| {
+            entropy_segment(&synthetic_jpeg((8, 8), &[(1, 1)], &[unit]))
+                .1
+                .to_vec()
+        })
+        .collect();
+
+    // A 24x8 image with a restart interval of one MCU, so each MCU is followed by a marker
+    let with_markers = |markers: [u8; 2]| {
+        let image = synthetic_jpeg((24, 8), &[(1, 1)], &units);
+        let (scan, _) = entropy_segment(&image);
+        let mut image_data = image[..scan].to_vec();
+        image_data.extend([0xFF, 0xDD, 0, 4, 0, 1]);
+        image_data.extend(&image[scan..scan + 10]);
+        image_data.extend(&intervals[0]);
+        for (marker, interval) in markers.iter().zip(&intervals[1..]) {
+            image_data.extend([0xFF, *marker]);
+            image_data.extend(interval);
+        }
+        image_data.extend([0xFF, 0xD9]);
+        image_data
+    };
+
+    let bitmap = JPEGDecoder::new(&with_markers([0xD0, 0xD1]))
+        .decode_luma()
+        .unwrap();
+    for (x, unit) in units.iter().enumerate() {
+        let expected = JPEGDecoder::new(&synthetic_jpeg((8, 8), &[(1, 1)], &[unit]))
+            .decode_luma()
+            .unwrap();
+        for (y, row) in bitmap.data.chunks_exact(24).enumerate() {
+            assert_eq!(row[x * 8..x * 8 + 8], expected.data[y * 8..(y + 1) * 8]);
+        }
+    }
+
+    // Markers out of sequence are only accepted outside strict mode
+    let out_of_sequence = with_markers([0xD0, 0xD0]);
+    assert!(matches!(
+        JPEGDecoder::new(&out_of_sequence).decode_luma(),
+        Err(crate::error::Error::Malformed(message))
+            if message == "Expected RST1 at the end of a restart interval, got RST0"
+    ));
+    let lenient = JPEGDecoder::new(&out_of_sequence).with_options(DecodeOptions {
+        strict: false,
+        ..Default::default()
+    });
+    assert_eq!(lenient.decode_luma().unwrap(), bitmap);
+}
+
+#[test]
+fn decode_invalid_huffman_code() {
+    // 2048 is coded with AC symbol 12, which the synthetic tables don't define. Its code and
+    // value bits make up the 16 bits the decoder reads before giving up on the code
+    let units: [&[i16]; 3] = [&[80, 3], &[-20, 2048], &[40, 0, -2]];
+
+    // A 24x8 image with a restart interval of one MCU, so each MCU is coded on its own
+    let image = synthetic_jpeg((24, 8), &[(1, 1)], &units);
+    let (scan, _) = entropy_segment(&image);
+    let mut image_data = image[..scan].to_vec();
+    image_data.extend([0xFF, 0xDD, 0, 4, 0, 1]);
+    image_data.extend(&image[scan..scan + 10]);
+    for (index, unit) in units.iter().enumerate() {
+        if index > 0 {
+            image_data.extend([0xFF, 0xD0 + index as u8 - 1]);
+        }
+        image_data.extend(entropy_segment(&synthetic_jpeg((8, 8), &[(1, 1)], &[unit])).1);
+    }
+    image_data.extend([0xFF, 0xD9]);
+
+    assert!(matches!(
+        JPEGDecoder::new(&image_data).decode_luma(),
+        Err(crate::error::Error::UnsupportedFeature(_))
+    ));
+
+    // The bad block ends at the invalid code, keeping its DC, and the blocks around it decode
+    // as usual
+    let bitmap = JPEGDecoder::new(&image_data)
+        .with_options(DecodeOptions {
+            strict: false,
+            ..Default::default()
+        })
+        .decode_luma()
+        .unwrap();
+    let expected: [&[i16]; 3] = [&[80, 3], &[-20], &[40, 0, -2]];
+    for (x, unit) in expected.iter().enumerate() {
+        let expected = JPEGDecoder::new(&synthetic_jpeg((8, 8), &[(1, 1)], &[unit]))
+            .decode_luma()
+            .unwrap();
+        for (y, row) in bitmap.data.chunks_exact(24).enumerate() {
+            assert_eq!(row[x * 8..x * 8 + 8], expected.data[y * 8..(y + 1) * 8]);
+        }
+    }
+}
+
+#[test]
+fn decode_empty_app_segment() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+
+    // An APP3 segment whose length only covers the length field, right after the SOI
+    let mut data = image_data[..2].to_vec();
+    data.extend([0xFF, 0xE3, 0x00, 0x02]);
+    data.extend(&image_data[2..]);
+
+    let expected = JPEGDecoder::new(image_data).decode().unwrap();
+    assert_eq!(JPEGDecoder::new(&data).decode().unwrap(), expected);
+}
+
+#[test]
+fn decode_skips_extension_markers() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+
+    // JPG0 and JPG13 segments, which are reserved for extensions, right after the SOI
+    let mut data = image_data[..2].to_vec();
+    data.extend([0xFF, 0xF0, 0x00, 0x04, 0x12, 0x34]);
+    data.extend([0xFF, 0xFD, 0x00, 0x03, 0x56]);
+    data.extend(&image_data[2..]);
+
+    let expected = JPEGDecoder::new(image_data).decode().unwrap();
+    assert_eq!(JPEGDecoder::new(&data).decode().unwrap(), expected);
+}
+
+#[test]
+fn decode_fill_bytes_before_frame() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+
+    // Three extra 0xFF fill bytes before the SOF0 marker. The APP13 segment happens to hold the
+    // same bytes, so search from the end.
+    let frame = image_data
+        .windows(2)
+        .rposition(|bytes| bytes == [0xFF, 0xC0])
+        .unwrap();
+    let mut data = image_data[..frame].to_vec();
+    data.extend([0xFF; 3]);
+    data.extend(&image_data[frame..]);
+
+    let expected = JPEGDecoder::new(image_data).decode().unwrap();
+    assert_eq!(JPEGDecoder::new(&data).decode().unwrap(), expected);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn on_app_segment() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+
+    let segments = std::sync::Mutex::new(Vec::new());
+    let mut decoder = JPEGDecoder::new(image_data);
+    decoder
+        .on_app_segment(|index, payload| segments.lock().unwrap().push((index, payload.to_vec())));
+    decoder.verify().unwrap();
+    drop(decoder);
+    let segments = segments.into_inner().unwrap();
+
+    // An APP0 JFIF segment followed by an APP13 Photoshop segment
+    assert_eq!(segments.len(), 2);
+    assert_eq!(segments[0].0, 0);
+    assert!(segments[0].1.starts_with(b"JFIF\0"));
+    assert_eq!(segments[0].1.len(), 14);
+    assert_eq!(segments[1].0, 13);
+}
+
+#[test]
+fn segments() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let segments = JPEGDecoder::new(image_data).segments().unwrap();
+
+    let markers: Vec<JPEGMarker> = segments.iter().map(|segment| segment.marker).collect();
+    assert_eq!(
+        markers,
+        [
+            JPEGMarker::SOI,
+            JPEGMarker::APP0,
+            JPEGMarker::APP13,
+            JPEGMarker::DQT,
+            JPEGMarker::DQT,
+            JPEGMarker::SOF0,
+            JPEGMarker::DHT,
+            JPEGMarker::DHT,
+            JPEGMarker::DHT,
+            JPEGMarker::DHT,
+            JPEGMarker::SOS,
+            JPEGMarker::EOI,
+        ]
+    );
+
+    // Every segment starts right where the last one ends
+    for pair in segments.windows(2) {
+        assert_eq!(pair[0].offset + 2 + pair[0].length as u64, pair[1].offset);
+    }
+    assert_eq!(segments[1].length, 16);
+    assert_eq!(segments[11].offset as usize, image_data.len() - 2);
+
+    assert!(JPEGDecoder::new(&image_data[..5000]).segments().is_err());
+}
+
+#[test]
+fn primary_image_end() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let end = JPEGDecoder::new(image_data).primary_image_end().unwrap();
+    assert_eq!(end, image_data.len() as u64);
+
+    // A second image after the first, like a trailing thumbnail
+    let mut data = image_data.to_vec();
+    data.extend(include_bytes!(
+        "../../../image-decoder-app/resources/test2.jpg"
+    ));
+    let decoder = JPEGDecoder::new(&data);
+    assert_eq!(decoder.primary_image_end().unwrap(), end);
+    assert_eq!(decoder.decode().unwrap().size, (105, 150));
+
+    let trailing = JPEGDecoder::new(&data[end as usize..]).decode().unwrap();
+    assert_eq!(trailing.size, (474, 315));
+}
+
+#[test]
+fn images() {
+    let first = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let second = include_bytes!("../../../image-decoder-app/resources/test2.jpg");
+    assert_eq!(JPEGDecoder::new(first).images().unwrap().len(), 1);
+
+    // Add an APP2 MPF index after the first image's SOI, listing both images
+    let index_length = 4 + 8 + 2 + 12 + 4 + 2 * 16;
+    let first_size = first.len() + 4 + index_length;
+    let mut index = b"MPF\0MM\0\x2A".to_vec();
+    index.extend(8u32.to_be_bytes());
+    index.extend(1u16.to_be_bytes());
+    index.extend([0xB0, 0x02, 0, 7]);
+    index.extend(32u32.to_be_bytes());
+    index.extend(26u32.to_be_bytes());
+    index.extend(0u32.to_be_bytes());
+    // Offsets are relative to the byte order marker, 10 bytes into the file
+    for (size, offset) in [(first_size, 0), (second.len(), first_size - 10)] {
+        index.extend(0x20030000u32.to_be_bytes());
+        index.extend((size as u32).to_be_bytes());
+        index.extend((offset as u32).to_be_bytes());
+        index.extend([0; 4]);
+    }
+    assert_eq!(index.len(), index_length);
+
+    let mut data = first[..2].to_vec();
+    data.extend([0xFF, 0xE2]);
+    data.extend((index_length as u16 + 2).to_be_bytes());
+    data.extend(&index);
+    data.extend(&first[2..]);
+    data.extend(second);
+
+    let decoder = JPEGDecoder::new(&data);
+    let images = decoder.images().unwrap();
+    assert_eq!(images.len(), 2);
+    assert_eq!(images[0].decode().unwrap().size, (105, 150));
+    assert_eq!(images[1].decode().unwrap().size, (474, 315));
+    drop(images);
+    drop(decoder);
+
+    // An index pointing past the end of the file
+    let size_offset = 6 + index_length - 12;
+    data[size_offset..size_offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+    assert!(JPEGDecoder::new(&data).images().is_err());
+}
+
+#[test]
+fn reject_scan_before_frame() {
+    // Move the SOS segment in front of the SOF0 segment
+    let image_data = synthetic_jpeg((8, 8), &[(1, 1)], &[&[0]]);
+    let segment = |marker: u8| {
+        let start = image_data
+            .windows(2)
+            .position(|bytes| bytes == [0xFF, marker])
+            .unwrap();
+        let length = u16::from_be_bytes([image_data[start + 2], image_data[start + 3]]);
+        start..start + 2 + length as usize
+    };
+    let (frame, scan) = (segment(0xC0), segment(0xDA));
+
+    let mut data = image_data[..frame.start].to_vec();
+    data.extend(&image_data[scan.clone()]);
+    data.extend(&image_data[frame.clone()]);
+    data.extend(&image_data[frame.end..scan.start]);
+    data.extend(&image_data[scan.end..]);
+
+    assert!(matches!(
+        JPEGDecoder::new(&data).decode_luma(),
+        Err(crate::error::Error::Malformed(message)) if message == "SOS before SOF"
+    ));
+}
+
+#[test]
+fn reject_too_many_scans() {
+    let image_data = synthetic_jpeg((8, 8), &[(1, 1)], &[&[0]]);
+    let with_scans = |scans: usize| {
+        // Bare SOS markers after the entropy coded data of the first scan
+        let mut data = image_data[..image_data.len() - 2].to_vec();
+        data.extend([0xFF, 0xDA].repeat(scans - 1));
+        data.extend([0xFF, 0xD9]);
+        data
+    };
+
+    assert!(JPEGDecoder::new(&with_scans(64)).decode_luma().is_ok());
+    assert!(matches!(
+        JPEGDecoder::new(&with_scans(65)).decode_luma(),
+        Err(crate::error::Error::UnsupportedFeature(message)) if message == "too many scans"
+    ));
+
+    let decoder = JPEGDecoder::new(&image_data).with_options(DecodeOptions {
+        max_scans: 1,
+        ..Default::default()
+    });
+    assert!(decoder.decode_luma().is_ok());
+    let data = with_scans(2);
+    let decoder = JPEGDecoder::new(&data).with_options(DecodeOptions {
+        max_scans: 1,
+        ..Default::default()
+    });
+    assert!(matches!(
+        decoder.decode_luma(),
+        Err(crate::error::Error::UnsupportedFeature(message)) if message == "too many scans"
+    ));
+}
+
+#[test]
+fn reject_too_many_pixels() {
+    // The largest frame a header can describe, with no entropy coded data behind it
+    let mut image_data = synthetic_jpeg((8, 8), &[(2, 2), (1, 1), (1, 1)], &[]);
+    let frame = image_data
+        .windows(2)
+        .position(|bytes| bytes == [0xFF, 0xC0])
+        .unwrap();
+    image_data[frame + 5..frame + 9].copy_from_slice(&[0xFF; 4]);
+
+    let decoder = JPEGDecoder::new(&image_data);
+    let too_large = |result: Result<()>| {
+        matches!(
+            result,
+            Err(Error::UnsupportedFeature(message)) if message.starts_with("image is too large")
+        )
+    };
+    assert!(too_large(decoder.decode().map(|_| ())));
+    assert!(too_large(decoder.decode_luma().map(|_| ())));
+    assert!(too_large(decoder.decode_planes().map(|_| ())));
+    assert!(too_large(decoder.decode_coefficients().map(|_| ())));
+    assert!(too_large(decoder.mcu_iter().map(|_| ())));
+
+    // The limit counts the padding out to whole MCUs
+    let image_data = synthetic_jpeg((7, 7), &[(1, 1)], &[&[0]]);
+    let with_limit = |max_pixels| {
+        JPEGDecoder::new(&image_data).with_options(DecodeOptions {
+            max_pixels,
+            ..Default::default()
+        })
+    };
+    assert!(too_large(with_limit(63).decode_luma().map(|_| ())));
+    assert!(with_limit(64).decode_luma().is_ok());
+}
+
+/// Decodes corrupted data every way the decoder can, which should fail with an error rather than
+/// panic
+#[cfg(test)]
+fn decode_corrupted(data: &[u8]) {
+    let decoder = JPEGDecoder::new(data);
+    let _ = decoder.decode();
+    let _ = decoder.decode_luma();
+    let _ = decoder.decode_coefficients();
+}
+
+#[test]
+fn corrupted_regressions() {
+    let test = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+    let test2 = include_bytes!("../../../image-decoder-app/resources/test2.jpg");
+
+    // Byte replacements that made the decoder panic, with an optional truncated length
+    type Corruption<'a> = (&'a [u8], &'a [(usize, u8)], Option<usize>);
+    #[rustfmt::skip]
+    let corpus: [Corruption; 16] = [
+        (test2, &[(486, 0x10)], None),                                  // DC prediction overflow
+        (test, &[(305, 0xcb), (21, 0xda)], None),                       // Scan before frame
+        (test2, &[(272, 0x23), (34688, 0xaf), (142, 0x50)], None),      // DC category over 11
+        (test2, &[(589, 0x48), (484, 0x0f)], None),                     // DC category over 11
+        (test2, &[(694, 0x72), (525, 0xd0)], None),                     // AC size of 0
+        (test2, &[(34903, 0x29), (35258, 0xdd), (451, 0xdf)], None),    // AC size over 10
+        (test, &[(9225, 0x02), (419, 0x44), (353, 0x13), (6142, 0x0a)], None), // Large selector
+        (test2, &[(20296, 0x6c), (686, 0xd5)], None),                   // Unknown selector
+        (test2, &[(683, 0xb2)], Some(35489)),                           // DC table id
+        (test2, &[(697, 0xcb), (687, 0xf7)], None),                     // AC table id
+        (test2, &[(244, 0xcb)], Some(29595)),                           // Quantization table id
+        (test2, &[(238, 0x0f), (350, 0xc0)], None),                     // Sampling factor of 0
+        (test2, &[(6452, 0xd1), (227, 0xd4), (7816, 0xd0), (37042, 0x6a)], None), // Zero factor
+        (test, &[(4441, 0x53), (8019, 0xf6), (6562, 0xf8), (981, 0x7b)], None), // Run past 63
+        (test, &[(12882, 0x01)], None),                                 // Bits past the end
+        (test, &[(2, 0xFF), (3, 0xE0), (4, 0), (5, 1)], None),          // Segment length of 1
+    ];
+
+    for (original, replacements, length) in corpus {
+        let mut data = original.to_vec();
+        for (offset, byte) in replacements {
+            data[*offset] = *byte;
+        }
+        if let Some(length) = length {
+            data.truncate(length);
+        }
+        decode_corrupted(&data);
+    }
+}
+
+#[test]
+fn corrupted_random() {
+    let original = include_bytes!("../../../image-decoder-app/resources/test.jpg");
+
+    // xorshift, so the same inputs are tried on every run
+    let mut state: u64 = 0x1234_5678;
+    let mut random = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize
+    };
+
+    for _ in 0..64 {
+        let mut data = original.to_vec();
+        for _ in 0..1 + random() % 4 {
+            // Favour the headers, where a single byte changes the most
+            let offset = if random() % 2 == 0 {
+                random() % 700
+            } else {
+                random() % data.len()
+            };
+            data[offset] = random() as u8;
+        }
+        if random() % 5 == 0 {
+            data.truncate(random() % data.len());
+        }
+        decode_corrupted(&data);
+    }
+}
+
+#[cfg(test)]
+fn fnv1a(values: impl Iterator<Item = u64>) -> u64 {
+    values.fold(0xcbf29ce484222325, |hash, value| {
+        (hash ^ value).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[test]
+fn matches_baseline_output() {
+    // Hashes of the output from before entropy decoding and reconstruction were split into
+    // separate passes. The integer IDCT keeps them the same across platforms.
+    let images: [(&[u8], u64, u64); 2] = [
+        (
+            include_bytes!("../../../image-decoder-app/resources/test.jpg"),
+            0x6db4ff253091e93c,
+            0xc7694f982ed2b253,
+        ),
+        (
+            include_bytes!("../../../image-decoder-app/resources/test2.jpg"),
+            0x41d800e9ea879c84,
+            0x89f1d99d774d5620,
+        ),
+    ];
+
+    for (image_data, bitmap_hash, coefficient_hash) in images {
+        let decoder = JPEGDecoder::new(image_data).with_options(DecodeOptions {
+            idct: IdctMethod::Integer,
+            ..Default::default()
+        });
+
+        let bitmap = decoder.decode().unwrap();
+        assert_eq!(
+            fnv1a(bitmap.data.iter().map(|byte| *byte as u64)),
+            bitmap_hash
+        );
+
+        let coefficients = decoder.decode_coefficients().unwrap();
+        let values = coefficients
+            .components
+            .iter()
+            .flat_map(|component| component.blocks.iter().flatten().flatten())
+            .map(|value| *value as u16 as u64);
+        assert_eq!(fnv1a(values), coefficient_hash);
+    }
+}
+
+#[cfg(not(feature = "std"))]
+#[test]
+fn decode_without_std() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test2.jpg");
+    let bitmap = JPEGDecoder::new(image_data).decode().unwrap();
+
+    assert_eq!(bitmap.size, (474, 315));
+    assert_eq!(bitmap.data.len(), 474 * 315 * 3);
+}
+
+#[test]
+fn decode_fill_missing_bits() {
+    #[rustfmt::skip]
+    let mut image_data = vec![
+        0xFF, 0xD8, // Start of image
+        0xFF, 0xDB, // Quantization table
+        0, 67,          // Length
+        0x00,           // 8 bit, table 0
+    ];
+    image_data.extend([1; 64]);
+    #[rustfmt::skip]
+    image_data.extend([
+        0xFF, 0xC4, // Huffman tables
+        0, 39,          // Length
+        0x00,           // DC table 0
+        1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0x00,           // Category 0 is "0"
+        0x10,           // AC table 0
+        2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        0xF0, 0xEA,     // ZRL is "0", a 14 zero run then a 10 bit value is "1"
+        0xFF, 0xC0, // Start of frame
+        0, 11,          // Length
+        8,              // Precision
+        0, 8,           // Height
+        0, 8,           // Width
+        1,              // Component count
+        1, 0x11, 0,     // Component data
+        0xFF, 0xDA, // Start of scan
+        0, 8,           // Length
+        1,              // Component count
+        1, 0x00,        // Component selector and tables
+        0, 63, 0,       // Spectral selection and successive approximation
+        // DC 0, three ZRLs, then coefficient 63 = 1023 and a fill bit, with the 0xFF stuffed
+        0x0F, 0xFF, 0x00,
+        0xFF, 0xD9, // End of image
+    ]);
+    let complete = JPEGDecoder::new(&image_data).decode_luma().unwrap();
+
+    // The same scan from an encoder that stops after the last whole byte, leaving out the ones
+    let end = image_data.len() - 4;
+    image_data.drain(end..end + 2);
+    assert!(JPEGDecoder::new(&image_data).decode_luma().is_err());
+
+    let filled = JPEGDecoder::new(&image_data)
+        .with_options(DecodeOptions {
+            fill_missing_bits: true,
+            ..Default::default()
+        })
+        .decode_luma()
+        .unwrap();
+    assert_eq!(filled, complete);
+}
+
+#[test]
+fn decode_lenient() {
+    let image_data = include_bytes!("../../../image-decoder-app/resources/test2.jpg");
+    let complete = JPEGDecoder::new(image_data).decode().unwrap();
+    let lenient = DecodeOptions {
+        strict: false,
+        ..Default::default()
+    };
+
+    // Fill bytes before the EOI marker are allowed by the spec, so both modes accept them
+    let mut padded = image_data[..image_data.len() - 2].to_vec();
+    padded.extend([0xFF, 0xFF, 0xFF, 0xD9]);
+    assert_eq!(JPEGDecoder::new(&padded).decode().unwrap(), complete);
+
+    // A missing EOI marker
+    let without_eoi = &image_data[..image_data.len() - 2];
+    assert!(matches!(
+        JPEGDecoder::new(without_eoi).decode(),
+        Err(Error::UnexpectedEof)
+    ));
+    let decoded = JPEGDecoder::new(without_eoi)
+        .with_options(lenient.clone())
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, complete);
+
+    // The image uses the Annex K tables, which stand in for its removed DHT segments
+    let mut without_dht = vec![0xFF, 0xD8];
+    let mut position = 2;
+    while image_data[position + 1] != 0xDA {
+        let length = u16::from_be_bytes([image_data[position + 2], image_data[position + 3]]);
+        let segment = &image_data[position..position + 2 + length as usize];
+        if image_data[position + 1] != 0xC4 {
+            without_dht.extend(segment);
+        }
+        position += segment.len();
+    }
+    without_dht.extend(&image_data[position..]);
+    assert!(JPEGDecoder::new(&without_dht).decode().is_err());
+    let decoded = JPEGDecoder::new(&without_dht)
+        .with_options(lenient.clone())
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, complete);
+
+    // A download cut off partway through the scan, losing the EOI marker
+    let truncated = &image_data[..image_data.len() * 2 / 3];
+    assert!(matches!(
+        JPEGDecoder::new(truncated).decode(),
+        Err(Error::UnexpectedEof)
+    ));
+    let decoded = JPEGDecoder::new(truncated)
+        .with_options(lenient)
+        .decode()
+        .unwrap();
+    assert_eq!(decoded.size, complete.size);
+    let row_length = complete.size.0 as usize * 3;
+    assert_eq!(decoded.data[..row_length], complete.data[..row_length]);
+    assert!(decoded.data[decoded.data.len() - row_length..]
+        .iter()
+        .all(|&sample| sample == 128));
+}
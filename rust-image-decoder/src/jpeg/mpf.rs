@@ -0,0 +1,107 @@
+use alloc::vec::Vec;
+
+use super::tiff::TiffReader;
+use crate::error::{Error, Result};
+
+/// The signature at the start of an APP2 segment holding a Multi-Picture Format index
+pub const MPF_SIGNATURE: &[u8] = b"MPF\0";
+
+/// The tag of the MP Entry field, which lists every image in the file
+const MP_ENTRY_TAG: u16 = 0xB002;
+
+/// The location of one image listed in an MPF index
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MpEntry {
+    /// The offset of the image's SOI marker, relative to the start of the MPF index's TIFF
+    /// header. The first image is always at offset 0, meaning the start of the file.
+    pub offset: u32,
+    /// The size of the image in bytes
+    pub size: u32,
+}
+
+/// Reads the MP entries out of an APP2 payload, after its `MPF\0` signature. The index is laid
+/// out like a TIFF IFD, in either byte order.
+pub fn read_mp_entries(index: &[u8]) -> Result<Vec<MpEntry>> {
+    let invalid = || Error::Malformed("Invalid MPF index".into());
+
+    let tiff = TiffReader::new(index).ok_or_else(invalid)?;
+    let field = tiff.find_field(MP_ENTRY_TAG).ok_or_else(invalid)?;
+
+    // Each entry is 16 bytes: attributes, size, offset, and two dependent image numbers
+    let length = tiff.u32_at(field + 4).ok_or_else(invalid)? as usize;
+    let start = tiff.u32_at(field + 8).ok_or_else(invalid)? as usize;
+    (0..length / 16)
+        .map(|entry| {
+            let entry = start + entry * 16;
+            Ok(MpEntry {
+                size: tiff.u32_at(entry + 4).ok_or_else(invalid)?,
+                offset: tiff.u32_at(entry + 8).ok_or_else(invalid)?,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+fn test_index(big_endian: bool) -> Vec<u8> {
+    let u16_bytes = |value: u16| {
+        if big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    };
+    let u32_bytes = |value: u32| {
+        if big_endian {
+            value.to_be_bytes()
+        } else {
+            value.to_le_bytes()
+        }
+    };
+
+    let mut index = Vec::new();
+    index.extend(if big_endian { b"MM" } else { b"II" });
+    index.extend(u16_bytes(0x2A));
+    index.extend(u32_bytes(8));
+    // Version and MP entry fields
+    index.extend(u16_bytes(2));
+    index.extend(u16_bytes(0xB000));
+    index.extend(u16_bytes(7));
+    index.extend(u32_bytes(4));
+    index.extend(b"0100");
+    index.extend(u16_bytes(MP_ENTRY_TAG));
+    index.extend(u16_bytes(7));
+    index.extend(u32_bytes(32));
+    index.extend(u32_bytes(8 + 2 + 24 + 4));
+    index.extend(u32_bytes(0)); // No next IFD
+    for (size, offset) in [(1000, 0), (500, 2000)] {
+        index.extend(u32_bytes(0x20030000));
+        index.extend(u32_bytes(size));
+        index.extend(u32_bytes(offset));
+        index.extend([0; 4]);
+    }
+    index
+}
+
+#[test]
+fn read_entries() {
+    for big_endian in [true, false] {
+        let entries = read_mp_entries(&test_index(big_endian)).unwrap();
+        assert_eq!(
+            entries,
+            [
+                MpEntry {
+                    offset: 0,
+                    size: 1000
+                },
+                MpEntry {
+                    offset: 2000,
+                    size: 500
+                }
+            ]
+        );
+    }
+
+    let index = test_index(true);
+    assert!(read_mp_entries(&index[..index.len() - 20]).is_err());
+    assert!(read_mp_entries(b"XX\0\x2A").is_err());
+}
@@ -0,0 +1,52 @@
+/// Reads values out of a TIFF structure, as embedded in EXIF and MPF segments, in the byte order
+/// its header gives. Reads past the end of the data return `None`.
+pub struct TiffReader<'data> {
+    data: &'data [u8],
+    big_endian: bool,
+}
+
+impl<'data> TiffReader<'data> {
+    /// Checks the byte order mark and magic number at the start of `data`. Offsets are relative
+    /// to the start of `data`.
+    pub fn new(data: &'data [u8]) -> Option<Self> {
+        let big_endian = match data.get(..2)? {
+            b"MM" => true,
+            b"II" => false,
+            _ => return None,
+        };
+        let reader = Self { data, big_endian };
+        (reader.u16_at(2)? == 0x2A).then_some(reader)
+    }
+
+    /// Reads the 16 bit value at `offset`
+    pub fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..)?.get(..2)?;
+        let bytes = [bytes[0], bytes[1]];
+        Some(if self.big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    }
+
+    /// Reads the 32 bit value at `offset`
+    pub fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..)?.get(..4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Some(if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    }
+
+    /// Returns the offset of the 12 byte field with `tag` in the first IFD. The field holds the
+    /// tag, type, count, and the value or an offset to it.
+    pub fn find_field(&self, tag: u16) -> Option<usize> {
+        let ifd = self.u32_at(4)? as usize;
+        let field_count = self.u16_at(ifd)? as usize;
+        (0..field_count)
+            .map(|field| ifd + 2 + field * 12)
+            .find(|&field| self.u16_at(field) == Some(tag))
+    }
+}
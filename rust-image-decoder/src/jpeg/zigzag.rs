@@ -0,0 +1,62 @@
+//! Conversions between the zigzag order coefficients are stored in within a JPEG file and their
+//! natural order, where `index = row * 8 + column`. F.1.1.5 Page 87
+
+/// The (row, column) of each coefficient, indexed by its position in zigzag order
+#[rustfmt::skip]
+pub const ZIGZAG_MAP: [(u8, u8); 64] =
+    [(0, 0), (0, 1), (1, 0), (2, 0), (1, 1), (0, 2), (0, 3), (1, 2),
+          (2, 1), (3, 0), (4, 0), (3, 1), (2, 2), (1, 3), (0, 4), (0, 5),
+          (1, 4), (2, 3), (3, 2), (4, 1), (5, 0), (6, 0), (5, 1), (4, 2),
+          (3, 3), (2, 4), (1, 5), (0, 6), (0, 7), (1, 6), (2, 5), (3, 4),
+          (4, 3), (5, 2), (6, 1), (7, 0), (7, 1), (6, 2), (5, 3), (4, 4),
+          (3, 5), (2, 6), (1, 7), (2, 7), (3, 6), (4, 5), (5, 4), (6, 3),
+          (7, 2), (7, 3), (6, 4), (5, 5), (4, 6), (3, 7), (4, 7), (5, 6),
+          (6, 5), (7, 4), (7, 5), (6, 6), (5, 7), (6, 7), (7, 6), (7, 7)];
+
+/// The zigzag position of each coefficient, indexed by its natural position. The inverse of
+/// `ZIGZAG_MAP`.
+pub const NATURAL_TO_ZIGZAG: [u8; 64] = {
+    let mut inverse = [0u8; 64];
+    let mut i = 0;
+    while i < 64 {
+        let (row, col) = ZIGZAG_MAP[i];
+        inverse[row as usize * 8 + col as usize] = i as u8;
+        i += 1;
+    }
+    inverse
+};
+
+/// Reorders 64 values from zigzag order to natural order.
+pub fn zigzag_to_natural<T: Copy>(zigzag: &[T; 64]) -> [T; 64] {
+    core::array::from_fn(|i| zigzag[NATURAL_TO_ZIGZAG[i] as usize])
+}
+
+/// Reorders 64 values from natural order to zigzag order.
+pub fn natural_to_zigzag<T: Copy>(natural: &[T; 64]) -> [T; 64] {
+    core::array::from_fn(|i| {
+        let (row, col) = ZIGZAG_MAP[i];
+        natural[row as usize * 8 + col as usize]
+    })
+}
+
+#[test]
+fn maps_are_inverses() {
+    for (i, (row, col)) in ZIGZAG_MAP.iter().enumerate() {
+        assert_eq!(
+            NATURAL_TO_ZIGZAG[*row as usize * 8 + *col as usize] as usize,
+            i
+        );
+    }
+    // The zigzag runs down the anti-diagonals, so the first few are easy to check by hand
+    assert_eq!(NATURAL_TO_ZIGZAG[..4], [0, 1, 5, 6]);
+    assert_eq!(NATURAL_TO_ZIGZAG[8], 2);
+    assert_eq!(NATURAL_TO_ZIGZAG[63], 63);
+}
+
+#[test]
+fn round_trip() {
+    let natural: [u16; 64] = core::array::from_fn(|i| i as u16 * 3);
+    let zigzag = natural_to_zigzag(&natural);
+    assert_eq!(zigzag[..4], [0, 3, 24, 48]);
+    assert_eq!(zigzag_to_natural(&zigzag), natural);
+}
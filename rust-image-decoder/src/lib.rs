@@ -1,11 +1,60 @@
 #![warn(missing_docs)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
 //! Allows opening and processing of various(just JPEG for now) image files.
+extern crate alloc;
+
 mod bitstream;
 mod error;
 /// Defines types for decoding images
 pub mod image;
-/// Decoder for JPEG images
+#[cfg(feature = "image")]
+mod image_interop;
+/// Decoder and encoder for JPEG images
 pub mod jpeg;
-/// Encoder for PPM images
+/// Encoder for PNG images
+#[cfg(feature = "std")]
+pub mod png;
+/// Encoder and decoder for PPM images
+#[cfg(feature = "std")]
 pub mod ppm;
+
+pub use error::{Error, Result};
+use image::{Bitmap, ImageDecoder};
+
+/// Decodes an image in any supported format, which is detected from the magic bytes at the start
+/// of the data.
+pub fn decode(data: &[u8]) -> Result<Bitmap> {
+    if data.starts_with(&[0xFF, 0xD8]) {
+        return jpeg::JPEGDecoder::new(data).decode();
+    }
+    #[cfg(feature = "std")]
+    if data.starts_with(b"P3") || data.starts_with(b"P6") {
+        return ppm::PPMDecoder::new(data).decode();
+    }
+
+    Err(Error::UnsupportedFeature(
+        "Unrecognized image format".into(),
+    ))
+}
+
+#[test]
+fn decode_detects_jpeg() {
+    let image_data = include_bytes!("../../image-decoder-app/resources/test.jpg");
+    let bitmap = decode(image_data).unwrap();
+    assert_eq!(bitmap, jpeg::JPEGDecoder::new(image_data).decode().unwrap());
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn decode_detects_ppm() {
+    let bitmap = decode(b"P6 1 1 255\n\x01\x02\x03").unwrap();
+    assert_eq!(bitmap, Bitmap::new(1, 1, 3, alloc::vec![1, 2, 3]).unwrap());
+}
+
+#[test]
+fn decode_rejects_unknown_format() {
+    let data = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0x42, 0x17];
+    assert!(matches!(decode(&data), Err(Error::UnsupportedFeature(_))));
+    assert!(matches!(decode(&[]), Err(Error::UnsupportedFeature(_))));
+}
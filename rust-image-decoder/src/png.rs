@@ -0,0 +1,621 @@
+use std::{fs::File, io, io::Write};
+
+use crate::{
+    error::{Error, Result},
+    image::{Bitmap, ColorSpace, ImageEncoder},
+};
+
+/// How the PNG encoder picks the filter applied to each row before compression
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum FilterStrategy {
+    /// Every row is stored unfiltered
+    None,
+    /// Each row gets whichever of the five filters leaves the smallest sum of absolute values,
+    /// the heuristic libpng uses. Photographs compress much better this way.
+    #[default]
+    Adaptive,
+}
+
+/// PNG encoder. The image data is compressed with LZ77 and the fixed Huffman codes of deflate.
+pub struct PNGEncoder<'bitmap> {
+    bitmap: &'bitmap Bitmap,
+    filter_strategy: FilterStrategy,
+}
+
+/// The row filters, numbered as in the filter type byte that starts each row
+const FILTER_NONE: u8 = 0;
+const FILTER_SUB: u8 = 1;
+const FILTER_UP: u8 = 2;
+const FILTER_AVERAGE: u8 = 3;
+const FILTER_PAETH: u8 = 4;
+
+impl<'bitmap> PNGEncoder<'bitmap> {
+    /// Sets how each row is filtered. Defaults to `FilterStrategy::Adaptive`.
+    pub fn with_filter_strategy(mut self, filter_strategy: FilterStrategy) -> Self {
+        self.filter_strategy = filter_strategy;
+        self
+    }
+
+    /// Encodes the bitmap and returns the PNG file data. Gray, RGB, BGR, and RGBA bitmaps with 8
+    /// or 16 bit samples are supported.
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let bitmap = self.bitmap;
+        let color_type = match bitmap.color_space {
+            ColorSpace::Gray => 0,
+            ColorSpace::Rgb | ColorSpace::Bgr => 2,
+            ColorSpace::Rgba => 6,
+            ColorSpace::Cmyk | ColorSpace::Ycck => {
+                return Err(Error::UnsupportedFeature(
+                    "PNG encoder only supports gray, RGB, and RGBA bitmaps".into(),
+                ))
+            }
+        };
+        if bitmap.bit_depth != 8 && bitmap.bit_depth != 16 {
+            return Err(Error::UnsupportedFeature(
+                "PNG encoder only supports 8 or 16 bit samples".into(),
+            ));
+        }
+        let channels = match color_type {
+            0 => 1,
+            2 => 3,
+            _ => 4,
+        };
+        if bitmap.channels != channels {
+            return Err(Error::Malformed(format!(
+                "{:?} bitmap has {} channels",
+                bitmap.color_space, bitmap.channels
+            )));
+        }
+        if bitmap.data.len()
+            != bitmap.size.0 as usize * bitmap.size.1 as usize * bitmap.bytes_per_pixel()
+        {
+            return Err(Error::Malformed(
+                "Bitmap data doesn't match its size".into(),
+            ));
+        }
+
+        let mut output = b"\x89PNG\r\n\x1A\n".to_vec();
+
+        let mut header = Vec::with_capacity(13);
+        header.extend((bitmap.size.0 as u32).to_be_bytes());
+        header.extend((bitmap.size.1 as u32).to_be_bytes());
+        // Bit depth, color type, deflate compression, adaptive filtering, no interlacing
+        header.extend([bitmap.bit_depth, color_type, 0, 0, 0]);
+        write_chunk(&mut output, b"IHDR", &header);
+
+        let mut data = vec![0x78, 0x01];
+        let scanlines = self.filter_scanlines();
+        deflate(&scanlines, &mut data);
+        data.extend(adler32(&scanlines).to_be_bytes());
+        write_chunk(&mut output, b"IDAT", &data);
+
+        write_chunk(&mut output, b"IEND", &[]);
+        Ok(output)
+    }
+
+    /// Returns the rows of the image, each behind the type byte of its filter
+    fn filter_scanlines(&self) -> Vec<u8> {
+        let bitmap = self.bitmap;
+        let pixel_length = bitmap.bytes_per_pixel().max(1);
+        let row_length = bitmap.size.0 as usize * pixel_length;
+        let owned_data;
+        let data = if bitmap.color_space == ColorSpace::Bgr {
+            owned_data = png_samples(bitmap);
+            &owned_data
+        } else {
+            &bitmap.data
+        };
+
+        let mut scanlines = Vec::with_capacity((row_length + 1) * bitmap.size.1 as usize);
+        let mut filtered = vec![0u8; row_length];
+        let mut best = vec![0u8; row_length];
+        let zero_row = vec![0u8; row_length];
+        for y in 0..bitmap.size.1 as usize {
+            let row = &data[y * row_length..(y + 1) * row_length];
+            let previous = match y {
+                0 => &zero_row,
+                _ => &data[(y - 1) * row_length..y * row_length],
+            };
+
+            let filters: &[u8] = match self.filter_strategy {
+                FilterStrategy::None => &[FILTER_NONE],
+                FilterStrategy::Adaptive => &[
+                    FILTER_NONE,
+                    FILTER_SUB,
+                    FILTER_UP,
+                    FILTER_AVERAGE,
+                    FILTER_PAETH,
+                ],
+            };
+            let mut best_filter = FILTER_NONE;
+            let mut best_score = u64::MAX;
+            for &filter in filters {
+                filter_row(filter, row, previous, pixel_length, &mut filtered);
+                // Treating the bytes as signed favors rows of small differences either way
+                let score = filtered
+                    .iter()
+                    .map(|&byte| (byte as i8).unsigned_abs() as u64)
+                    .sum();
+                if score < best_score {
+                    best_score = score;
+                    best_filter = filter;
+                    best.copy_from_slice(&filtered);
+                }
+            }
+
+            scanlines.push(best_filter);
+            scanlines.extend(&best);
+        }
+        scanlines
+    }
+}
+
+impl<'bitmap> ImageEncoder<'bitmap> for PNGEncoder<'bitmap> {
+    fn new(bitmap: &'bitmap Bitmap) -> Self {
+        Self {
+            bitmap,
+            filter_strategy: Default::default(),
+        }
+    }
+
+    fn encode_to_file(&self, path: &str) -> io::Result<()> {
+        let data = self.encode().map_err(|error| match error {
+            Error::Io(error) => error,
+            error => io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", error)),
+        })?;
+        File::create(path)?.write_all(&data)
+    }
+}
+
+/// Returns the data of a BGR bitmap with its pixels in the RGB order PNG stores. 16 bit samples
+/// are big endian in both, so they're left as they are.
+fn png_samples(bitmap: &Bitmap) -> Vec<u8> {
+    let mut data = bitmap.data.clone();
+    let sample_length = bitmap.bit_depth as usize / 8;
+    for pixel in data.chunks_exact_mut(sample_length * 3) {
+        let (blue, rest) = pixel.split_at_mut(sample_length);
+        blue.swap_with_slice(&mut rest[sample_length..]);
+    }
+    data
+}
+
+/// Applies `filter` to `row`, where `previous` is the row above, or zeros for the first row.
+/// Bytes to the left of the row count as zero too. PNG specification 9.2
+fn filter_row(filter: u8, row: &[u8], previous: &[u8], pixel_length: usize, output: &mut [u8]) {
+    for (index, (output, &byte)) in output.iter_mut().zip(row).enumerate() {
+        let left = match index.checked_sub(pixel_length) {
+            Some(left) => row[left],
+            None => 0,
+        };
+        let up = previous[index];
+        let up_left = match index.checked_sub(pixel_length) {
+            Some(left) => previous[left],
+            None => 0,
+        };
+
+        let prediction = match filter {
+            FILTER_SUB => left,
+            FILTER_UP => up,
+            FILTER_AVERAGE => ((left as u16 + up as u16) / 2) as u8,
+            FILTER_PAETH => paeth(left, up, up_left),
+            _ => 0,
+        };
+        *output = byte.wrapping_sub(prediction);
+    }
+}
+
+/// Predicts a byte from whichever neighbor is closest to `left + up - up_left`
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let distance = |value: u8| (estimate - value as i16).abs();
+    if distance(left) <= distance(up) && distance(left) <= distance(up_left) {
+        left
+    } else if distance(up) <= distance(up_left) {
+        up
+    } else {
+        up_left
+    }
+}
+
+/// Writes a chunk with its length, type, and the CRC of the type and data
+fn write_chunk(output: &mut Vec<u8>, chunk_type: &[u8; 4], data: &[u8]) {
+    output.extend((data.len() as u32).to_be_bytes());
+    let start = output.len();
+    output.extend(chunk_type);
+    output.extend(data);
+    let crc = crc32(&output[start..]);
+    output.extend(crc.to_be_bytes());
+}
+
+/// The CRC-32 of every byte value, for the reflected polynomial 0xEDB88320
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut index = 0;
+    while index < 256 {
+        let mut value = index as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            value = if value & 1 == 1 {
+                0xEDB8_8320 ^ (value >> 1)
+            } else {
+                value >> 1
+            };
+            bit += 1;
+        }
+        table[index] = value;
+        index += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        CRC_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+/// The checksum a zlib stream ends with. RFC 1950 Page 5
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that can be summed before `b` could overflow
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+/// The first length of each length code from 257, and how many extra bits follow it.
+/// RFC 1951 3.2.5
+const LENGTH_CODES: [(u16, u8); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+/// The first distance of each distance code, and how many extra bits follow it
+const DISTANCE_CODES: [(u16, u8); 30] = [
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 1),
+    (7, 1),
+    (9, 2),
+    (13, 2),
+    (17, 3),
+    (25, 3),
+    (33, 4),
+    (49, 4),
+    (65, 5),
+    (97, 5),
+    (129, 6),
+    (193, 6),
+    (257, 7),
+    (385, 7),
+    (513, 8),
+    (769, 8),
+    (1025, 9),
+    (1537, 9),
+    (2049, 10),
+    (3073, 10),
+    (4097, 11),
+    (6145, 11),
+    (8193, 12),
+    (12289, 12),
+    (16385, 13),
+    (24577, 13),
+];
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+/// How many earlier positions with the same hash are tried for each match
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+/// Compresses `data` as a single deflate block with the fixed Huffman codes, finding repeats with
+/// hash chains over the last 32 KiB.
+fn deflate(data: &[u8], output: &mut Vec<u8>) {
+    let mut writer = BitWriter::new(output);
+    // The final block, with fixed Huffman codes
+    writer.write_bits(1, 1);
+    writer.write_bits(1, 2);
+
+    let hash = |position: usize| {
+        let bytes = (data[position] as u32) << 16
+            | (data[position + 1] as u32) << 8
+            | data[position + 2] as u32;
+        (bytes.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+    };
+    // The last position with each hash, and the position before each with the same hash. Both
+    // are stored one higher so zero can mean none.
+    let mut head = vec![0usize; 1 << HASH_BITS];
+    let mut previous = vec![0usize; WINDOW_SIZE];
+    let insert = |position: usize, head: &mut [usize], previous: &mut [usize]| {
+        if position + MIN_MATCH <= data.len() {
+            let hash = hash(position);
+            previous[position % WINDOW_SIZE] = head[hash];
+            head[hash] = position + 1;
+        }
+    };
+
+    let mut position = 0;
+    while position < data.len() {
+        let (mut best_length, mut best_distance) = (0, 0);
+        if position + MIN_MATCH <= data.len() {
+            let mut candidate = head[hash(position)];
+            let mut chain = 0;
+            while candidate > 0 && chain < MAX_CHAIN {
+                let start = candidate - 1;
+                if position - start > WINDOW_SIZE {
+                    break;
+                }
+                let length = data[start..]
+                    .iter()
+                    .zip(&data[position..])
+                    .take(MAX_MATCH)
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    (best_length, best_distance) = (length, position - start);
+                    if length == MAX_MATCH {
+                        break;
+                    }
+                }
+                let next = previous[start % WINDOW_SIZE];
+                // Entries older than the window have been overwritten by newer positions
+                if next == 0 || next > start {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+
+        if best_length >= MIN_MATCH {
+            write_match(&mut writer, best_length, best_distance);
+            for skipped in position..position + best_length {
+                insert(skipped, &mut head, &mut previous);
+            }
+            position += best_length;
+        } else {
+            write_literal(&mut writer, data[position] as u16);
+            insert(position, &mut head, &mut previous);
+            position += 1;
+        }
+    }
+
+    // End of block
+    write_literal(&mut writer, 256);
+    writer.flush();
+}
+
+/// Writes a literal, length, or end of block symbol with its fixed Huffman code. RFC 1951 3.2.6
+fn write_literal(writer: &mut BitWriter, symbol: u16) {
+    let (code, length) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    };
+    writer.write_code(code, length);
+}
+
+fn write_match(writer: &mut BitWriter, length: usize, distance: usize) {
+    let code = LENGTH_CODES
+        .iter()
+        .rposition(|&(base, _)| base as usize <= length)
+        .unwrap_or(0);
+    let (base, extra_bits) = LENGTH_CODES[code];
+    write_literal(writer, 257 + code as u16);
+    writer.write_bits(length as u32 - base as u32, extra_bits);
+
+    let code = DISTANCE_CODES
+        .iter()
+        .rposition(|&(base, _)| base as usize <= distance)
+        .unwrap_or(0);
+    let (base, extra_bits) = DISTANCE_CODES[code];
+    // Distance codes are all 5 bits long
+    writer.write_code(code as u16, 5);
+    writer.write_bits(distance as u32 - base as u32, extra_bits);
+}
+
+/// Writes bits least significant first, as deflate packs them
+struct BitWriter<'output> {
+    output: &'output mut Vec<u8>,
+    buffer: u32,
+    bit_count: u8,
+}
+
+impl<'output> BitWriter<'output> {
+    fn new(output: &'output mut Vec<u8>) -> Self {
+        Self {
+            output,
+            buffer: 0,
+            bit_count: 0,
+        }
+    }
+
+    /// Writes the low `length` bits of `bits`. `length` can be at most 16.
+    fn write_bits(&mut self, bits: u32, length: u8) {
+        self.buffer |= (bits & ((1 << length) - 1)) << self.bit_count;
+        self.bit_count += length;
+        while self.bit_count >= 8 {
+            self.output.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.bit_count -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which is packed starting from its most significant bit
+    fn write_code(&mut self, code: u16, length: u8) {
+        let reversed = code.reverse_bits() >> (16 - length);
+        self.write_bits(reversed as u32, length);
+    }
+
+    /// Pads the last byte with zero bits
+    fn flush(&mut self) {
+        if self.bit_count > 0 {
+            self.write_bits(0, 8 - self.bit_count);
+        }
+    }
+}
+
+#[test]
+fn checksums() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+    assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    assert_eq!(adler32(&[]), 1);
+}
+
+#[test]
+fn filter_rows() {
+    // Two RGB pixels over a row above
+    let previous = [10, 20, 30, 40, 50, 60];
+    let row = [12, 22, 32, 45, 55, 65];
+    let mut output = [0u8; 6];
+
+    filter_row(FILTER_SUB, &row, &previous, 3, &mut output);
+    assert_eq!(output, [12, 22, 32, 33, 33, 33]);
+    filter_row(FILTER_UP, &row, &previous, 3, &mut output);
+    assert_eq!(output, [2, 2, 2, 5, 5, 5]);
+    filter_row(FILTER_AVERAGE, &row, &previous, 3, &mut output);
+    assert_eq!(output, [7, 12, 17, 19, 19, 19]);
+    filter_row(FILTER_PAETH, &row, &previous, 3, &mut output);
+    assert_eq!(output, [2, 2, 2, 5, 5, 5]);
+
+    assert_eq!(paeth(10, 20, 15), 15);
+    assert_eq!(paeth(10, 20, 30), 10);
+    assert_eq!(paeth(30, 20, 10), 30);
+}
+
+#[test]
+fn encode() {
+    let bitmap = Bitmap::new(2, 1, 3, vec![255, 0, 0, 0, 0, 255]).unwrap();
+    let data = PNGEncoder::new(&bitmap).encode().unwrap();
+    assert!(data.starts_with(b"\x89PNG\r\n\x1A\n\0\0\0\x0DIHDR"));
+    assert_eq!(data[16..29], [0, 0, 0, 2, 0, 0, 0, 1, 8, 2, 0, 0, 0]);
+    assert!(data.ends_with(b"\0\0\0\0IEND\xAE\x42\x60\x82"));
+
+    let deep_bgr = Bitmap {
+        color_space: ColorSpace::Bgr,
+        bit_depth: 16,
+        data: vec![1, 2, 3, 4, 5, 6],
+        ..bitmap
+    };
+    assert_eq!(png_samples(&deep_bgr), [5, 6, 3, 4, 1, 2]);
+
+    let cmyk = Bitmap {
+        color_space: ColorSpace::Cmyk,
+        ..Bitmap::new(1, 1, 4, vec![0; 4]).unwrap()
+    };
+    assert!(matches!(
+        PNGEncoder::new(&cmyk).encode(),
+        Err(Error::UnsupportedFeature(_))
+    ));
+
+    let short = Bitmap {
+        data: vec![255, 0, 0],
+        ..bitmap.clone()
+    };
+    assert!(matches!(
+        PNGEncoder::new(&short).encode(),
+        Err(Error::Malformed(_))
+    ));
+    let gray = Bitmap {
+        color_space: ColorSpace::Gray,
+        ..bitmap
+    };
+    assert!(matches!(
+        PNGEncoder::new(&gray).encode(),
+        Err(Error::Malformed(_))
+    ));
+}
+
+#[test]
+fn round_trip() {
+    use crate::{image::ImageDecoder, jpeg::JPEGDecoder};
+
+    let image_data = include_bytes!("../../image-decoder-app/resources/test.jpg");
+    let photo = JPEGDecoder::new(image_data).decode().unwrap();
+    // Gradients with 16 bit samples, so every filter has something to predict
+    let words: Vec<u16> = (0..8 * 5 * 4).map(|index| index * 409).collect();
+    let deep = Bitmap {
+        channels: 4,
+        color_space: ColorSpace::Rgba,
+        bit_depth: 16,
+        size: (8, 5),
+        data: words.iter().flat_map(|word| word.to_be_bytes()).collect(),
+    };
+    let gray = Bitmap::new(7, 3, 1, (0..21).map(|index| index * 12).collect()).unwrap();
+
+    for bitmap in [&photo, &deep, &gray] {
+        for strategy in [FilterStrategy::None, FilterStrategy::Adaptive] {
+            let encoded = PNGEncoder::new(bitmap)
+                .with_filter_strategy(strategy)
+                .encode()
+                .unwrap();
+
+            // The reference decoder inflates the IDAT data and undoes the row filters
+            let mut reader = png::Decoder::new(&encoded[..]).read_info().unwrap();
+            let mut data = vec![0; reader.output_buffer_size()];
+            let info = reader.next_frame(&mut data).unwrap();
+            assert_eq!(
+                (info.width, info.height),
+                (bitmap.size.0 as u32, bitmap.size.1 as u32)
+            );
+            assert_eq!(data, bitmap.data, "{:?}", strategy);
+        }
+    }
+}
+
+#[test]
+fn adaptive_filtering() {
+    use crate::{image::ImageDecoder, jpeg::JPEGDecoder};
+
+    let image_data = include_bytes!("../../image-decoder-app/resources/test.jpg");
+    let bitmap = JPEGDecoder::new(image_data).decode().unwrap();
+    let unfiltered = PNGEncoder::new(&bitmap)
+        .with_filter_strategy(FilterStrategy::None)
+        .encode()
+        .unwrap();
+    let adaptive = PNGEncoder::new(&bitmap).encode().unwrap();
+    assert!(
+        adaptive.len() <= unfiltered.len(),
+        "{} > {}",
+        adaptive.len(),
+        unfiltered.len()
+    );
+}
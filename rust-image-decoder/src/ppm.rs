@@ -3,7 +3,186 @@ use std::{
     io::{self, Write},
 };
 
-use crate::image::{Bitmap, ImageEncoder};
+use crate::{
+    error::{Error, Result},
+    image::{Bitmap, ColorSpace, ImageDecoder, ImageEncoder},
+};
+
+/// PPM decoder, reading both ASCII (P3) and binary (P6) images
+pub struct PPMDecoder<'data> {
+    image_data: &'data [u8],
+    max_pixels: u64,
+}
+
+impl PPMDecoder<'_> {
+    /// Sets the most pixels an image can have before decoding fails with
+    /// `Error::UnsupportedFeature`, so a tiny file can't ask for a huge allocation. Defaults to
+    /// 2^28, a 16384x16384 image, like `DecodeOptions::max_pixels` for JPEGs.
+    pub fn with_max_pixels(mut self, max_pixels: u64) -> Self {
+        self.max_pixels = max_pixels;
+        self
+    }
+}
+
+impl<'data> ImageDecoder<'data> for PPMDecoder<'data> {
+    fn new(image_data: &'data [u8]) -> Self {
+        Self {
+            image_data,
+            max_pixels: 1 << 28,
+        }
+    }
+
+    /// Decodes the image to an RGB bitmap. Images with a maximum value over 255 give 16 bit
+    /// samples. Samples are scaled to the full 8 or 16 bit range when the maximum is lower.
+    fn decode(&self) -> Result<Bitmap> {
+        let mut parser = HeaderParser {
+            data: self.image_data,
+            position: 0,
+        };
+        let binary = match self.image_data.get(..2) {
+            Some(b"P3") => false,
+            Some(b"P6") => true,
+            _ => return Err(Error::Malformed("Not a PPM image".into())),
+        };
+        parser.position = 2;
+
+        let width = parser.read_number()?;
+        let height = parser.read_number()?;
+        let max_value = parser.read_number()?;
+        let size = match (u16::try_from(width), u16::try_from(height)) {
+            (Ok(width), Ok(height)) => (width, height),
+            _ => {
+                return Err(Error::UnsupportedFeature(format!(
+                    "PPM image is too large ({}x{})",
+                    width, height
+                )))
+            }
+        };
+        if size.0 as u64 * size.1 as u64 > self.max_pixels {
+            return Err(Error::UnsupportedFeature(format!(
+                "image is too large ({}x{} pixels, the limit is {} pixels)",
+                size.0, size.1, self.max_pixels
+            )));
+        }
+        if !(1..=65535).contains(&max_value) {
+            return Err(Error::Malformed(format!(
+                "Invalid PPM maximum value {}",
+                max_value
+            )));
+        }
+
+        let (bit_depth, full_scale) = if max_value > 255 {
+            (16, 65535)
+        } else {
+            (8, 255)
+        };
+        let sample_count = size.0 as usize * size.1 as usize * 3;
+        let raster = if binary {
+            // A single whitespace byte separates the header from the binary samples
+            match self.image_data.get(parser.position) {
+                Some(byte) if byte.is_ascii_whitespace() => {}
+                Some(_) => return Err(Error::Malformed("Invalid PPM header".into())),
+                None => return Err(Error::UnexpectedEof),
+            }
+            let sample_size = if max_value > 255 { 2 } else { 1 };
+            let start = parser.position + 1;
+            self.image_data
+                .get(start..start + sample_count * sample_size)
+                .ok_or(Error::UnexpectedEof)?
+        } else {
+            &[]
+        };
+
+        // The binary raster is known to be all there by now. ASCII samples take at least a digit
+        // and a whitespace byte each, so don't reserve room for more than the rest of the data
+        // could hold.
+        let reserved_samples = if binary {
+            sample_count
+        } else {
+            sample_count.min((self.image_data.len() - parser.position) / 2 + 1)
+        };
+        let mut data = Vec::with_capacity(reserved_samples * (bit_depth as usize / 8));
+        for index in 0..sample_count {
+            let sample = match (binary, max_value > 255) {
+                (true, false) => raster[index] as u32,
+                (true, true) => {
+                    u16::from_be_bytes([raster[index * 2], raster[index * 2 + 1]]) as u32
+                }
+                (false, _) => parser.read_number()?,
+            };
+            if sample > max_value {
+                return Err(Error::Malformed(format!(
+                    "PPM sample {} is over the maximum value {}",
+                    sample, max_value
+                )));
+            }
+
+            let scaled = (sample * full_scale + max_value / 2) / max_value;
+            match bit_depth {
+                16 => data.extend((scaled as u16).to_be_bytes()),
+                _ => data.push(scaled as u8),
+            }
+        }
+
+        Ok(Bitmap {
+            channels: 3,
+            color_space: ColorSpace::Rgb,
+            bit_depth,
+            size,
+            data,
+        })
+    }
+}
+
+/// Reads the whitespace separated decimal numbers of a PPM header, and the samples of an ASCII
+/// image
+struct HeaderParser<'data> {
+    data: &'data [u8],
+    position: usize,
+}
+
+impl HeaderParser<'_> {
+    /// Skips whitespace and `#` comments, then reads a number
+    fn read_number(&mut self) -> Result<u32> {
+        loop {
+            match self.data.get(self.position) {
+                Some(b'#') => {
+                    // Comments run to the end of the line
+                    while self
+                        .data
+                        .get(self.position)
+                        .is_some_and(|&byte| byte != b'\n')
+                    {
+                        self.position += 1;
+                    }
+                }
+                Some(byte) if byte.is_ascii_whitespace() => self.position += 1,
+                Some(_) => break,
+                None => return Err(Error::UnexpectedEof),
+            }
+        }
+
+        let start = self.position;
+        let mut number: u32 = 0;
+        while let Some(byte) = self
+            .data
+            .get(self.position)
+            .filter(|byte| byte.is_ascii_digit())
+        {
+            number = number
+                .checked_mul(10)
+                .and_then(|number| number.checked_add((byte - b'0') as u32))
+                .ok_or_else(|| Error::Malformed("PPM number is too large".into()))?;
+            self.position += 1;
+        }
+        if self.position == start {
+            return Err(Error::Malformed(
+                "Expected a number in the PPM image".into(),
+            ));
+        }
+        Ok(number)
+    }
+}
 
 /// PPM encoder
 pub struct PPMEncoder<'bitmap> {
@@ -16,30 +195,147 @@ impl<'bitmap> ImageEncoder<'bitmap> for PPMEncoder<'bitmap> {
     }
 
     fn encode_to_file(&self, path: &str) -> io::Result<()> {
-        let mut file = File::create(path).expect("Failed to create file");
-        file.write(format!("P{}\n", self.bitmap.channels).as_bytes())
-            .expect("Failed to write file");
-        file.write(format!("{} {}\n", self.bitmap.size.0, self.bitmap.size.1).as_bytes())
-            .expect("Failed to write file");
-        file.write(format!("255\n").as_bytes())
-            .expect("Failed to write file");
-
-        for y in 0..self.bitmap.size.1 {
-            for x in 0..self.bitmap.size.0 {
-                let index = ((y as usize * self.bitmap.size.0 as usize) + x as usize)
-                    * self.bitmap.channels as usize;
-                file.write(
-                    format!(
-                        "{} {} {}\n",
-                        self.bitmap.data[index + 0],
-                        self.bitmap.data[index + 1],
-                        self.bitmap.data[index + 2]
-                    )
-                    .as_bytes(),
-                )
-                .expect("Failed to write file");
-            }
+        if self.bitmap.color_space != ColorSpace::Rgb {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PPM encoder only supports RGB bitmaps",
+            ));
+        }
+        if self.bitmap.bit_depth != 8 && self.bitmap.bit_depth != 16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "PPM encoder only supports 8 or 16 bit samples",
+            ));
+        }
+        let mut file = File::create(path)?;
+        file.write_all(format!("P{}\n", self.bitmap.channels).as_bytes())?;
+        file.write_all(format!("{} {}\n", self.bitmap.size.0, self.bitmap.size.1).as_bytes())?;
+        file.write_all(format!("{}\n", (1u32 << self.bitmap.bit_depth) - 1).as_bytes())?;
+
+        for (_, _, pixel) in self.bitmap.pixels() {
+            // 16 bit samples are written as is, with a maximum value to match
+            let sample = |channel: usize| match self.bitmap.bit_depth {
+                16 => u16::from_be_bytes([pixel[channel * 2], pixel[channel * 2 + 1]]),
+                _ => pixel[channel] as u16,
+            };
+            file.write_all(format!("{} {} {}\n", sample(0), sample(1), sample(2)).as_bytes())?;
         }
         Ok(())
     }
 }
+
+#[test]
+fn decode_binary() {
+    let mut image_data = b"P6\n# A comment\n2 1 # Another\n\t255\n".to_vec();
+    image_data.extend([10, 20, 30, 255, 0, 128]);
+    let bitmap = PPMDecoder::new(&image_data).decode().unwrap();
+    assert_eq!(
+        bitmap,
+        Bitmap::new(2, 1, 3, vec![10, 20, 30, 255, 0, 128]).unwrap()
+    );
+
+    // Samples are scaled up from smaller maximums, and read as words past 255
+    let mut image_data = b"P6 1 1 15 ".to_vec();
+    image_data.extend([0, 15, 5]);
+    assert_eq!(
+        PPMDecoder::new(&image_data).decode().unwrap().data,
+        [0, 255, 85]
+    );
+    let mut image_data = b"P6 1 1 1023\n".to_vec();
+    image_data.extend([0, 0, 3, 255, 0, 1]);
+    let bitmap = PPMDecoder::new(&image_data).decode().unwrap();
+    assert_eq!(bitmap.bit_depth, 16);
+    assert_eq!(bitmap.to_u16_samples().unwrap(), [0, 65535, 64]);
+
+    image_data.pop();
+    assert!(matches!(
+        PPMDecoder::new(&image_data).decode(),
+        Err(Error::UnexpectedEof)
+    ));
+    assert!(matches!(
+        PPMDecoder::new(b"P6 1 1 0\n").decode(),
+        Err(Error::Malformed(_))
+    ));
+    assert!(matches!(
+        PPMDecoder::new(b"P5 1 1 255\n\0").decode(),
+        Err(Error::Malformed(_))
+    ));
+}
+
+#[test]
+fn reject_huge_header() {
+    // A 21 byte file claiming a 65535x65535 image of 16 bit samples
+    let image_data = b"P6 65535 65535 65535\n";
+    assert!(matches!(
+        PPMDecoder::new(image_data).decode(),
+        Err(Error::UnsupportedFeature(_))
+    ));
+    assert!(matches!(
+        crate::decode(image_data),
+        Err(Error::UnsupportedFeature(_))
+    ));
+
+    // Under a raised limit the missing raster is found before anything is allocated
+    let unlimited = |image_data| PPMDecoder::new(image_data).with_max_pixels(u64::MAX);
+    assert!(matches!(
+        unlimited(image_data).decode(),
+        Err(Error::UnexpectedEof)
+    ));
+    assert!(matches!(
+        unlimited(b"P3 65535 65535 65535\n1 2 3").decode(),
+        Err(Error::UnexpectedEof)
+    ));
+
+    assert!(matches!(
+        PPMDecoder::new(b"P6 2 1 255\n\0\0\0\0\0\0")
+            .with_max_pixels(1)
+            .decode(),
+        Err(Error::UnsupportedFeature(_))
+    ));
+}
+
+#[test]
+fn decode_ascii() {
+    let image_data = b"P3\n2 1\n255\n1 2 3\n# A comment\n4 5 6\n";
+    let bitmap = PPMDecoder::new(image_data).decode().unwrap();
+    assert_eq!(
+        bitmap,
+        Bitmap::new(2, 1, 3, vec![1, 2, 3, 4, 5, 6]).unwrap()
+    );
+
+    assert!(matches!(
+        PPMDecoder::new(b"P3 1 1 255 1 2").decode(),
+        Err(Error::UnexpectedEof)
+    ));
+    assert!(matches!(
+        PPMDecoder::new(b"P3 1 1 255 1 2 256").decode(),
+        Err(Error::Malformed(_))
+    ));
+}
+
+#[test]
+fn encode_round_trip() {
+    let path = std::env::temp_dir().join(format!("ppm-round-trip-{}.ppm", std::process::id()));
+    let path = path.to_str().unwrap();
+
+    let bitmap = Bitmap::new(3, 2, 3, (0..18).map(|i| i * 14).collect()).unwrap();
+    PPMEncoder::new(&bitmap).encode_to_file(path).unwrap();
+    let decoded = PPMDecoder::new(&std::fs::read(path).unwrap())
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, bitmap);
+
+    let words: [u16; 6] = [0, 1, 256, 4095, 40000, 65535];
+    let bitmap = Bitmap {
+        bit_depth: 16,
+        data: words.iter().flat_map(|word| word.to_be_bytes()).collect(),
+        ..Bitmap::new(2, 1, 3, vec![0; 6]).unwrap()
+    };
+    PPMEncoder::new(&bitmap).encode_to_file(path).unwrap();
+    let decoded = PPMDecoder::new(&std::fs::read(path).unwrap())
+        .decode()
+        .unwrap();
+    assert_eq!(decoded, bitmap);
+
+    std::fs::remove_file(path).unwrap();
+}